@@ -0,0 +1,85 @@
+//! Cost of sending one tick's keyframe `Snapshot` to a crowd of clients who
+//! all see the same players.
+//!
+//! `per_client_encode` is the net task serializing the snapshot again for
+//! every client, as it does for a `SendToClient` message. `shared_frame` is
+//! the sim encoding it once into a `Frame` sent with `SendFrame`, which the
+//! net task only clones.
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use farmworld_online_server::messages::{Frame, PlayerState, ServerMessage, WireFormat};
+use std::hint::black_box;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+use uuid::Uuid;
+
+const PLAYERS_IN_VIEW: usize = 100;
+
+fn keyframe() -> ServerMessage {
+    ServerMessage::Snapshot {
+        seq: 0,
+        tick: 0,
+        baseline: None,
+        players: (0..PLAYERS_IN_VIEW)
+            .map(|i| PlayerState {
+                player_id: Uuid::new_v4(),
+                x: i as f32,
+                y: i as f32,
+                last_processed_input: None,
+            })
+            .collect(),
+    }
+}
+
+/// One outbound queue per client, like the net task keeps.
+fn clients(count: usize) -> (Vec<mpsc::Sender<Message>>, Vec<mpsc::Receiver<Message>>) {
+    (0..count).map(|_| mpsc::channel(4)).unzip()
+}
+
+fn text(bytes: impl Into<bytes::Bytes>) -> Message {
+    Message::Text(Utf8Bytes::try_from(bytes.into()).unwrap())
+}
+
+fn drain(receivers: &mut [mpsc::Receiver<Message>]) {
+    for rx in receivers {
+        while let Ok(msg) = rx.try_recv() {
+            black_box(msg);
+        }
+    }
+}
+
+fn bench_snapshot_fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot_fan_out");
+    for count in [10, 100, 1000] {
+        let (senders, mut receivers) = clients(count);
+        let message = keyframe();
+
+        group.bench_with_input(
+            BenchmarkId::new("per_client_encode", count),
+            &count,
+            |b, _| {
+                b.iter(|| {
+                    for tx in &senders {
+                        let _ = tx.try_send(text(WireFormat::Json.encode(&message)));
+                    }
+                    drain(&mut receivers);
+                })
+            },
+        );
+
+        group.bench_with_input(BenchmarkId::new("shared_frame", count), &count, |b, _| {
+            b.iter(|| {
+                let frame = Frame::encode(&message);
+                let msg = text(frame.bytes(WireFormat::Json).clone());
+                for tx in &senders {
+                    let _ = tx.try_send(msg.clone());
+                }
+                drain(&mut receivers);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_snapshot_fan_out);
+criterion_main!(benches);
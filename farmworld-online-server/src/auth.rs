@@ -0,0 +1,356 @@
+use crate::messages::ClientMessage;
+use crate::persistence::{StorageError, open_database, prepare};
+use crate::sim::is_valid_account_name;
+use argon2::Argon2;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use rusqlite::{Connection, ErrorCode, OptionalExtension, params};
+use sha2::Sha256;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_then_login() {
+        let accounts = Accounts::open_in_memory().unwrap();
+        let registered = accounts.register("alice", "correct horse").unwrap();
+        assert_eq!(registered.username, "alice");
+
+        // Usernames are matched case-insensitively but keep their spelling
+        let logged_in = accounts.login("ALICE", "correct horse").unwrap();
+        assert_eq!(logged_in, registered);
+
+        assert!(matches!(
+            accounts.login("alice", "wrong horse"),
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            accounts.login("bob", "correct horse"),
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+
+    #[test]
+    fn test_register_validates_credentials() {
+        let accounts = Accounts::open_in_memory().unwrap();
+        accounts.register("alice", "correct horse").unwrap();
+        assert!(matches!(
+            accounts.register("Alice", "another password"),
+            Err(AuthError::UsernameTaken)
+        ));
+        assert!(matches!(
+            accounts.register("bad name", "correct horse"),
+            Err(AuthError::InvalidUsername)
+        ));
+        assert!(matches!(
+            accounts.register("bob", "short"),
+            Err(AuthError::WeakPassword)
+        ));
+    }
+
+    #[test]
+    fn test_passwords_are_not_stored_in_plain_text() {
+        let accounts = Accounts::open_in_memory().unwrap();
+        accounts.register("alice", "correct horse").unwrap();
+        let hash: String = accounts
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT password_hash FROM accounts", [], |row| row.get(0))
+            .unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(!hash.contains("correct horse"));
+    }
+
+    #[test]
+    fn test_session_tokens() {
+        let keys = SessionKeys::generate();
+        let account_id = Uuid::new_v4();
+        let now = SystemTime::now();
+        let token = keys.issue(account_id, now);
+        assert_eq!(keys.verify(&token, now), Some(account_id));
+
+        // Expired
+        assert_eq!(keys.verify(&token, now + SESSION_TTL), None);
+        // Signed by someone else
+        assert_eq!(SessionKeys::generate().verify(&token, now), None);
+        // Tampered with
+        let forged = token.replacen(&account_id.to_string(), &Uuid::new_v4().to_string(), 1);
+        assert_eq!(keys.verify(&forged, now), None);
+        assert_eq!(keys.verify("not a token", now), None);
+    }
+}
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+/// How long a session token stays valid after login.
+pub const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidUsername,
+    WeakPassword,
+    UsernameTaken,
+    InvalidCredentials,
+    InvalidSession,
+    Storage(StorageError),
+    Hash(argon2::password_hash::Error),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::InvalidUsername => {
+                write!(f, "usernames may only contain letters, digits, '_' and '-'")
+            }
+            AuthError::WeakPassword => write!(
+                f,
+                "passwords must be {} to {} characters long",
+                MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+            ),
+            AuthError::UsernameTaken => write!(f, "that username is already taken"),
+            AuthError::InvalidCredentials => write!(f, "wrong username or password"),
+            AuthError::InvalidSession => write!(f, "session expired, log in again"),
+            AuthError::Storage(e) => write!(f, "{}", e),
+            AuthError::Hash(e) => write!(f, "could not hash password: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<StorageError> for AuthError {
+    fn from(e: StorageError) -> Self {
+        AuthError::Storage(e)
+    }
+}
+
+impl From<rusqlite::Error> for AuthError {
+    fn from(e: rusqlite::Error) -> Self {
+        AuthError::Storage(StorageError::Sqlite(e))
+    }
+}
+
+/// An account that has proven who it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedAccount {
+    /// Stable id, used as the player id for everything the account does
+    pub id: Uuid,
+    pub username: String,
+}
+
+/// Usernames and argon2 password hashes, stored alongside the saved world.
+pub struct Accounts {
+    conn: Mutex<Connection>,
+}
+
+impl Accounts {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Ok(Self {
+            conn: Mutex::new(open_database(path)?),
+        })
+    }
+
+    pub fn open_in_memory() -> Result<Self, StorageError> {
+        Ok(Self {
+            conn: Mutex::new(prepare(Connection::open_in_memory()?)?),
+        })
+    }
+
+    pub fn register(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<AuthenticatedAccount, AuthError> {
+        if !is_valid_account_name(username) {
+            return Err(AuthError::InvalidUsername);
+        }
+        let length = password.chars().count();
+        if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+            return Err(AuthError::WeakPassword);
+        }
+
+        let password_hash = hash_password(password)?;
+        let account = AuthenticatedAccount {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+        };
+        let inserted = self.conn.lock().unwrap().execute(
+            "INSERT INTO accounts (id, username, password_hash) VALUES (?1, ?2, ?3)",
+            params![account.id.to_string(), account.username, password_hash],
+        );
+        match inserted {
+            Ok(_) => Ok(account),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == ErrorCode::ConstraintViolation =>
+            {
+                Err(AuthError::UsernameTaken)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn login(&self, username: &str, password: &str) -> Result<AuthenticatedAccount, AuthError> {
+        let found: Option<(String, String, String)> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id, username, password_hash FROM accounts WHERE username = ?1",
+                params![username],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        let Some((id, username, password_hash)) = found else {
+            // Spend as long as a real check so unknown names can't be told apart
+            let _ = verify_password(password, dummy_hash());
+            return Err(AuthError::InvalidCredentials);
+        };
+        if !verify_password(password, &password_hash) {
+            return Err(AuthError::InvalidCredentials);
+        }
+        let id = Uuid::parse_str(&id).map_err(|_| AuthError::InvalidCredentials)?;
+        Ok(AuthenticatedAccount { id, username })
+    }
+
+    /// The account with the given id, if it still exists.
+    pub fn find(&self, id: Uuid) -> Result<Option<AuthenticatedAccount>, AuthError> {
+        let username = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT username FROM accounts WHERE id = ?1",
+                params![id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(username.map(|username| AuthenticatedAccount { id, username }))
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(AuthError::Hash)
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("not a real password").unwrap_or_default())
+}
+
+/// Signs and checks session tokens. Tokens look like
+/// `<account id>.<expiry unix seconds>.<HMAC-SHA256 signature>` and stop
+/// working when the server restarts with a new key.
+pub struct SessionKeys {
+    key: [u8; 32],
+}
+
+impl SessionKeys {
+    pub fn generate() -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self { key }
+    }
+
+    pub fn issue(&self, account_id: Uuid, now: SystemTime) -> String {
+        let expires = (now + SESSION_TTL)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let payload = format!("{}.{}", account_id, expires);
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// The account a token was issued to, if it is genuine and not expired.
+    pub fn verify(&self, token: &str, now: SystemTime) -> Option<Uuid> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+
+        let (account_id, expires) = payload.split_once('.')?;
+        let expires = UNIX_EPOCH + Duration::from_secs(expires.parse().ok()?);
+        if now >= expires {
+            return None;
+        }
+        Uuid::parse_str(account_id).ok()
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+/// The ways a client can prove who it is.
+pub enum LoginRequest {
+    Register { username: String, password: String },
+    Login { username: String, password: String },
+    Resume { session_token: String },
+}
+
+impl LoginRequest {
+    /// Picks login messages out from everything else a client sends.
+    pub fn from_message(msg: ClientMessage) -> Result<Self, ClientMessage> {
+        match msg {
+            ClientMessage::Register { username, password } => {
+                Ok(LoginRequest::Register { username, password })
+            }
+            ClientMessage::Login { username, password } => {
+                Ok(LoginRequest::Login { username, password })
+            }
+            ClientMessage::Resume { session_token } => Ok(LoginRequest::Resume { session_token }),
+            msg => Err(msg),
+        }
+    }
+}
+
+/// Everything needed to log players in.
+pub struct Auth {
+    pub accounts: Accounts,
+    pub sessions: SessionKeys,
+}
+
+impl Auth {
+    pub fn authenticate(
+        &self,
+        request: LoginRequest,
+        now: SystemTime,
+    ) -> Result<AuthenticatedAccount, AuthError> {
+        match request {
+            LoginRequest::Register { username, password } => {
+                self.accounts.register(&username, &password)
+            }
+            LoginRequest::Login { username, password } => self.accounts.login(&username, &password),
+            LoginRequest::Resume { session_token } => {
+                let id = self
+                    .sessions
+                    .verify(&session_token, now)
+                    .ok_or(AuthError::InvalidSession)?;
+                self.accounts.find(id)?.ok_or(AuthError::InvalidSession)
+            }
+        }
+    }
+}
@@ -0,0 +1,568 @@
+use crate::net::{NetConfig, RateLimit, RateLimits};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn test_default_config_documents_the_defaults() {
+        assert_eq!(
+            ServerConfig::from_toml(DEFAULT_CONFIG).unwrap(),
+            ServerConfig::default()
+        );
+        assert!(ServerConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_config_file_settings_override_defaults() {
+        let config = ServerConfig::from_toml(
+            "bind = \"0.0.0.0:9100\"\nmax_players = 8\n\n[spawn_point]\nx = 48.0\ny = 64.0\n",
+        )
+        .unwrap();
+        assert_eq!(config.bind, "0.0.0.0:9100");
+        assert_eq!(config.max_players, 8);
+        assert_eq!(config.spawn_point, SpawnPoint { x: 48.0, y: 64.0 });
+        // Anything left out keeps its default
+        assert_eq!(config.tick_rate, ServerConfig::default().tick_rate);
+
+        assert!(matches!(
+            ServerConfig::from_toml("tick_rat = 30.0\n"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_command_line_overrides_environment() {
+        let env = |name: &str| match name {
+            "FARMWORLD_TICK_RATE" => Some("30".to_string()),
+            "FARMWORLD_MAX_PLAYERS" => Some("12".to_string()),
+            "FARMWORLD_DB" => Some("env.db".to_string()),
+            "FARMWORLD_CROP_CATALOG" => Some("env/crops.json".to_string()),
+            _ => None,
+        };
+        let Launch::Run(config) = ServerConfig::load(
+            args(&[
+                "--max-players",
+                "4",
+                "--spawn-point=10,20.5",
+                "--bind",
+                "[::1]:9002",
+            ]),
+            env,
+        )
+        .unwrap() else {
+            panic!("Expected to run the server");
+        };
+        assert_eq!(config.tick_rate, 30.0);
+        assert_eq!(config.max_players, 4);
+        assert_eq!(config.spawn_point, SpawnPoint { x: 10.0, y: 20.5 });
+        assert_eq!(config.bind, "[::1]:9002");
+        assert_eq!(config.save_path, PathBuf::from("env.db"));
+        assert_eq!(config.crop_catalog, PathBuf::from("env/crops.json"));
+    }
+
+    #[test]
+    fn test_network_settings_reach_net_config() {
+        let env = |name: &str| match name {
+            "FARMWORLD_PONG_TIMEOUT" => Some("20".to_string()),
+            "FARMWORLD_RATE_LIMIT_CHAT" => Some("2,10".to_string()),
+            _ => None,
+        };
+        let Launch::Run(mut config) =
+            ServerConfig::load(args(&["--ping-interval", "2.5"]), env).unwrap()
+        else {
+            panic!("Expected to run the server");
+        };
+        let file = ServerConfig::from_toml(
+            "idle_timeout = 60.0\n\n[rate_limits]\nmovement = { per_second = 30.0, burst = 45.0 }\n",
+        )
+        .unwrap();
+        config.idle_timeout = file.idle_timeout;
+        config.rate_limits.movement = file.rate_limits.movement;
+
+        let net = config.net_config();
+        assert_eq!(net.ping_interval, Duration::from_millis(2500));
+        assert_eq!(net.pong_timeout, Duration::from_secs(20));
+        assert_eq!(net.idle_timeout, Duration::from_secs(60));
+        let limits = &net.rate_limits;
+        assert_eq!(
+            limits.chat,
+            RateLimit {
+                per_second: 2.0,
+                burst: 10.0
+            }
+        );
+        assert_eq!(
+            limits.movement,
+            RateLimit {
+                per_second: 30.0,
+                burst: 45.0
+            }
+        );
+        // Limits left out keep their defaults
+        assert_eq!(limits.actions, RateLimits::default().actions);
+    }
+
+    #[test]
+    fn test_print_default_config_flag() {
+        assert!(matches!(
+            ServerConfig::load(args(&["--print-default-config"]), no_env),
+            Ok(Launch::PrintDefaultConfig)
+        ));
+    }
+
+    #[test]
+    fn test_bad_settings_are_rejected() {
+        let load = |flags: &[&str]| ServerConfig::load(args(flags), no_env);
+        assert!(matches!(
+            load(&["--tick-rate", "fast"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            load(&["--spawn-point", "10"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            load(&["--rate-limit-chat", "1"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            load(&["--max-players"]),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(
+            load(&["--verbose"]),
+            Err(ConfigError::UnknownFlag(_))
+        ));
+        assert!(matches!(
+            load(&["--config", "does/not/exist.toml"]),
+            Err(ConfigError::Io { .. })
+        ));
+
+        // Values that parse but make no sense
+        for flags in [
+            &["--bind", "localhost"][..],
+            &["--tick-rate", "0"],
+            &["--snapshot-rate", "40"],
+            &["--spawn-point", "NaN,0"],
+            &["--player-speed", "-1"],
+            &["--max-players", "0"],
+            &["--save-interval", "inf"],
+            &["--world-file", ""],
+            &["--crop-catalog", ""],
+            &["--ping-interval", "0"],
+            &["--idle-timeout", "1e30"],
+            &["--rate-limit-chat", "1,0.5"],
+            &["--rate-limit-movement", "-1,5"],
+        ] {
+            assert!(
+                matches!(load(flags), Err(ConfigError::Invalid(_))),
+                "{:?}",
+                flags
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot_interval_in_ticks() {
+        let mut config = ServerConfig::default();
+        assert_eq!(config.snapshot_interval_ticks(), 1);
+        config.tick_rate = 60.0;
+        config.snapshot_rate = 20.0;
+        assert_eq!(config.snapshot_interval_ticks(), 3);
+        config.snapshot_rate = 25.0;
+        assert_eq!(config.snapshot_interval_ticks(), 2);
+    }
+}
+
+/// The configuration file the server reads when none is given.
+pub const DEFAULT_CONFIG_FILE: &str = "farmworld.toml";
+
+/// `ServerConfig::default()` as a commented configuration file, printed by
+/// `--print-default-config`.
+pub const DEFAULT_CONFIG: &str = r#"# Farmworld server configuration.
+#
+# Every setting can also be given as an environment variable or a command line
+# flag, which take precedence in that order: e.g. `tick_rate` is overridden by
+# FARMWORLD_TICK_RATE and `--tick-rate`. The save path's variable is
+# FARMWORLD_DB. Rate limits are overridden as `per_second,burst`, e.g.
+# `rate_limits.chat` by FARMWORLD_RATE_LIMIT_CHAT and `--rate-limit-chat 1,5`.
+
+# Address the WebSocket server listens on
+bind = "127.0.0.1:9001"
+# Simulation steps per second
+tick_rate = 20.0
+# Snapshots sent to clients per second, at most `tick_rate`
+snapshot_rate = 20.0
+# How fast players walk, in pixels per second
+player_speed = 300.0
+# Players allowed in the world at once, counting those who may still resume
+max_players = 100
+# Tile map players walk around on
+world_file = "content/world.map"
+# Crops that can be grown, checked before the server starts
+crop_catalog = "content/crops.json"
+# SQLite database accounts and the world are saved to
+save_path = "farmworld.db"
+# Seconds between saves
+save_interval = 60.0
+# Seconds between pings checking that clients are still there
+ping_interval = 5.0
+# Seconds a client has to answer a ping before it counts as gone
+pong_timeout = 10.0
+# Seconds a client may send nothing before it is disconnected
+idle_timeout = 900.0
+
+# Where new players appear, in pixels. Must be on open ground.
+[spawn_point]
+x = 365.0
+y = 175.0
+
+# Messages one connection may send: `burst` at once, refilling at `per_second`
+[rate_limits]
+# Everything together
+connection = { per_second = 100.0, burst = 200.0 }
+movement = { per_second = 60.0, burst = 60.0 }
+actions = { per_second = 10.0, burst = 20.0 }
+chat = { per_second = 1.0, burst = 5.0 }
+# Handshake, login and joining. Logins hash passwords, so keep it low.
+session = { per_second = 1.0, burst = 5.0 }
+# Snapshot acknowledgements and anything that doesn't parse
+other = { per_second = 40.0, burst = 40.0 }
+# Messages over the limits tolerated before the client is disconnected
+excess = { per_second = 10.0, burst = 100.0 }
+"#;
+
+/// Settings for one server run: read from a TOML file, then overridden by
+/// environment variables and command line flags.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub tick_rate: f64,
+    pub snapshot_rate: f64,
+    pub player_speed: f32,
+    pub max_players: usize,
+    pub world_file: PathBuf,
+    pub crop_catalog: PathBuf,
+    pub save_path: PathBuf,
+    pub save_interval: f32,
+    pub ping_interval: f32,
+    pub pong_timeout: f32,
+    pub idle_timeout: f32,
+    pub spawn_point: SpawnPoint,
+    pub rate_limits: RateLimits,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:9001".to_string(),
+            tick_rate: crate::sim::DEFAULT_TICK_RATE,
+            snapshot_rate: crate::sim::DEFAULT_TICK_RATE,
+            player_speed: crate::sim::DEFAULT_PLAYER_SPEED,
+            max_players: 100,
+            world_file: PathBuf::from("content/world.map"),
+            crop_catalog: PathBuf::from("content/crops.json"),
+            save_path: PathBuf::from("farmworld.db"),
+            save_interval: 60.0,
+            ping_interval: 5.0,
+            pong_timeout: 10.0,
+            idle_timeout: 15.0 * 60.0,
+            spawn_point: SpawnPoint { x: 365.0, y: 175.0 },
+            rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+/// A position in the world, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpawnPoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// What the command line asked the server to do.
+#[derive(Debug)]
+pub enum Launch {
+    Run(Box<ServerConfig>),
+    PrintDefaultConfig,
+}
+
+/// Settings that can be overridden, by flag name and environment variable.
+const OVERRIDES: &[(&str, &str)] = &[
+    ("bind", "FARMWORLD_BIND"),
+    ("tick-rate", "FARMWORLD_TICK_RATE"),
+    ("snapshot-rate", "FARMWORLD_SNAPSHOT_RATE"),
+    ("player-speed", "FARMWORLD_PLAYER_SPEED"),
+    ("max-players", "FARMWORLD_MAX_PLAYERS"),
+    ("world-file", "FARMWORLD_WORLD_FILE"),
+    ("crop-catalog", "FARMWORLD_CROP_CATALOG"),
+    ("save-path", "FARMWORLD_DB"),
+    ("save-interval", "FARMWORLD_SAVE_INTERVAL"),
+    ("spawn-point", "FARMWORLD_SPAWN_POINT"),
+    ("ping-interval", "FARMWORLD_PING_INTERVAL"),
+    ("pong-timeout", "FARMWORLD_PONG_TIMEOUT"),
+    ("idle-timeout", "FARMWORLD_IDLE_TIMEOUT"),
+    ("rate-limit-connection", "FARMWORLD_RATE_LIMIT_CONNECTION"),
+    ("rate-limit-movement", "FARMWORLD_RATE_LIMIT_MOVEMENT"),
+    ("rate-limit-actions", "FARMWORLD_RATE_LIMIT_ACTIONS"),
+    ("rate-limit-chat", "FARMWORLD_RATE_LIMIT_CHAT"),
+    ("rate-limit-session", "FARMWORLD_RATE_LIMIT_SESSION"),
+    ("rate-limit-other", "FARMWORLD_RATE_LIMIT_OTHER"),
+    ("rate-limit-excess", "FARMWORLD_RATE_LIMIT_EXCESS"),
+];
+
+impl ServerConfig {
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(ConfigError::Parse)
+    }
+
+    /// Reads the configuration file, falling back to the defaults if there is
+    /// no `farmworld.toml` and none was asked for.
+    pub fn from_file(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(DEFAULT_CONFIG_FILE), false),
+        };
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::from_toml(&text),
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(ConfigError::Io {
+                path: path.to_path_buf(),
+                error,
+            }),
+        }
+    }
+
+    /// Works out the configuration from the command line `args` (without the
+    /// program name) and the environment, as looked up by `env`. The file
+    /// comes from `--config` or FARMWORLD_CONFIG.
+    pub fn load(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Launch, ConfigError> {
+        let mut config_file = env("FARMWORLD_CONFIG").map(PathBuf::from);
+        let mut flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(ConfigError::UnknownFlag(arg));
+            };
+            if flag == "print-default-config" {
+                return Ok(Launch::PrintDefaultConfig);
+            }
+            let name = flag.split_once('=').map_or(flag, |(name, _)| name);
+            if name != "config" && !OVERRIDES.iter().any(|(setting, _)| *setting == name) {
+                return Err(ConfigError::UnknownFlag(arg));
+            }
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                    (flag.to_string(), value)
+                }
+            };
+            if name == "config" {
+                config_file = Some(PathBuf::from(value));
+            } else {
+                flags.push((name, value));
+            }
+        }
+
+        let mut config = Self::from_file(config_file.as_deref())?;
+        for (name, variable) in OVERRIDES {
+            if let Some(value) = env(variable) {
+                config.set(name, &value)?;
+            }
+        }
+        for (name, value) in &flags {
+            config.set(name, value)?;
+        }
+        config.validate()?;
+        Ok(Launch::Run(Box::new(config)))
+    }
+
+    /// Overrides one setting by its flag name.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+            value.trim().parse().map_err(|_| ConfigError::InvalidValue {
+                setting: name.to_string(),
+                value: value.to_string(),
+            })
+        }
+        // Two numbers separated by a comma
+        fn parse_pair<T: std::str::FromStr>(
+            name: &str,
+            value: &str,
+        ) -> Result<(T, T), ConfigError> {
+            let Some((a, b)) = value.split_once(',') else {
+                return Err(ConfigError::InvalidValue {
+                    setting: name.to_string(),
+                    value: value.to_string(),
+                });
+            };
+            Ok((parse(name, a)?, parse(name, b)?))
+        }
+        let rate_limit = |value| {
+            let (per_second, burst) = parse_pair(name, value)?;
+            Ok::<_, ConfigError>(RateLimit { per_second, burst })
+        };
+        let limits = &mut self.rate_limits;
+        match name {
+            "bind" => self.bind = value.to_string(),
+            "tick-rate" => self.tick_rate = parse(name, value)?,
+            "snapshot-rate" => self.snapshot_rate = parse(name, value)?,
+            "player-speed" => self.player_speed = parse(name, value)?,
+            "max-players" => self.max_players = parse(name, value)?,
+            "world-file" => self.world_file = PathBuf::from(value),
+            "crop-catalog" => self.crop_catalog = PathBuf::from(value),
+            "save-path" => self.save_path = PathBuf::from(value),
+            "save-interval" => self.save_interval = parse(name, value)?,
+            "ping-interval" => self.ping_interval = parse(name, value)?,
+            "pong-timeout" => self.pong_timeout = parse(name, value)?,
+            "idle-timeout" => self.idle_timeout = parse(name, value)?,
+            "spawn-point" => {
+                let (x, y) = parse_pair(name, value)?;
+                self.spawn_point = SpawnPoint { x, y };
+            }
+            "rate-limit-connection" => limits.connection = rate_limit(value)?,
+            "rate-limit-movement" => limits.movement = rate_limit(value)?,
+            "rate-limit-actions" => limits.actions = rate_limit(value)?,
+            "rate-limit-chat" => limits.chat = rate_limit(value)?,
+            "rate-limit-session" => limits.session = rate_limit(value)?,
+            "rate-limit-other" => limits.other = rate_limit(value)?,
+            "rate-limit-excess" => limits.excess = rate_limit(value)?,
+            _ => return Err(ConfigError::UnknownFlag(format!("--{}", name))),
+        }
+        Ok(())
+    }
+
+    /// Checks the settings make sense together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.to_string()));
+        let positive = |v: f64| v > 0.0 && v.is_finite();
+        if self.bind.parse::<SocketAddr>().is_err() {
+            return invalid("bind must be an address and port, like 127.0.0.1:9001");
+        }
+        if !positive(self.tick_rate) {
+            return invalid("tick_rate must be a positive number of ticks per second");
+        }
+        if !positive(self.snapshot_rate) || self.snapshot_rate > self.tick_rate {
+            return invalid("snapshot_rate must be positive and at most tick_rate");
+        }
+        if !positive(self.player_speed.into()) {
+            return invalid("player_speed must be a positive number of pixels per second");
+        }
+        if self.max_players == 0 {
+            return invalid("max_players must be at least 1");
+        }
+        if !positive(self.save_interval.into()) {
+            return invalid("save_interval must be a positive number of seconds");
+        }
+        let seconds = |v: f32| positive(v.into()) && Duration::try_from_secs_f32(v).is_ok();
+        if !seconds(self.ping_interval)
+            || !seconds(self.pong_timeout)
+            || !seconds(self.idle_timeout)
+        {
+            return invalid(
+                "ping_interval, pong_timeout and idle_timeout must be positive numbers of seconds",
+            );
+        }
+        let limits = &self.rate_limits;
+        let usable = |limit: &RateLimit| {
+            positive(limit.per_second) && limit.burst >= 1.0 && limit.burst.is_finite()
+        };
+        if ![
+            limits.connection,
+            limits.movement,
+            limits.actions,
+            limits.chat,
+            limits.session,
+            limits.other,
+            limits.excess,
+        ]
+        .iter()
+        .all(usable)
+        {
+            return invalid("rate limits need a positive per_second and a burst of at least 1");
+        }
+        if !self.spawn_point.x.is_finite() || !self.spawn_point.y.is_finite() {
+            return invalid("spawn_point must be a finite position");
+        }
+        if [&self.world_file, &self.crop_catalog, &self.save_path]
+            .iter()
+            .any(|path| path.as_os_str().is_empty())
+        {
+            return invalid("world_file, crop_catalog and save_path can't be empty");
+        }
+        Ok(())
+    }
+
+    /// The network settings, with the rest left at their defaults.
+    pub fn net_config(&self) -> NetConfig {
+        NetConfig {
+            rate_limits: self.rate_limits.clone(),
+            ping_interval: Duration::from_secs_f32(self.ping_interval),
+            pong_timeout: Duration::from_secs_f32(self.pong_timeout),
+            idle_timeout: Duration::from_secs_f32(self.idle_timeout),
+            ..NetConfig::default()
+        }
+    }
+
+    /// How many ticks apart snapshots go out, to send about `snapshot_rate`
+    /// of them per second.
+    pub fn snapshot_interval_ticks(&self) -> u64 {
+        ((self.tick_rate / self.snapshot_rate).round() as u64).max(1)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse(toml::de::Error),
+    UnknownFlag(String),
+    MissingValue(String),
+    InvalidValue {
+        setting: String,
+        value: String,
+    },
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => {
+                write!(f, "could not read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse(e) => write!(f, "invalid configuration file: {}", e),
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown option {}", flag),
+            ConfigError::MissingValue(flag) => write!(f, "{} needs a value", flag),
+            ConfigError::InvalidValue { setting, value } => {
+                write!(f, "invalid {}: {:?}", setting, value)
+            }
+            ConfigError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
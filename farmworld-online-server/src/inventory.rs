@@ -0,0 +1,336 @@
+use crate::messages::{ErrorCode, InventorySlot, ItemStack, RequestError};
+use bevy::prelude::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(item_id: &str, count: u32) -> Option<ItemStack> {
+        Some(ItemStack {
+            item_id: item_id.to_string(),
+            count,
+        })
+    }
+
+    #[test]
+    fn test_add_fills_existing_stacks_then_empty_slots() {
+        let mut inventory = Inventory::default();
+        assert_eq!(inventory.add("wheat", 90), Ok(vec![0]));
+        assert_eq!(inventory.add("wheat", 20), Ok(vec![0, 1]));
+        assert_eq!(inventory.slot(0), stack("wheat", MAX_STACK).as_ref());
+        assert_eq!(inventory.slot(1), stack("wheat", 11).as_ref());
+        assert_eq!(inventory.count("wheat"), 110);
+    }
+
+    #[test]
+    fn test_add_is_all_or_nothing_when_full() {
+        let mut inventory = Inventory::default();
+        inventory
+            .add("stone", MAX_STACK * INVENTORY_SLOTS as u32 - 1)
+            .unwrap();
+        assert_eq!(inventory.add("wheat", 1), Err(InventoryError::Full));
+        assert_eq!(inventory.add("stone", 2), Err(InventoryError::Full));
+        assert_eq!(
+            inventory.count("stone"),
+            MAX_STACK * INVENTORY_SLOTS as u32 - 1
+        );
+    }
+
+    #[test]
+    fn test_move_merges_or_swaps() {
+        let mut inventory = Inventory::default();
+        inventory.slots[0] = stack("wheat", 60);
+        inventory.slots[1] = stack("wheat", 50);
+        inventory.slots[2] = stack("carrot", 1);
+
+        // Merging respects the stack limit
+        assert_eq!(inventory.move_stack(0, 1), Ok(vec![0, 1]));
+        assert_eq!(inventory.slot(0), stack("wheat", 11).as_ref());
+        assert_eq!(inventory.slot(1), stack("wheat", MAX_STACK).as_ref());
+
+        // Different items swap places
+        assert_eq!(inventory.move_stack(2, 0), Ok(vec![2, 0]));
+        assert_eq!(inventory.slot(0), stack("carrot", 1).as_ref());
+        assert_eq!(inventory.slot(2), stack("wheat", 11).as_ref());
+
+        assert_eq!(
+            inventory.move_stack(5, 0),
+            Err(InventoryError::EmptySlot(5))
+        );
+        assert_eq!(
+            inventory.move_stack(0, INVENTORY_SLOTS),
+            Err(InventoryError::InvalidSlot(INVENTORY_SLOTS))
+        );
+    }
+
+    #[test]
+    fn test_split_and_take_validate_counts() {
+        let mut inventory = Inventory::default();
+        inventory.slots[0] = stack("wheat", 10);
+        inventory.slots[1] = stack("carrot", 1);
+
+        assert_eq!(inventory.split(0, 10, 2), Err(InventoryError::InvalidCount));
+        assert_eq!(inventory.split(0, 0, 2), Err(InventoryError::InvalidCount));
+        assert_eq!(
+            inventory.split(0, 4, 1),
+            Err(InventoryError::SlotOccupied(1))
+        );
+        assert_eq!(inventory.split(0, 4, 2), Ok(vec![0, 2]));
+        assert_eq!(inventory.slot(0), stack("wheat", 6).as_ref());
+        assert_eq!(inventory.slot(2), stack("wheat", 4).as_ref());
+
+        assert_eq!(inventory.take(2, 5), Err(InventoryError::InvalidCount));
+        assert_eq!(inventory.take(2, 4), Ok(vec![2]));
+        assert_eq!(inventory.slot(2), None);
+    }
+}
+
+pub const INVENTORY_SLOTS: usize = 24;
+pub const MAX_STACK: u32 = 99;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InventoryError {
+    InvalidSlot(usize),
+    EmptySlot(usize),
+    SlotOccupied(usize),
+    InvalidCount,
+    NotEnough,
+    Full,
+}
+
+impl InventoryError {
+    /// The error code clients are told.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            InventoryError::InvalidSlot(_)
+            | InventoryError::EmptySlot(_)
+            | InventoryError::SlotOccupied(_) => ErrorCode::InvalidSlot,
+            InventoryError::InvalidCount | InventoryError::NotEnough => ErrorCode::InvalidCount,
+            InventoryError::Full => ErrorCode::InventoryFull,
+        }
+    }
+}
+
+impl From<InventoryError> for RequestError {
+    fn from(e: InventoryError) -> Self {
+        RequestError {
+            code: e.code(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for InventoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InventoryError::InvalidSlot(slot) => write!(f, "there is no slot {}", slot),
+            InventoryError::EmptySlot(slot) => write!(f, "slot {} is empty", slot),
+            InventoryError::SlotOccupied(slot) => write!(f, "slot {} is taken", slot),
+            InventoryError::InvalidCount => write!(f, "invalid item count"),
+            InventoryError::NotEnough => write!(f, "not enough items"),
+            InventoryError::Full => write!(f, "inventory is full"),
+        }
+    }
+}
+
+impl std::error::Error for InventoryError {}
+
+/// A player's items, kept in a fixed number of slots. Every operation either
+/// succeeds completely and returns the slots it changed, or leaves the
+/// inventory untouched.
+#[derive(Component, Debug, Clone)]
+pub struct Inventory {
+    pub slots: Vec<Option<ItemStack>>,
+}
+
+impl Default for Inventory {
+    fn default() -> Self {
+        Self {
+            slots: vec![None; INVENTORY_SLOTS],
+        }
+    }
+}
+
+impl Inventory {
+    pub fn slot(&self, slot: usize) -> Option<&ItemStack> {
+        self.slots.get(slot).and_then(Option::as_ref)
+    }
+
+    pub fn count(&self, item_id: &str) -> u32 {
+        self.slots
+            .iter()
+            .flatten()
+            .filter(|stack| stack.item_id == item_id)
+            .map(|stack| stack.count)
+            .sum()
+    }
+
+    /// Adds items, topping up existing stacks before using empty slots.
+    pub fn add(&mut self, item_id: &str, count: u32) -> Result<Vec<usize>, InventoryError> {
+        let space: u32 = self
+            .slots
+            .iter()
+            .map(|slot| match slot {
+                None => MAX_STACK,
+                Some(stack) if stack.item_id == item_id => MAX_STACK.saturating_sub(stack.count),
+                Some(_) => 0,
+            })
+            .sum();
+        if space < count {
+            return Err(InventoryError::Full);
+        }
+
+        let mut changed = Vec::new();
+        let mut remaining = count;
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if let Some(stack) = slot.as_mut().filter(|s| s.item_id == item_id) {
+                let moved = remaining.min(MAX_STACK.saturating_sub(stack.count));
+                if moved > 0 {
+                    stack.count += moved;
+                    remaining -= moved;
+                    changed.push(index);
+                }
+            }
+        }
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+            if slot.is_none() {
+                let moved = remaining.min(MAX_STACK);
+                *slot = Some(ItemStack {
+                    item_id: item_id.to_string(),
+                    count: moved,
+                });
+                remaining -= moved;
+                changed.push(index);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Removes items from wherever they are stored.
+    pub fn remove(&mut self, item_id: &str, count: u32) -> Result<Vec<usize>, InventoryError> {
+        if self.count(item_id) < count {
+            return Err(InventoryError::NotEnough);
+        }
+
+        let mut changed = Vec::new();
+        let mut remaining = count;
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+            if let Some(stack) = slot.as_mut().filter(|s| s.item_id == item_id) {
+                let moved = remaining.min(stack.count);
+                stack.count -= moved;
+                remaining -= moved;
+                if stack.count == 0 {
+                    *slot = None;
+                }
+                changed.push(index);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Moves the stack in `from` onto `to`, merging identical items up to the
+    /// stack limit and swapping otherwise.
+    pub fn move_stack(&mut self, from: usize, to: usize) -> Result<Vec<usize>, InventoryError> {
+        self.check_slot(to)?;
+        self.stack_in(from)?;
+        if from == to {
+            return Ok(Vec::new());
+        }
+
+        let (source, target) = self.pair_mut(from, to);
+        match (source.as_mut(), target.as_mut()) {
+            (Some(src), Some(dst)) if src.item_id == dst.item_id => {
+                let moved = src.count.min(MAX_STACK.saturating_sub(dst.count));
+                dst.count += moved;
+                src.count -= moved;
+                if src.count == 0 {
+                    *source = None;
+                }
+            }
+            _ => std::mem::swap(source, target),
+        }
+        Ok(vec![from, to])
+    }
+
+    /// Moves `count` items from `slot` into the empty slot `to`.
+    pub fn split(
+        &mut self,
+        slot: usize,
+        count: u32,
+        to: usize,
+    ) -> Result<Vec<usize>, InventoryError> {
+        self.check_slot(to)?;
+        let stack = self.stack_in(slot)?;
+        if count == 0 || count >= stack.count {
+            return Err(InventoryError::InvalidCount);
+        }
+        if self.slots[to].is_some() {
+            return Err(InventoryError::SlotOccupied(to));
+        }
+
+        let (source, target) = self.pair_mut(slot, to);
+        let source = source.as_mut().unwrap();
+        source.count -= count;
+        *target = Some(ItemStack {
+            item_id: source.item_id.clone(),
+            count,
+        });
+        Ok(vec![slot, to])
+    }
+
+    /// Removes `count` items from one slot.
+    pub fn take(&mut self, slot: usize, count: u32) -> Result<Vec<usize>, InventoryError> {
+        let stack = self.stack_in(slot)?;
+        if count == 0 || count > stack.count {
+            return Err(InventoryError::InvalidCount);
+        }
+        let stack = self.slots[slot].as_mut().unwrap();
+        stack.count -= count;
+        if stack.count == 0 {
+            self.slots[slot] = None;
+        }
+        Ok(vec![slot])
+    }
+
+    /// The current contents of the given slots, for `InventoryUpdated`.
+    pub fn updates(&self, changed: &[usize]) -> Vec<InventorySlot> {
+        changed
+            .iter()
+            .map(|&slot| InventorySlot {
+                slot,
+                stack: self.slots[slot].clone(),
+            })
+            .collect()
+    }
+
+    fn check_slot(&self, slot: usize) -> Result<(), InventoryError> {
+        if slot < self.slots.len() {
+            Ok(())
+        } else {
+            Err(InventoryError::InvalidSlot(slot))
+        }
+    }
+
+    fn stack_in(&self, slot: usize) -> Result<&ItemStack, InventoryError> {
+        self.check_slot(slot)?;
+        self.slots[slot]
+            .as_ref()
+            .ok_or(InventoryError::EmptySlot(slot))
+    }
+
+    fn pair_mut(&mut self, a: usize, b: usize) -> (&mut Option<ItemStack>, &mut Option<ItemStack>) {
+        if a < b {
+            let (left, right) = self.slots.split_at_mut(b);
+            (&mut left[a], &mut right[0])
+        } else {
+            let (left, right) = self.slots.split_at_mut(a);
+            (&mut right[0], &mut left[b])
+        }
+    }
+}
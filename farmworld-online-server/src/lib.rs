@@ -1,3 +1,8 @@
+pub mod auth;
+pub mod config;
+pub mod inventory;
 pub mod messages;
 pub mod net;
-pub mod sim;
\ No newline at end of file
+pub mod persistence;
+pub mod sim;
+pub mod world;
@@ -1,12 +1,71 @@
-mod messages;
-mod net;
-mod sim;
-
 use bevy::prelude::*;
+use farmworld_online_server::{auth, config, net, persistence, sim, world};
+use std::sync::Arc;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc;
 
 fn main() {
+    let env = |name: &str| std::env::var(name).ok();
+    let config = match config::ServerConfig::load(std::env::args().skip(1), env) {
+        Ok(config::Launch::Run(config)) => *config,
+        Ok(config::Launch::PrintDefaultConfig) => {
+            print!("{}", config::DEFAULT_CONFIG);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Load and validate game content before accepting any players
+    let crop_catalog = match sim::CropCatalog::load(&config.crop_catalog) {
+        Ok(catalog) => catalog,
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", config.crop_catalog.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let world_map = match world::WorldMap::load(&config.world_file) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", config.world_file.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let spawn = config.spawn_point;
+    if !world_map.is_box_free(spawn.x, spawn.y, sim::PLAYER_HALF_EXTENT) {
+        eprintln!(
+            "Invalid configuration: spawn_point ({}, {}) is not open ground in {}",
+            spawn.x,
+            spawn.y,
+            config.world_file.display()
+        );
+        std::process::exit(1);
+    }
+
+    // Saved world state, see persistence.rs
+    let db_path = config.save_path.display().to_string();
+    let storage = match persistence::Storage::open(&db_path) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", db_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let accounts = match auth::Accounts::open(&db_path) {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", db_path, e);
+            std::process::exit(1);
+        }
+    };
+    let auth = Arc::new(auth::Auth {
+        accounts,
+        sessions: auth::SessionKeys::generate(),
+    });
+
     // Create two main communication channels
     // Channel 1: Client messages flow to Bevy ECS simulation
     let (client_to_sim_tx, client_to_sim_rx) = mpsc::unbounded_channel::<sim::EcsCommand>();
@@ -16,31 +75,95 @@ fn main() {
         mpsc::unbounded_channel::<sim::ServerToClientMessage>();
 
     // Run Bevy ECS simulation in a separate thread
-    std::thread::spawn(|| {
-        App::new()
+    let (restored_tx, restored_rx) = std::sync::mpsc::channel();
+    let shutdown_tx = client_to_sim_tx.clone();
+    let bind = config.bind.clone();
+    let net_config = config.net_config();
+    let sim_thread = std::thread::spawn(move || {
+        let mut app = App::new();
+        // Restore the saved world before any player can join
+        let restored = persistence::restore_world(app.world_mut(), storage);
+        let failed = restored.is_err();
+        let _ = restored_tx.send(restored);
+        if failed {
+            return;
+        }
+
+        app.insert_resource(crop_catalog)
+            .insert_resource(world_map)
             .insert_resource(sim::CommandQueue {
                 rx: client_to_sim_rx,
             })
             .insert_resource(sim::ServerToClientQueue {
                 tx: sim_to_client_tx,
             })
-            .insert_resource(sim::BroadcastTimer { last_broadcast: 0.0_f32 })
+            .insert_resource(persistence::SaveTimer {
+                interval: config.save_interval,
+                last_save: 0.0,
+            })
+            .insert_resource(sim::SnapshotInterval {
+                ticks: config.snapshot_interval_ticks(),
+            })
+            .insert_resource(config.clone())
             .add_plugins(MinimalPlugins) // no graphics
-            .add_systems(
-                Update,
-                (
-                    sim::process_commands,
-                    sim::movement_system,
-                    sim::broadcast_positions,
-                ),
-            )
+            .add_plugins(sim::SimulationPlugin {
+                tick_rate: config.tick_rate,
+            })
+            .add_systems(Update, persistence::save_system)
+            .add_systems(Last, persistence::save_on_exit)
             .run();
     });
+    match restored_rx.recv() {
+        Ok(Ok(())) => println!("Restored world from {}", db_path),
+        Ok(Err(e)) => {
+            eprintln!("Failed to restore world from {}: {}", db_path, e);
+            std::process::exit(1);
+        }
+        Err(_) => {
+            eprintln!("Simulation thread exited during startup");
+            std::process::exit(1);
+        }
+    }
 
     // Run WebSocket server on Tokio runtime
     // Net layer owns: sender to sim, receiver from sim
     let rt = Runtime::new().unwrap();
     rt.block_on(async {
-        net::run_websocket_server("127.0.0.1:9001", client_to_sim_tx, sim_to_client_rx).await;
+        let shutdown = async {
+            shutdown_signal().await;
+            net::ShutdownNotice {
+                reason: "server is shutting down".to_string(),
+                reconnect_after: None,
+            }
+        };
+        net::run_websocket_server(
+            &bind,
+            net_config,
+            auth,
+            client_to_sim_tx,
+            sim_to_client_rx,
+            shutdown,
+        )
+        .await;
     });
+
+    // Queued behind the departing players' commands, so the simulation
+    // handles those, saves once more and exits
+    let _ = shutdown_tx.send(sim::EcsCommand::Shutdown);
+    let _ = sim_thread.join();
+}
+
+/// Waits for Ctrl-C, or SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
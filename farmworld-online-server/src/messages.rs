@@ -1,24 +1,501 @@
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The protocol this server speaks. Bump it whenever messages change in a way
+/// older clients can't cope with.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Client protocol versions the server still accepts.
+pub const SUPPORTED_PROTOCOL_VERSIONS: RangeInclusive<u32> = 1..=PROTOCOL_VERSION;
+/// Optional features a client can ask for in `Hello`.
+pub const SERVER_CAPABILITIES: &[&str] = &["msgpack", "resume"];
+
+/// A `ClientMessage` as it arrives on the wire. Clients may tag it with a
+/// `request_id` of their choosing to have it acknowledged with an `Ack`;
+/// errors about it echo the id back too.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientRequest {
+    pub request_id: Option<u32>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+impl From<ClientMessage> for ClientRequest {
+    fn from(message: ClientMessage) -> Self {
+        ClientRequest {
+            request_id: None,
+            message,
+        }
+    }
+}
+
+/// The `action` of every `ClientMessage`.
+pub const CLIENT_ACTIONS: &[&str] = &[
+    "Hello",
+    "Register",
+    "Login",
+    "Resume",
+    "Join",
+    "Move",
+    "Input",
+    "PlantCrop",
+    "WaterPlot",
+    "Harvest",
+    "MoveItem",
+    "SplitStack",
+    "DropItem",
+    "UseItem",
+    "Chat",
+    "JoinGuild",
+    "LeaveGuild",
+    "AckSnapshot",
+];
+
+/// What can still be read from a request that doesn't decode.
+#[derive(Deserialize)]
+struct RequestHeader {
+    action: Option<String>,
+    request_id: Option<u32>,
+}
+
+/// Why a client's message couldn't be decoded, as told back to the client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MalformedRequest {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Echoed back when it could be read despite the rest
+    pub request_id: Option<u32>,
+}
+
+impl MalformedRequest {
+    /// Works out why `bytes` failed to decode as a `ClientRequest`.
+    pub fn diagnose(format: WireFormat, bytes: &[u8], error: &DecodeError) -> Self {
+        let Ok(header) = format.decode::<RequestHeader>(bytes) else {
+            return MalformedRequest {
+                code: ErrorCode::MalformedMessage,
+                message: error.to_string(),
+                request_id: None,
+            };
+        };
+        let (code, message) = match header.action {
+            Some(action) if !CLIENT_ACTIONS.contains(&action.as_str()) => (
+                ErrorCode::UnknownAction,
+                format!("unknown action {:?}", action),
+            ),
+            _ => (ErrorCode::MalformedMessage, error.to_string()),
+        };
+        MalformedRequest {
+            code,
+            message,
+            request_id: header.request_id,
+        }
+    }
+
+    pub fn into_message(self) -> ServerMessage {
+        ServerMessage::Error {
+            code: self.code,
+            message: self.message,
+            request_id: self.request_id,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "action", content = "data")]
 pub enum ClientMessage {
+    /// Must be the first message on every connection.
+    Hello {
+        protocol_version: u32,
+        /// Free-form build id, only used for logging
+        client_build: String,
+        capabilities: Vec<String>,
+    },
+    Register {
+        username: String,
+        password: String,
+    },
+    Login {
+        username: String,
+        password: String,
+    },
+    /// Picks a session back up after a dropped connection and puts the player
+    /// back in the world, no `Join` needed.
+    Resume {
+        session_token: String,
+    },
     Join,
-    Move { dx: f32, dy: f32 },
+    Move {
+        dx: f32,
+        dy: f32,
+    },
+    /// Movement input number `seq`, meant for simulation `tick`. Inputs are
+    /// applied in `seq` order and snapshots report the last one applied, so
+    /// clients can predict ahead and reconcile.
+    Input {
+        seq: u32,
+        dx: f32,
+        dy: f32,
+        tick: u64,
+    },
+    PlantCrop {
+        x: i32,
+        y: i32,
+        crop_type: String,
+    },
+    WaterPlot {
+        x: i32,
+        y: i32,
+    },
+    Harvest {
+        x: i32,
+        y: i32,
+    },
+    MoveItem {
+        from: usize,
+        to: usize,
+    },
+    SplitStack {
+        slot: usize,
+        count: u32,
+        to: usize,
+    },
+    DropItem {
+        slot: usize,
+        count: u32,
+    },
+    UseItem {
+        slot: usize,
+        x: i32,
+        y: i32,
+    },
+    Chat {
+        channel: ChatChannel,
+        text: String,
+    },
+    /// Joins the guild called `guild`, leaving any other. A guild exists for
+    /// as long as someone is in it.
+    JoinGuild {
+        guild: String,
+    },
+    LeaveGuild,
+    /// Confirms a `Snapshot` arrived, so later ones can be sent as deltas
+    /// against it.
+    AckSnapshot {
+        seq: u32,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "event", content = "data")]
 pub enum ServerMessage {
-    PlayerJoined { player_id: Uuid, x: f32, y: f32 },
-    PlayerState { players: Vec<PlayerState> },
-    PlayerLeft { player_id: Uuid },
+    /// Accepts a `Hello`, listing the requested capabilities the server has.
+    Welcome {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    },
+    /// Turns a connection away; the server closes it right after.
+    Rejected {
+        reason: String,
+    },
+    PlayerJoined {
+        player_id: Uuid,
+        x: f32,
+        y: f32,
+    },
+    /// Positions of the players in view as of snapshot `seq`, taken on
+    /// simulation `tick`. Without a `baseline` this is a full keyframe.
+    /// Otherwise it only lists players that moved or came into view since the
+    /// acknowledged `baseline` snapshot, which clients apply to their copy of
+    /// that snapshot.
+    Snapshot {
+        seq: u32,
+        tick: u64,
+        baseline: Option<u32>,
+        players: Vec<PlayerState>,
+    },
+    /// A player came into view. `entity_id` is their player id.
+    EntityEntered {
+        entity_id: Uuid,
+        x: f32,
+        y: f32,
+    },
+    /// A player went out of view or left the world.
+    EntityLeft {
+        entity_id: Uuid,
+    },
+    PlayerLeft {
+        player_id: Uuid,
+    },
+    CropPlanted {
+        x: i32,
+        y: i32,
+        crop_type: String,
+        stage: u8,
+        watered: bool,
+    },
+    PlotWatered {
+        x: i32,
+        y: i32,
+    },
+    CropGrew {
+        x: i32,
+        y: i32,
+        stage: u8,
+    },
+    CropHarvested {
+        x: i32,
+        y: i32,
+        crop_type: String,
+        player_id: Uuid,
+    },
+    ContentManifest {
+        crops: Vec<CropDef>,
+    },
+    InventoryUpdated {
+        slots: Vec<InventorySlot>,
+    },
+    ChatMessage {
+        channel: ChatChannel,
+        player_id: Uuid,
+        text: String,
+    },
+    /// The player's guild, sent when it changes and when they enter the
+    /// world in one.
+    GuildChanged {
+        guild: Option<String>,
+    },
+    /// The connection's round-trip time in milliseconds, measured with the
+    /// server's pings. Sent after every pong.
+    Latency {
+        rtt_ms: u32,
+    },
+    LoggedIn {
+        player_id: Uuid,
+        username: String,
+        session_token: String,
+    },
+    /// A request failed or was turned down. `request_id` is the one the
+    /// client gave the request, if any; requests the simulation turns down
+    /// are answered with an `Ack` instead when they have one.
+    Error {
+        code: ErrorCode,
+        message: String,
+        request_id: Option<u32>,
+    },
+    /// The simulation processed the request the client tagged `request_id`.
+    /// Requests it turned down say why in `result`.
+    Ack {
+        request_id: u32,
+        result: Result<(), RequestError>,
+    },
+    /// The server is going down and closes the connection right after.
+    /// `reconnect_after` is roughly how many seconds until it is back, if
+    /// that is known.
+    ServerShutdown {
+        reason: String,
+        reconnect_after: Option<u32>,
+    },
+}
+
+/// How messages are encoded on a connection, picked with the WebSocket
+/// subprotocol. JSON is the default since it is easy to read while debugging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    /// MessagePack with named fields, sent as binary frames
+    MessagePack,
+}
+
+impl WireFormat {
+    pub const ALL: [WireFormat; 2] = [WireFormat::Json, WireFormat::MessagePack];
+
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            WireFormat::Json => "farmworld.json",
+            WireFormat::MessagePack => "farmworld.msgpack",
+        }
+    }
+
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.subprotocol() == name.trim())
+    }
+
+    pub fn encode<T: Serialize>(self, message: &T) -> Vec<u8> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(message).expect("messages always serialize"),
+            WireFormat::MessagePack => {
+                rmp_serde::to_vec_named(message).expect("messages always serialize")
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, DecodeError> {
+        match self {
+            WireFormat::Json => serde_json::from_slice(bytes).map_err(DecodeError::Json),
+            WireFormat::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(DecodeError::MessagePack)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Json(serde_json::Error),
+    MessagePack(rmp_serde::decode::Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Json(e) => write!(f, "invalid JSON: {}", e),
+            DecodeError::MessagePack(e) => write!(f, "invalid MessagePack: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A server message encoded once in every wire format, so it can go out to
+/// any number of clients without being serialized again. Cloning only bumps
+/// reference counts.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    json: Bytes,
+    msgpack: Bytes,
+}
+
+impl Frame {
+    pub fn encode(message: &ServerMessage) -> Self {
+        Frame {
+            json: WireFormat::Json.encode(message).into(),
+            msgpack: WireFormat::MessagePack.encode(message).into(),
+        }
+    }
+
+    pub fn decode(&self, format: WireFormat) -> Result<ServerMessage, DecodeError> {
+        format.decode(self.bytes(format))
+    }
+
+    pub fn bytes(&self, format: WireFormat) -> &Bytes {
+        match format {
+            WireFormat::Json => &self.json,
+            WireFormat::MessagePack => &self.msgpack,
+        }
+    }
+}
+
+/// Why a request was turned down, for clients to act on. `Error::message` is
+/// meant for people.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    NotAuthenticated,
+    AlreadyLoggedIn,
+    /// This connection was replaced by a newer login to the same account
+    LoggedInElsewhere,
+    InvalidSession,
+    InvalidUsername,
+    WeakPassword,
+    UsernameTaken,
+    InvalidCredentials,
+    /// The server is disconnecting this client for misbehaving
+    Kicked,
+    /// The client sent messages faster than it may
+    RateLimited,
+    /// The client sent nothing for too long
+    IdleTimeout,
+    /// The message couldn't be decoded
+    MalformedMessage,
+    UnknownAction,
+    /// The player has to join the world first
+    NotInWorld,
+    AlreadyInWorld,
+    /// The world already holds `ServerConfig::max_players` players
+    ServerFull,
+    /// Movement that isn't a finite direction
+    InvalidMovement,
+    UnknownCrop,
+    /// Nothing can be planted on that tile
+    NotFarmable,
+    /// The tile is too far from the player
+    OutOfReach,
+    /// Something is already growing there
+    TileOccupied,
+    /// There is no planted plot there
+    NoPlot,
+    AlreadyWatered,
+    NothingToHarvest,
+    NotRipe,
+    /// The player doesn't have what the action takes, e.g. seeds
+    MissingItem,
+    /// The item in that slot can't be used like that
+    NotUsable,
+    InventoryFull,
+    InvalidSlot,
+    InvalidCount,
+    EmptyMessage,
+    /// The player a message was meant for isn't online
+    PlayerNotFound,
+    /// Guild names follow the same rules as usernames
+    InvalidGuildName,
+    NotInGuild,
+    ServerError,
+}
+
+/// Why the simulation turned a request down.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestError {
+    pub code: ErrorCode,
+    pub message: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Who a chat message is delivered to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatChannel {
+    /// Players standing near the speaker
+    Say,
+    /// Everyone on the server
+    Global,
+    /// A single player, plus an echo back to the speaker
+    Whisper { to: Uuid },
+    /// Members of the speaker's guild
+    Guild,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct PlayerState {
     pub player_id: Uuid,
     pub x: f32,
     pub y: f32,
+    /// `seq` of the player's last applied `ClientMessage::Input`, if any.
+    pub last_processed_input: Option<u32>,
+}
+
+/// A crop type as listed in the content file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CropDef {
+    pub id: String,
+    /// Seconds each growth stage takes; the crop is ripe after the last one.
+    pub stage_seconds: Vec<f32>,
+    pub needs_water: bool,
+    pub seasons: Vec<String>,
+    pub yield_amount: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemStack {
+    pub item_id: String,
+    pub count: u32,
+}
+
+/// The new contents of one inventory slot; `None` means the slot is empty.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InventorySlot {
+    pub slot: usize,
+    pub stack: Option<ItemStack>,
 }
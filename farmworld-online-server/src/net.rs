@@ -1,16 +1,36 @@
-use crate::messages::ClientMessage;
+use crate::auth::{Auth, AuthError, AuthenticatedAccount, LoginRequest};
+use crate::messages::{
+    ClientMessage, ClientRequest, ErrorCode, Frame, MalformedRequest, PROTOCOL_VERSION,
+    SERVER_CAPABILITIES, SUPPORTED_PROTOCOL_VERSIONS, ServerMessage, WireFormat,
+};
 use crate::sim::{EcsCommand, ServerToClientMessage};
+use bytes::Bytes;
 use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio_tungstenite::accept_async;
-use tokio_tungstenite::tungstenite::protocol::Message;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender, error::TrySendError};
+use tokio::task::JoinSet;
+use tokio::time::{self, MissedTickBehavior};
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message, WebSocketConfig};
 use uuid::Uuid;
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
     use super::*;
+    use crate::auth::{Accounts, SessionKeys};
+    use crate::messages::{CLIENT_ACTIONS, ChatChannel};
     use tokio::sync::mpsc;
 
     #[test]
@@ -34,6 +54,41 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_valid_plant_crop_message() {
+        let json = r#"{"action":"PlantCrop","data":{"x":10,"y":5,"crop_type":"wheat"}}"#;
+        let result: Result<ClientMessage, _> = serde_json::from_str(json);
+        if let Ok(ClientMessage::PlantCrop { x, y, crop_type }) = result {
+            assert_eq!(x, 10);
+            assert_eq!(y, 5);
+            assert_eq!(crop_type, "wheat");
+        } else {
+            panic!("Expected PlantCrop message");
+        }
+    }
+
+    #[test]
+    fn test_parse_valid_chat_message() {
+        let json = r#"{"action":"Chat","data":{"channel":{"Whisper":{"to":"67e55044-10b1-426f-9247-bb680e5fe0c8"}},"text":"hi"}}"#;
+        let result: Result<ClientMessage, _> = serde_json::from_str(json);
+        if let Ok(ClientMessage::Chat { channel, text }) = result {
+            assert!(matches!(channel, ChatChannel::Whisper { .. }));
+            assert_eq!(text, "hi");
+        } else {
+            panic!("Expected Chat message");
+        }
+
+        let json = r#"{"action":"Chat","data":{"channel":"Say","text":"hello"}}"#;
+        let result: Result<ClientMessage, _> = serde_json::from_str(json);
+        assert!(matches!(
+            result,
+            Ok(ClientMessage::Chat {
+                channel: ChatChannel::Say,
+                ..
+            })
+        ));
+    }
+
     #[test]
     fn test_parse_malformed_json() {
         let json = r#"{"action":"Move","data":{"dx":1.5,"dy":}}"#; // Missing dy value
@@ -48,6 +103,292 @@ mod tests {
         assert!(result.is_err());
     }
 
+    fn every_client_message() -> Vec<ClientMessage> {
+        let credentials = || ("alice".to_string(), "correct horse".to_string());
+        let (username, password) = credentials();
+        let register = ClientMessage::Register { username, password };
+        let (username, password) = credentials();
+        vec![
+            hello(),
+            register,
+            ClientMessage::Login { username, password },
+            ClientMessage::Resume {
+                session_token: "token".to_string(),
+            },
+            ClientMessage::Join,
+            ClientMessage::Move { dx: 1.5, dy: -2.0 },
+            ClientMessage::Input {
+                seq: 3,
+                dx: 0.0,
+                dy: 1.0,
+                tick: 1200,
+            },
+            ClientMessage::PlantCrop {
+                x: 10,
+                y: -5,
+                crop_type: "wheat".to_string(),
+            },
+            ClientMessage::WaterPlot { x: 1, y: 2 },
+            ClientMessage::Harvest { x: 3, y: 4 },
+            ClientMessage::MoveItem { from: 0, to: 8 },
+            ClientMessage::SplitStack {
+                slot: 1,
+                count: 3,
+                to: 2,
+            },
+            ClientMessage::DropItem { slot: 4, count: 1 },
+            ClientMessage::UseItem {
+                slot: 0,
+                x: 5,
+                y: 6,
+            },
+            ClientMessage::Chat {
+                channel: ChatChannel::Whisper { to: Uuid::new_v4() },
+                text: "hi".to_string(),
+            },
+            ClientMessage::Chat {
+                channel: ChatChannel::Say,
+                text: "hello".to_string(),
+            },
+            ClientMessage::JoinGuild {
+                guild: "growers".to_string(),
+            },
+            ClientMessage::LeaveGuild,
+            ClientMessage::AckSnapshot { seq: 42 },
+        ]
+    }
+
+    fn every_server_message() -> Vec<ServerMessage> {
+        use crate::messages::{CropDef, InventorySlot, ItemStack, PlayerState, RequestError};
+        let player_id = Uuid::new_v4();
+        vec![
+            ServerMessage::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec!["msgpack".to_string()],
+            },
+            ServerMessage::Rejected {
+                reason: "too old".to_string(),
+            },
+            ServerMessage::PlayerJoined {
+                player_id,
+                x: 1.0,
+                y: 2.0,
+            },
+            ServerMessage::Snapshot {
+                seq: 7,
+                tick: 140,
+                baseline: None,
+                players: vec![PlayerState {
+                    player_id,
+                    x: 3.5,
+                    y: 4.5,
+                    last_processed_input: Some(12),
+                }],
+            },
+            ServerMessage::Snapshot {
+                seq: 8,
+                tick: 141,
+                baseline: Some(7),
+                players: vec![],
+            },
+            ServerMessage::EntityEntered {
+                entity_id: player_id,
+                x: 1.0,
+                y: 2.0,
+            },
+            ServerMessage::EntityLeft {
+                entity_id: player_id,
+            },
+            ServerMessage::PlayerLeft { player_id },
+            ServerMessage::CropPlanted {
+                x: 1,
+                y: 2,
+                crop_type: "wheat".to_string(),
+                stage: 0,
+                watered: false,
+            },
+            ServerMessage::PlotWatered { x: 1, y: 2 },
+            ServerMessage::CropGrew {
+                x: 1,
+                y: 2,
+                stage: 3,
+            },
+            ServerMessage::CropHarvested {
+                x: 1,
+                y: 2,
+                crop_type: "wheat".to_string(),
+                player_id,
+            },
+            ServerMessage::ContentManifest {
+                crops: vec![CropDef {
+                    id: "wheat".to_string(),
+                    stage_seconds: vec![30.0, 45.5],
+                    needs_water: true,
+                    seasons: vec!["spring".to_string()],
+                    yield_amount: 2,
+                }],
+            },
+            ServerMessage::InventoryUpdated {
+                slots: vec![
+                    InventorySlot {
+                        slot: 0,
+                        stack: Some(ItemStack {
+                            item_id: "wheat_seeds".to_string(),
+                            count: 5,
+                        }),
+                    },
+                    InventorySlot {
+                        slot: 1,
+                        stack: None,
+                    },
+                ],
+            },
+            ServerMessage::ChatMessage {
+                channel: ChatChannel::Guild,
+                player_id,
+                text: "hello".to_string(),
+            },
+            ServerMessage::LoggedIn {
+                player_id,
+                username: "alice".to_string(),
+                session_token: "token".to_string(),
+            },
+            ServerMessage::Error {
+                code: ErrorCode::LoggedInElsewhere,
+                message: "logged in from another connection".to_string(),
+                request_id: None,
+            },
+            ServerMessage::Error {
+                code: ErrorCode::OutOfReach,
+                message: "that tile is out of reach".to_string(),
+                request_id: Some(3),
+            },
+            ServerMessage::Ack {
+                request_id: 4,
+                result: Ok(()),
+            },
+            ServerMessage::Ack {
+                request_id: 5,
+                result: Err(RequestError {
+                    code: ErrorCode::NotRipe,
+                    message: "not ripe yet".to_string(),
+                }),
+            },
+            ServerMessage::ServerShutdown {
+                reason: "maintenance".to_string(),
+                reconnect_after: Some(60),
+            },
+            ServerMessage::GuildChanged {
+                guild: Some("growers".to_string()),
+            },
+            ServerMessage::Latency { rtt_ms: 42 },
+        ]
+    }
+
+    #[test]
+    fn test_client_messages_round_trip() {
+        for format in WireFormat::ALL {
+            for message in every_client_message() {
+                let encoded = format.encode(&message);
+                let decoded: ClientMessage = format.decode(&encoded).unwrap();
+                assert_eq!(decoded, message, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn test_client_requests_round_trip() {
+        for format in WireFormat::ALL {
+            for (i, message) in every_client_message().into_iter().enumerate() {
+                let request = ClientRequest {
+                    request_id: (i % 2 == 0).then_some(i as u32),
+                    message,
+                };
+                let encoded = format.encode(&request);
+                let decoded: ClientRequest = format.decode(&encoded).unwrap();
+                assert_eq!(decoded, request, "{:?}", format);
+            }
+        }
+
+        // Plain messages are requests without an id
+        let json = r#"{"action":"Move","data":{"dx":1.0,"dy":0.0}}"#;
+        let request: ClientRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            request,
+            ClientRequest::from(ClientMessage::Move { dx: 1.0, dy: 0.0 })
+        );
+        let json = r#"{"request_id":7,"action":"Join"}"#;
+        let request: ClientRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(request.request_id, Some(7));
+    }
+
+    #[test]
+    fn test_every_action_is_known() {
+        for message in every_client_message() {
+            let json = serde_json::to_value(&message).unwrap();
+            let action = json["action"].as_str().unwrap();
+            assert!(CLIENT_ACTIONS.contains(&action), "{}", action);
+        }
+    }
+
+    #[test]
+    fn test_malformed_requests_are_diagnosed() {
+        let diagnose = |json: &str| {
+            let error = WireFormat::Json
+                .decode::<ClientRequest>(json.as_bytes())
+                .unwrap_err();
+            MalformedRequest::diagnose(WireFormat::Json, json.as_bytes(), &error)
+        };
+
+        let malformed = diagnose(r#"{"action":"Move","data":{"dx":1.5,"dy":}}"#);
+        assert_eq!(malformed.code, ErrorCode::MalformedMessage);
+        assert_eq!(malformed.request_id, None);
+
+        let unknown = diagnose(r#"{"request_id":4,"action":"Fly","data":{}}"#);
+        assert_eq!(unknown.code, ErrorCode::UnknownAction);
+        assert_eq!(unknown.request_id, Some(4));
+
+        // A known action with the wrong data still gets its id back
+        let invalid = diagnose(r#"{"request_id":5,"action":"Harvest","data":{"x":"a"}}"#);
+        assert_eq!(invalid.code, ErrorCode::MalformedMessage);
+        assert_eq!(invalid.request_id, Some(5));
+
+        let msgpack = rmp_serde::to_vec_named(&serde_json::json!({"action": "Fly"})).unwrap();
+        let error = WireFormat::MessagePack
+            .decode::<ClientRequest>(&msgpack)
+            .unwrap_err();
+        let unknown = MalformedRequest::diagnose(WireFormat::MessagePack, &msgpack, &error);
+        assert_eq!(unknown.code, ErrorCode::UnknownAction);
+    }
+
+    #[test]
+    fn test_server_messages_round_trip() {
+        for format in WireFormat::ALL {
+            for message in every_server_message() {
+                let encoded = format.encode(&message);
+                let decoded: ServerMessage = format.decode(&encoded).unwrap();
+                assert_eq!(decoded, message, "{:?}", format);
+
+                // Pre-encoded frames carry the same bytes
+                let frame = Frame::encode(&message);
+                assert_eq!(frame.bytes(format).as_ref(), encoded.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn test_wire_format_from_subprotocol() {
+        assert_eq!(
+            WireFormat::from_subprotocol(" farmworld.msgpack"),
+            Some(WireFormat::MessagePack)
+        );
+        assert_eq!(
+            WireFormat::from_subprotocol("farmworld.json"),
+            Some(WireFormat::Json)
+        );
+        assert_eq!(WireFormat::from_subprotocol("chat"), None);
+    }
+
     #[test]
     fn test_command_routing_join() {
         let (tx, mut _rx) = mpsc::unbounded_channel();
@@ -61,13 +402,19 @@ mod tests {
                 // Join is handled by SpawnPlayer above
             }
             ClientMessage::Move { dx, dy } => {
-                let cmd = EcsCommand::UpdateVelocity { player_id, dx, dy };
+                let cmd = EcsCommand::UpdateVelocity {
+                    player_id,
+                    request_id: None,
+                    dx,
+                    dy,
+                };
                 let _ = tx.send(cmd);
             }
+            _ => {}
         }
 
         // Verify no command was sent for Join
-        assert!(matches!(_rx.try_recv(), Err(_)));
+        assert!(_rx.try_recv().is_err());
     }
 
     #[test]
@@ -81,9 +428,15 @@ mod tests {
         match client_msg {
             ClientMessage::Join => {}
             ClientMessage::Move { dx, dy } => {
-                let cmd = EcsCommand::UpdateVelocity { player_id, dx, dy };
+                let cmd = EcsCommand::UpdateVelocity {
+                    player_id,
+                    request_id: None,
+                    dx,
+                    dy,
+                };
                 let _ = tx.send(cmd);
             }
+            _ => {}
         }
 
         // Verify command was sent
@@ -93,6 +446,7 @@ mod tests {
                 player_id: pid,
                 dx,
                 dy,
+                ..
             } => {
                 assert_eq!(pid, player_id);
                 assert_eq!(dx, 1.0);
@@ -109,46 +463,1326 @@ mod tests {
         assert!(result.is_err());
         // Should not panic, just return error
     }
+
+    /// Starts a server on a free port with an empty account database.
+    async fn test_server() -> (
+        String,
+        mpsc::UnboundedReceiver<EcsCommand>,
+        mpsc::UnboundedSender<ServerToClientMessage>,
+    ) {
+        test_server_with(NetConfig::default()).await
+    }
+
+    async fn test_server_with(
+        config: NetConfig,
+    ) -> (
+        String,
+        mpsc::UnboundedReceiver<EcsCommand>,
+        mpsc::UnboundedSender<ServerToClientMessage>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let auth = Arc::new(Auth {
+            accounts: Accounts::open_in_memory().unwrap(),
+            sessions: SessionKeys::generate(),
+        });
+        let (client_to_sim_tx, client_to_sim_rx) = mpsc::unbounded_channel();
+        let (sim_to_net_tx, sim_to_net_rx) = mpsc::unbounded_channel();
+        tokio::spawn(serve(
+            listener,
+            config,
+            auth,
+            client_to_sim_tx,
+            sim_to_net_rx,
+            std::future::pending(),
+        ));
+        (url, client_to_sim_rx, sim_to_net_tx)
+    }
+
+    type TestClient =
+        tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>;
+
+    async fn next_server_message(client: &mut TestClient) -> ServerMessage {
+        loop {
+            if let Message::Text(text) = client.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests_get_error_replies() {
+        let (url, mut sim_rx, _sim_tx) = test_server().await;
+        let mut client = connect(&url).await;
+
+        let send = |json: &'static str| Message::Text(json.into());
+        client.send(send(r#"{"action":"Join""#)).await.unwrap();
+        assert!(matches!(
+            next_server_message(&mut client).await,
+            ServerMessage::Error {
+                code: ErrorCode::MalformedMessage,
+                request_id: None,
+                ..
+            }
+        ));
+
+        client
+            .send(send(
+                r#"{"request_id":1,"action":"Teleport","data":{"x":0}}"#,
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_server_message(&mut client).await,
+            ServerMessage::Error {
+                code: ErrorCode::UnknownAction,
+                request_id: Some(1),
+                ..
+            }
+        ));
+
+        // Requests the client isn't allowed to make yet
+        client
+            .send(send(
+                r#"{"request_id":2,"action":"Harvest","data":{"x":1,"y":2}}"#,
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_server_message(&mut client).await,
+            ServerMessage::Error {
+                code: ErrorCode::NotAuthenticated,
+                request_id: Some(2),
+                ..
+            }
+        ));
+
+        // Valid ones carry their id on to the simulation
+        register(&mut client).await;
+        client
+            .send(send(
+                r#"{"request_id":3,"action":"Harvest","data":{"x":1,"y":2}}"#,
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::Harvest {
+                request_id: Some(3),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_login_required_before_playing() {
+        let (url, mut sim_rx, _sim_tx) = test_server().await;
+        let mut client = connect(&url).await;
+
+        let send = |json: &'static str| Message::Text(json.into());
+        client
+            .send(send(r#"{"action":"Move","data":{"dx":1.0,"dy":0.0}}"#))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_server_message(&mut client).await,
+            ServerMessage::Error {
+                code: ErrorCode::NotAuthenticated,
+                ..
+            }
+        ));
+
+        client
+            .send(send(
+                r#"{"action":"Login","data":{"username":"alice","password":"correct horse"}}"#,
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_server_message(&mut client).await,
+            ServerMessage::Error {
+                code: ErrorCode::InvalidCredentials,
+                ..
+            }
+        ));
+
+        client
+            .send(send(
+                r#"{"action":"Register","data":{"username":"alice","password":"correct horse"}}"#,
+            ))
+            .await
+            .unwrap();
+        let ServerMessage::LoggedIn {
+            player_id,
+            username,
+            session_token,
+        } = next_server_message(&mut client).await
+        else {
+            panic!("Expected LoggedIn");
+        };
+        assert_eq!(username, "alice");
+        assert!(session_token.starts_with(&player_id.to_string()));
+
+        // Nothing reached the sim before logging in
+        assert!(sim_rx.try_recv().is_err());
+        client.send(send(r#"{"action":"Join"}"#)).await.unwrap();
+        match sim_rx.recv().await.unwrap() {
+            EcsCommand::SpawnPlayer {
+                player_id: pid,
+                account,
+                ..
+            } => {
+                assert_eq!(pid, player_id);
+                assert_eq!(account, "alice");
+            }
+            _ => panic!("Expected SpawnPlayer command"),
+        }
+
+        client.close(None).await.unwrap();
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::DespawnPlayer { player_id: pid } if pid == player_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sim_can_kick_a_client() {
+        let (url, mut sim_rx, sim_tx) = test_server().await;
+        let mut client = connect(&url).await;
+        let register = ClientMessage::Register {
+            username: "alice".to_string(),
+            password: "correct horse".to_string(),
+        };
+        client.send(text(&register)).await.unwrap();
+        let ServerMessage::LoggedIn { player_id, .. } = next_server_message(&mut client).await
+        else {
+            panic!("Expected LoggedIn");
+        };
+
+        sim_tx
+            .send(ServerToClientMessage::Kick {
+                player_id,
+                reason: "invalid movement".to_string(),
+            })
+            .unwrap();
+        assert!(matches!(
+            next_server_message(&mut client).await,
+            ServerMessage::Error {
+                code: ErrorCode::Kicked,
+                ..
+            }
+        ));
+
+        // Ignoring the close frame doesn't keep the player around: they
+        // leave without a grace period and nothing more gets through
+        for _ in 0..5 {
+            let _ = client
+                .send(text(&ClientMessage::Move { dx: 1.0, dy: 0.0 }))
+                .await;
+        }
+        loop {
+            match sim_rx.recv().await.unwrap() {
+                EcsCommand::DespawnPlayer { player_id: pid } if pid == player_id => break,
+                EcsCommand::UpdateVelocity { .. } => {}
+                _ => panic!("Expected DespawnPlayer"),
+            }
+        }
+        let mut close_code = None;
+        while let Some(Ok(msg)) = client.next().await {
+            if let Message::Close(Some(frame)) = msg {
+                close_code = Some(frame.code);
+            }
+        }
+        assert_eq!(close_code, Some(CloseCode::Policy));
+        assert!(sim_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limit = |per_second, burst| RateLimit { per_second, burst };
+        let limits = RateLimits {
+            connection: limit(100.0, 3.0),
+            chat: limit(1.0, 2.0),
+            excess: limit(1.0, 2.0),
+            ..RateLimits::default()
+        };
+        let start = Instant::now();
+
+        // Bursts are fine, then the client is warned once, then dropped
+        let mut limiter = RateLimiter::new(&limits, start);
+        let decisions: Vec<_> = (0..5)
+            .map(|_| limiter.check(MessageKind::Chat, start))
+            .collect();
+        assert_eq!(
+            decisions,
+            [
+                RateDecision::Allow,
+                RateDecision::Allow,
+                RateDecision::Warn,
+                RateDecision::Drop,
+                RateDecision::Disconnect,
+            ]
+        );
+
+        // Each kind has its own limit, within the connection's
+        let mut limiter = RateLimiter::new(&limits, start);
+        assert_eq!(limiter.check(MessageKind::Chat, start), RateDecision::Allow);
+        assert_eq!(limiter.check(MessageKind::Chat, start), RateDecision::Allow);
+        assert_eq!(limiter.check(MessageKind::Chat, start), RateDecision::Warn);
+        assert_eq!(
+            limiter.check(MessageKind::Movement, start),
+            RateDecision::Allow
+        );
+        assert_eq!(
+            limiter.check(MessageKind::Movement, start),
+            RateDecision::Drop
+        );
+
+        // Buckets refill over time
+        let later = start + std::time::Duration::from_secs(1);
+        assert_eq!(limiter.check(MessageKind::Chat, later), RateDecision::Allow);
+        assert_eq!(limiter.check(MessageKind::Chat, later), RateDecision::Drop);
+    }
+
+    /// Registers "alice" and returns her player id.
+    async fn register(client: &mut TestClient) -> Uuid {
+        let register = ClientMessage::Register {
+            username: "alice".to_string(),
+            password: "correct horse".to_string(),
+        };
+        client.send(text(&register)).await.unwrap();
+        let ServerMessage::LoggedIn { player_id, .. } = next_server_message(client).await else {
+            panic!("Expected LoggedIn");
+        };
+        player_id
+    }
+
+    #[tokio::test]
+    async fn test_flooding_client_is_warned_then_disconnected() {
+        let mut config = NetConfig::default();
+        config.rate_limits.chat = RateLimit {
+            per_second: 0.001,
+            burst: 1.0,
+        };
+        config.rate_limits.excess = RateLimit {
+            per_second: 0.001,
+            burst: 3.0,
+        };
+        let (url, mut sim_rx, _sim_tx) = test_server_with(config).await;
+        let mut client = connect(&url).await;
+        let player_id = register(&mut client).await;
+
+        let chat = ClientMessage::Chat {
+            channel: ChatChannel::Global,
+            text: "spam".to_string(),
+        };
+        for _ in 0..5 {
+            client.send(text(&chat)).await.unwrap();
+        }
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::Chat { .. }
+        ));
+        for _ in 0..2 {
+            assert!(matches!(
+                next_server_message(&mut client).await,
+                ServerMessage::Error {
+                    code: ErrorCode::RateLimited,
+                    ..
+                }
+            ));
+        }
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::DespawnPlayer { player_id: pid } if pid == player_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_ends_the_connection() {
+        let config = NetConfig {
+            max_message_size: 1024,
+            ..NetConfig::default()
+        };
+        let (url, mut sim_rx, _sim_tx) = test_server_with(config).await;
+        let mut client = connect(&url).await;
+        let player_id = register(&mut client).await;
+
+        let chat = ClientMessage::Chat {
+            channel: ChatChannel::Global,
+            text: "a".repeat(4096),
+        };
+        let _ = client.send(text(&chat)).await;
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::DisconnectPlayer { player_id: pid } if pid == player_id
+        ));
+    }
+
+    /// Waits for the next command from the connection while the client keeps
+    /// reading, and so answering pings.
+    async fn next_command_while_reading(
+        client: &mut TestClient,
+        sim_rx: &mut mpsc::UnboundedReceiver<EcsCommand>,
+    ) -> EcsCommand {
+        let read = async { while client.next().await.is_some() {} };
+        let wait = async {
+            tokio::select! {
+                cmd = sim_rx.recv() => cmd.unwrap(),
+                _ = read => panic!("connection closed"),
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pings_measure_round_trip_time() {
+        let config = NetConfig {
+            ping_interval: Duration::from_millis(50),
+            ..NetConfig::default()
+        };
+        let (url, mut sim_rx, _sim_tx) = test_server_with(config).await;
+        let mut client = connect(&url).await;
+        let player_id = register(&mut client).await;
+
+        match next_command_while_reading(&mut client, &mut sim_rx).await {
+            EcsCommand::UpdateLatency {
+                player_id: pid,
+                rtt,
+            } => {
+                assert_eq!(pid, player_id);
+                assert!(rtt < Duration::from_secs(1));
+            }
+            _ => panic!("Expected UpdateLatency"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_pings_drop_the_connection() {
+        let config = NetConfig {
+            ping_interval: Duration::from_millis(50),
+            pong_timeout: Duration::from_millis(100),
+            ..NetConfig::default()
+        };
+        let (url, mut sim_rx, _sim_tx) = test_server_with(config).await;
+        let mut client = connect(&url).await;
+        let player_id = register(&mut client).await;
+
+        // Never reads again, so never answers a ping
+        let cmd = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match sim_rx.recv().await.unwrap() {
+                    EcsCommand::UpdateLatency { .. } => continue,
+                    cmd => return cmd,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            cmd,
+            EcsCommand::DisconnectPlayer { player_id: pid } if pid == player_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_is_disconnected() {
+        let config = NetConfig {
+            idle_timeout: Duration::from_millis(200),
+            ..NetConfig::default()
+        };
+        let (url, mut sim_rx, _sim_tx) = test_server_with(config).await;
+        let mut client = connect(&url).await;
+        let player_id = register(&mut client).await;
+
+        assert!(matches!(
+            next_server_message(&mut client).await,
+            ServerMessage::Error {
+                code: ErrorCode::IdleTimeout,
+                ..
+            }
+        ));
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::DespawnPlayer { player_id: pid } if pid == player_id
+        ));
+    }
+
+    async fn next_binary_message(client: &mut TestClient) -> ServerMessage {
+        loop {
+            if let Message::Binary(data) = client.next().await.unwrap().unwrap() {
+                return WireFormat::MessagePack.decode(&data).unwrap();
+            }
+        }
+    }
+
+    fn hello() -> ClientMessage {
+        ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "test".to_string(),
+            capabilities: vec![],
+        }
+    }
+
+    /// Connects and gets through the handshake.
+    async fn connect(url: &str) -> TestClient {
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        client.send(text(&hello())).await.unwrap();
+        assert!(matches!(
+            next_server_message(&mut client).await,
+            ServerMessage::Welcome { .. }
+        ));
+        client
+    }
+
+    fn text(msg: &ClientMessage) -> Message {
+        Message::Text(serde_json::to_string(msg).unwrap().into())
+    }
+
+    #[tokio::test]
+    async fn test_hello_handshake() {
+        let (url, mut sim_rx, _sim_tx) = test_server().await;
+
+        let (mut client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let hello = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "test".to_string(),
+            capabilities: vec!["msgpack".to_string(), "teleport".to_string()],
+        };
+        client.send(text(&hello)).await.unwrap();
+        let ServerMessage::Welcome {
+            protocol_version,
+            capabilities,
+        } = next_server_message(&mut client).await
+        else {
+            panic!("Expected Welcome");
+        };
+        assert_eq!(protocol_version, PROTOCOL_VERSION);
+        assert_eq!(capabilities, vec!["msgpack".to_string()]);
+
+        // Anything else first, or an unsupported version, is turned away
+        let too_new = ClientMessage::Hello {
+            protocol_version: SUPPORTED_PROTOCOL_VERSIONS.end() + 1,
+            client_build: "future".to_string(),
+            capabilities: vec![],
+        };
+        for first in [ClientMessage::Join, too_new] {
+            let (mut client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            client.send(text(&first)).await.unwrap();
+            assert!(matches!(
+                next_server_message(&mut client).await,
+                ServerMessage::Rejected { .. }
+            ));
+            match client.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => {
+                    assert_eq!(frame.code, CloseCode::Policy)
+                }
+                other => panic!("Expected a close frame, got {:?}", other),
+            }
+        }
+        assert!(sim_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_messagepack_negotiated_by_subprotocol() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let (url, mut sim_rx, sim_tx) = test_server().await;
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("farmworld.msgpack, farmworld.json"),
+        );
+        let (mut client, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(
+            response.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(),
+            "farmworld.msgpack"
+        );
+
+        let encoded = WireFormat::MessagePack.encode(&hello());
+        client.send(Message::Binary(encoded.into())).await.unwrap();
+        assert!(matches!(
+            next_binary_message(&mut client).await,
+            ServerMessage::Welcome { .. }
+        ));
+
+        let register = ClientMessage::Register {
+            username: "alice".to_string(),
+            password: "correct horse".to_string(),
+        };
+        let encoded = WireFormat::MessagePack.encode(&register);
+        client.send(Message::Binary(encoded.into())).await.unwrap();
+        let ServerMessage::LoggedIn { player_id, .. } = next_binary_message(&mut client).await
+        else {
+            panic!("Expected LoggedIn");
+        };
+
+        let message = ServerMessage::PlayerLeft { player_id };
+        sim_tx
+            .send(ServerToClientMessage::Broadcast { message })
+            .unwrap();
+        assert!(matches!(
+            next_binary_message(&mut client).await,
+            ServerMessage::PlayerLeft { player_id: pid } if pid == player_id
+        ));
+        // So do frames the sim already encoded, whoever else they're for
+        let frame = Frame::encode(&ServerMessage::EntityLeft {
+            entity_id: player_id,
+        });
+        let player_ids = vec![Uuid::new_v4(), player_id];
+        sim_tx
+            .send(ServerToClientMessage::SendFrame { player_ids, frame })
+            .unwrap();
+        assert!(matches!(
+            next_binary_message(&mut client).await,
+            ServerMessage::EntityLeft { entity_id } if entity_id == player_id
+        ));
+
+        // JSON text frames still work on the same connection
+        client.send(text(&ClientMessage::Join)).await.unwrap();
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::SpawnPlayer { player_id: pid, .. } if pid == player_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_client_does_not_hold_up_others() {
+        let config = NetConfig {
+            client_queue_capacity: 4,
+            slow_client_policy: SlowClientPolicy::Disconnect,
+            ..NetConfig::default()
+        };
+        let (url, mut sim_rx, sim_tx) = test_server_with(config).await;
+
+        let mut clients = Vec::new();
+        for username in ["healthy", "stalled"] {
+            let mut client = connect(&url).await;
+            let register = ClientMessage::Register {
+                username: username.to_string(),
+                password: "correct horse".to_string(),
+            };
+            client.send(text(&register)).await.unwrap();
+            let ServerMessage::LoggedIn { player_id, .. } = next_server_message(&mut client).await
+            else {
+                panic!("Expected LoggedIn");
+            };
+            clients.push((client, player_id));
+        }
+        // Never reads another message
+        let (_stalled, stalled_id) = clients.pop().unwrap();
+        let (mut healthy, _) = clients.pop().unwrap();
+
+        // Big enough that the stalled client's socket buffers soon fill up
+        let snapshot = || ServerMessage::Snapshot {
+            seq: 0,
+            tick: 0,
+            baseline: None,
+            players: (0..500)
+                .map(|i| crate::messages::PlayerState {
+                    player_id: Uuid::new_v4(),
+                    x: i as f32,
+                    y: i as f32,
+                    last_processed_input: None,
+                })
+                .collect(),
+        };
+        let mut kicked = false;
+        for _ in 0..1000 {
+            let message = snapshot();
+            sim_tx
+                .send(ServerToClientMessage::Broadcast { message })
+                .unwrap();
+            let received = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                next_server_message(&mut healthy),
+            )
+            .await
+            .expect("healthy client was held up by the stalled one");
+            assert!(matches!(received, ServerMessage::Snapshot { .. }));
+
+            if let Ok(cmd) = sim_rx.try_recv() {
+                assert!(matches!(
+                    cmd,
+                    EcsCommand::DisconnectPlayer { player_id } if player_id == stalled_id
+                ));
+                kicked = true;
+                break;
+            }
+        }
+        assert!(kicked, "stalled client was never disconnected");
+
+        // Everyone else keeps getting updates
+        let message = snapshot();
+        sim_tx
+            .send(ServerToClientMessage::Broadcast { message })
+            .unwrap();
+        assert!(matches!(
+            next_server_message(&mut healthy).await,
+            ServerMessage::Snapshot { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_dropped_connection() {
+        let (url, mut sim_rx, _sim_tx) = test_server().await;
+        let login = ClientMessage::Register {
+            username: "alice".to_string(),
+            password: "correct horse".to_string(),
+        };
+        let mut client = connect(&url).await;
+        client.send(text(&login)).await.unwrap();
+        let ServerMessage::LoggedIn {
+            player_id,
+            session_token,
+            ..
+        } = next_server_message(&mut client).await
+        else {
+            panic!("Expected LoggedIn");
+        };
+
+        // Losing the socket without a close frame only marks them disconnected
+        drop(client);
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::DisconnectPlayer { player_id: pid } if pid == player_id
+        ));
+
+        let mut resumed = connect(&url).await;
+        let forged = ClientMessage::Resume {
+            session_token: format!("{}.4102444800.AAAA", player_id),
+        };
+        resumed.send(text(&forged)).await.unwrap();
+        assert!(matches!(
+            next_server_message(&mut resumed).await,
+            ServerMessage::Error {
+                code: ErrorCode::InvalidSession,
+                ..
+            }
+        ));
+        resumed
+            .send(text(&ClientMessage::Resume { session_token }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_server_message(&mut resumed).await,
+            ServerMessage::LoggedIn { player_id: pid, .. } if pid == player_id
+        ));
+        // The player is put back without a Join
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::SpawnPlayer { player_id: pid, .. } if pid == player_id
+        ));
+
+        // A newer login to the same account replaces this connection
+        let login = ClientMessage::Login {
+            username: "alice".to_string(),
+            password: "correct horse".to_string(),
+        };
+        let mut newest = connect(&url).await;
+        newest.send(text(&login)).await.unwrap();
+        assert!(matches!(
+            next_server_message(&mut newest).await,
+            ServerMessage::LoggedIn { player_id: pid, .. } if pid == player_id
+        ));
+        assert!(matches!(
+            next_server_message(&mut resumed).await,
+            ServerMessage::Error {
+                code: ErrorCode::LoggedInElsewhere,
+                ..
+            }
+        ));
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::DisconnectPlayer { player_id: pid } if pid == player_id
+        ));
+
+        // The replaced socket can't move the player anymore, even if it
+        // ignores the close frame
+        let _ = resumed
+            .send(text(&ClientMessage::Move { dx: 1.0, dy: 0.0 }))
+            .await;
+
+        // Only the connection that owns the player can take it out of the world
+        drop(resumed);
+        newest.close(None).await.unwrap();
+        assert!(matches!(
+            sim_rx.recv().await.unwrap(),
+            EcsCommand::DespawnPlayer { player_id: pid } if pid == player_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_failed_handshake_only_drops_that_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (url, _sim_rx, _sim_tx) = test_server().await;
+        let mut stream = TcpStream::connect(url.trim_start_matches("ws://"))
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: farmworld\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("connection wasn't closed")
+            .unwrap();
+
+        // Everyone else still gets in
+        connect(&url).await;
+    }
 }
 
 pub async fn run_websocket_server(
     addr: &str,
+    config: NetConfig,
+    auth: Arc<Auth>,
     client_to_sim_tx: UnboundedSender<EcsCommand>,
-    mut sim_to_net_rx: UnboundedReceiver<ServerToClientMessage>,
+    sim_to_net_rx: UnboundedReceiver<ServerToClientMessage>,
+    shutdown: impl Future<Output = ShutdownNotice>,
 ) {
     let listener = TcpListener::bind(addr).await.unwrap();
     println!("WebSocket server listening on {}", addr);
+    serve(
+        listener,
+        config,
+        auth,
+        client_to_sim_tx,
+        sim_to_net_rx,
+        shutdown,
+    )
+    .await;
+}
+
+/// What connected clients are told when the server shuts down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownNotice {
+    pub reason: String,
+    /// Roughly how many seconds until the server is back, if known
+    pub reconnect_after: Option<u32>,
+}
+
+/// What to do with a client whose outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Drop the message for that client and carry on
+    Skip,
+    /// Drop the connection; the player can resume and get a fresh snapshot
+    Disconnect,
+}
+
+#[derive(Debug, Clone)]
+pub struct NetConfig {
+    /// How many messages may wait to be written to one client
+    pub client_queue_capacity: usize,
+    pub slow_client_policy: SlowClientPolicy,
+    /// Largest message (and frame) a client may send, in bytes
+    pub max_message_size: usize,
+    pub rate_limits: RateLimits,
+    /// How often clients are pinged to check they're still there
+    pub ping_interval: Duration,
+    /// How long a client has to answer a ping before it counts as gone
+    pub pong_timeout: Duration,
+    /// Clients that send nothing for this long are disconnected
+    pub idle_timeout: Duration,
+    /// How long clients get to answer the close frame on shutdown
+    pub shutdown_grace: Duration,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        Self {
+            client_queue_capacity: 256,
+            slow_client_policy: SlowClientPolicy::Disconnect,
+            max_message_size: 64 * 1024,
+            rate_limits: RateLimits::default(),
+            ping_interval: Duration::from_secs(5),
+            pong_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(15 * 60),
+            shutdown_grace: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A token bucket: up to `burst` messages at once, refilling at `per_second`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+/// How fast each connection may send messages, overall and per kind.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub connection: RateLimit,
+    pub movement: RateLimit,
+    pub actions: RateLimit,
+    pub chat: RateLimit,
+    /// Handshake, login and joining. Logins hash passwords, so keep it low.
+    pub session: RateLimit,
+    /// Snapshot acknowledgements and anything that doesn't parse
+    pub other: RateLimit,
+    /// How many messages over the limits are tolerated before the client is
+    /// disconnected. The first one gets a warning.
+    pub excess: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let limit = |per_second, burst| RateLimit { per_second, burst };
+        Self {
+            connection: limit(100.0, 200.0),
+            movement: limit(60.0, 60.0),
+            actions: limit(10.0, 20.0),
+            chat: limit(1.0, 5.0),
+            session: limit(1.0, 5.0),
+            other: limit(40.0, 40.0),
+            excess: limit(10.0, 100.0),
+        }
+    }
+}
+
+/// Which rate limit a client message counts against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageKind {
+    Movement,
+    Action,
+    Chat,
+    Session,
+    Other,
+}
+
+impl MessageKind {
+    fn of(msg: &ClientMessage) -> Self {
+        match msg {
+            ClientMessage::Hello { .. }
+            | ClientMessage::Register { .. }
+            | ClientMessage::Login { .. }
+            | ClientMessage::Resume { .. }
+            | ClientMessage::Join => MessageKind::Session,
+            ClientMessage::Move { .. } | ClientMessage::Input { .. } => MessageKind::Movement,
+            ClientMessage::PlantCrop { .. }
+            | ClientMessage::WaterPlot { .. }
+            | ClientMessage::Harvest { .. }
+            | ClientMessage::MoveItem { .. }
+            | ClientMessage::SplitStack { .. }
+            | ClientMessage::DropItem { .. }
+            | ClientMessage::UseItem { .. } => MessageKind::Action,
+            ClientMessage::Chat { .. } => MessageKind::Chat,
+            ClientMessage::JoinGuild { .. } | ClientMessage::LeaveGuild => MessageKind::Action,
+            ClientMessage::AckSnapshot { .. } => MessageKind::Other,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.updated = now;
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// What to do with a message from a client, given its rate limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RateDecision {
+    Allow,
+    /// Over the limit for the first time: drop it and tell the client
+    Warn,
+    Drop,
+    /// Over the limit for too long: hang up
+    Disconnect,
+}
+
+/// One connection's token buckets.
+#[derive(Debug)]
+struct RateLimiter {
+    connection: TokenBucket,
+    kinds: [(MessageKind, TokenBucket); 5],
+    excess: TokenBucket,
+    warned: bool,
+}
+
+impl RateLimiter {
+    fn new(limits: &RateLimits, now: Instant) -> Self {
+        let bucket = |limit| TokenBucket::new(limit, now);
+        Self {
+            connection: bucket(limits.connection),
+            kinds: [
+                (MessageKind::Movement, bucket(limits.movement)),
+                (MessageKind::Action, bucket(limits.actions)),
+                (MessageKind::Chat, bucket(limits.chat)),
+                (MessageKind::Session, bucket(limits.session)),
+                (MessageKind::Other, bucket(limits.other)),
+            ],
+            excess: bucket(limits.excess),
+            warned: false,
+        }
+    }
+
+    fn check(&mut self, kind: MessageKind, now: Instant) -> RateDecision {
+        let (_, kind_bucket) = self
+            .kinds
+            .iter_mut()
+            .find(|(k, _)| *k == kind)
+            .expect("every kind has a bucket");
+        // Only spend tokens when both buckets have one
+        kind_bucket.refill(now);
+        self.connection.refill(now);
+        if kind_bucket.tokens >= 1.0 && self.connection.tokens >= 1.0 {
+            kind_bucket.tokens -= 1.0;
+            self.connection.tokens -= 1.0;
+            return RateDecision::Allow;
+        }
+
+        if !self.excess.try_take(now) {
+            RateDecision::Disconnect
+        } else if !self.warned {
+            self.warned = true;
+            RateDecision::Warn
+        } else {
+            RateDecision::Drop
+        }
+    }
+}
+
+type ClientSink =
+    futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, Message>;
+
+/// Tells a connection's task to hang up from outside it.
+#[derive(Default)]
+struct Hangup {
+    notify: Notify,
+    /// The player was thrown out rather than dropped, so they get no grace
+    /// period to resume in
+    kicked: AtomicBool,
+    /// A newer login took the player over, which has nothing left to clean up
+    replaced: AtomicBool,
+}
+
+impl Hangup {
+    /// The client can't keep up with its queue.
+    fn too_slow(&self) {
+        self.notify.notify_one();
+    }
+
+    /// The sim kicked the player. Stops reading from the client even if it
+    /// ignores the close frame.
+    fn kick(&self) {
+        self.kicked.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    /// Another connection logged in as the same account. Stops reading from
+    /// this one, which could otherwise keep moving the player.
+    fn replace(&self) {
+        self.replaced.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+}
+
+struct Client {
+    /// Which socket this is, so a replaced socket can't remove its successor
+    connection: Uuid,
+    /// Queue drained by the connection's writer task
+    tx: mpsc::Sender<Message>,
+    /// Tells the connection to hang up, e.g. because it can't keep up
+    kick: Arc<Hangup>,
+    format: WireFormat,
+}
+
+impl Client {
+    /// Queues `message` and a close frame, so the client learns why it is
+    /// being disconnected.
+    fn close_with(&self, player_id: Uuid, message: &ServerMessage, close: CloseFrame) {
+        let message = to_ws_message(message, self.format);
+        self.queue(player_id, message, SlowClientPolicy::Skip);
+        let close = Message::Close(Some(close));
+        self.queue(player_id, close, SlowClientPolicy::Skip);
+    }
+
+    /// Queues `msg` without waiting, applying `policy` if the queue is full.
+    fn queue(&self, player_id: Uuid, msg: Message, policy: SlowClientPolicy) {
+        match self.tx.try_send(msg) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => match policy {
+                SlowClientPolicy::Skip => {}
+                SlowClientPolicy::Disconnect => {
+                    eprintln!("Client {} can't keep up, disconnecting", player_id);
+                    self.kick.too_slow();
+                }
+            },
+        }
+    }
+}
+
+type ConnectedClients = Arc<tokio::sync::RwLock<HashMap<Uuid, Client>>>;
+
+fn to_ws_message(message: &ServerMessage, format: WireFormat) -> Message {
+    ws_message(format.encode(message).into(), format)
+}
+
+/// JSON goes out as text frames, MessagePack as binary ones.
+fn ws_message(bytes: Bytes, format: WireFormat) -> Message {
+    match format {
+        WireFormat::Json => Message::Text(Utf8Bytes::try_from(bytes).expect("JSON is text")),
+        WireFormat::MessagePack => Message::Binary(bytes),
+    }
+}
+
+/// `frame` as the client's wire format wants it, without encoding it again.
+fn frame_message(frame: &Frame, format: WireFormat) -> Message {
+    ws_message(frame.bytes(format).clone(), format)
+}
+
+/// Queues `frame` for every client. Cloning it only bumps a refcount.
+fn broadcast(clients: &HashMap<Uuid, Client>, frame: &Frame, policy: SlowClientPolicy) {
+    let json = frame_message(frame, WireFormat::Json);
+    let msgpack = frame_message(frame, WireFormat::MessagePack);
+    for (player_id, client) in clients.iter() {
+        let msg = match client.format {
+            WireFormat::Json => &json,
+            WireFormat::MessagePack => &msgpack,
+        };
+        client.queue(*player_id, msg.clone(), policy);
+    }
+}
+
+/// The first wire format the client asked for that we speak, if any.
+fn negotiate_format(request: &Request) -> Option<WireFormat> {
+    request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(WireFormat::from_subprotocol)
+}
+
+/// The client registered under `key`, if it is still the socket `connection`
+/// rather than a newer login that replaced it.
+fn owned(clients: &HashMap<Uuid, Client>, key: Uuid, connection: Uuid) -> Option<&Client> {
+    clients
+        .get(&key)
+        .filter(|client| client.connection == connection)
+}
+
+/// Passes `cmd` on to the sim while `connection` is still the one playing
+/// `key`, so a replaced socket can't drive its successor's player. Returns
+/// whether it did.
+async fn forward(
+    clients: &ConnectedClients,
+    key: Uuid,
+    connection: Uuid,
+    client_to_sim_tx: &UnboundedSender<EcsCommand>,
+    cmd: EcsCommand,
+) -> bool {
+    // Holding the lock keeps a login from taking over in between
+    let clients = clients.read().await;
+    if owned(&clients, key, connection).is_none() {
+        return false;
+    }
+    let _ = client_to_sim_tx.send(cmd);
+    true
+}
 
-    // Track all connected clients: player_id -> WebSocket sink
-    let connected_clients = std::sync::Arc::new(tokio::sync::RwLock::new(HashMap::<
-        Uuid,
-        futures_util::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
-            Message,
-        >,
-    >::new()));
+/// Sends `message` straight to the client registered under `key`.
+async fn reply(clients: &ConnectedClients, key: Uuid, connection: Uuid, message: ServerMessage) {
+    let clients = clients.read().await;
+    if let Some(client) = owned(&clients, key, connection) {
+        client.queue(
+            key,
+            to_ws_message(&message, client.format),
+            SlowClientPolicy::Skip,
+        );
+    }
+}
+
+/// Tells the client why it is being turned away, then closes the connection.
+async fn reject(clients: &ConnectedClients, key: Uuid, connection: Uuid, reason: String) {
+    let rejected = ServerMessage::Rejected { reason };
+    close_with(clients, key, connection, rejected, policy_close("rejected")).await;
+}
+
+async fn close_with(
+    clients: &ConnectedClients,
+    key: Uuid,
+    connection: Uuid,
+    message: ServerMessage,
+    close: CloseFrame,
+) {
+    let clients = clients.read().await;
+    if let Some(client) = owned(&clients, key, connection) {
+        client.close_with(key, &message, close);
+    }
+}
+
+/// Queues a raw WebSocket frame, such as a ping, for a client.
+async fn send_frame(clients: &ConnectedClients, key: Uuid, connection: Uuid, frame: Message) {
+    let clients = clients.read().await;
+    if let Some(client) = owned(&clients, key, connection) {
+        client.queue(key, frame, SlowClientPolicy::Skip);
+    }
+}
+
+/// A close frame for clients the server is done with.
+fn policy_close(reason: &'static str) -> CloseFrame {
+    CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.into(),
+    }
+}
+
+/// Checks the first message on a connection, which must be a `Hello` with a
+/// protocol version this server still speaks.
+fn greet(msg: ClientMessage) -> Result<ServerMessage, String> {
+    let ClientMessage::Hello {
+        protocol_version,
+        client_build,
+        capabilities,
+    } = msg
+    else {
+        return Err("the first message must be Hello".to_string());
+    };
+    if protocol_version != PROTOCOL_VERSION {
+        println!(
+            "Client build {} speaks protocol {}, server speaks {}",
+            client_build, protocol_version, PROTOCOL_VERSION
+        );
+    }
+    if !SUPPORTED_PROTOCOL_VERSIONS.contains(&protocol_version) {
+        return Err(format!(
+            "protocol version {} is not supported, update to a build speaking {} to {}",
+            protocol_version,
+            SUPPORTED_PROTOCOL_VERSIONS.start(),
+            SUPPORTED_PROTOCOL_VERSIONS.end()
+        ));
+    }
+    let capabilities = capabilities
+        .into_iter()
+        .filter(|capability| SERVER_CAPABILITIES.contains(&capability.as_str()))
+        .collect();
+    Ok(ServerMessage::Welcome {
+        protocol_version,
+        capabilities,
+    })
+}
+
+/// Writes one client's queued messages to its socket, so a slow client only
+/// ever holds itself up.
+async fn write_to_client(mut sink: ClientSink, mut rx: mpsc::Receiver<Message>) {
+    while let Some(msg) = rx.recv().await {
+        if let Err(e) = sink.send(msg).await {
+            eprintln!("Error sending to client: {:?}", e);
+            return;
+        }
+    }
+    // The client was removed, e.g. replaced by a newer login
+    let _ = sink.close().await;
+}
+
+fn error_message(code: ErrorCode, message: &str, request_id: Option<u32>) -> ServerMessage {
+    ServerMessage::Error {
+        code,
+        message: message.to_string(),
+        request_id,
+    }
+}
+
+fn auth_error(e: &AuthError, request_id: Option<u32>) -> ServerMessage {
+    let code = match e {
+        AuthError::InvalidUsername => ErrorCode::InvalidUsername,
+        AuthError::WeakPassword => ErrorCode::WeakPassword,
+        AuthError::UsernameTaken => ErrorCode::UsernameTaken,
+        AuthError::InvalidCredentials => ErrorCode::InvalidCredentials,
+        AuthError::InvalidSession => ErrorCode::InvalidSession,
+        AuthError::Storage(_) | AuthError::Hash(_) => {
+            eprintln!("Login failed: {}", e);
+            ErrorCode::ServerError
+        }
+    };
+    error_message(code, &e.to_string(), request_id)
+}
+
+/// Serves WebSocket clients from an already bound listener until `shutdown`
+/// resolves. Then it stops accepting connections, tells every client why and
+/// waits a little for them to hang up, so their players leave the simulation
+/// like any other.
+pub async fn serve(
+    listener: TcpListener,
+    config: NetConfig,
+    auth: Arc<Auth>,
+    client_to_sim_tx: UnboundedSender<EcsCommand>,
+    mut sim_to_net_rx: UnboundedReceiver<ServerToClientMessage>,
+    shutdown: impl Future<Output = ShutdownNotice>,
+) {
+    // Track all connected clients: player_id -> outbound queue. Clients that
+    // haven't logged in yet are keyed by their connection id.
+    let connected_clients: ConnectedClients = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
 
     // Task: Receive messages from sim and route to clients
     let clients_clone = connected_clients.clone();
+    let policy = config.slow_client_policy;
     tokio::spawn(async move {
         while let Some(msg) = sim_to_net_rx.recv().await {
-            let mut clients = clients_clone.write().await;
+            let clients = clients_clone.read().await;
             match msg {
                 ServerToClientMessage::SendToClient { player_id, message } => {
-                    if let Some(client_sink) = clients.get_mut(&player_id) {
-                        let json = serde_json::to_string(&message).unwrap();
-                        let ws_msg = Message::Text(json.into());
-                        if let Err(e) = client_sink.send(ws_msg).await {
-                            eprintln!("Error sending to client {}: {:?}", player_id, e);
-                        }
+                    if let Some(client) = clients.get(&player_id) {
+                        client.queue(player_id, to_ws_message(&message, client.format), policy);
                     }
                 }
                 ServerToClientMessage::Broadcast { message } => {
-                    let json = serde_json::to_string(&message).unwrap();
-                    let ws_msg = Message::Text(json.into());
-                    for (player_id, client_sink) in clients.iter_mut() {
-                        if let Err(e) = client_sink.send(ws_msg.clone()).await {
-                            eprintln!("Error broadcasting to client {}: {:?}", player_id, e);
+                    broadcast(&clients, &Frame::encode(&message), policy);
+                }
+                ServerToClientMessage::SendFrame { player_ids, frame } => {
+                    for player_id in player_ids {
+                        if let Some(client) = clients.get(&player_id) {
+                            client.queue(player_id, frame_message(&frame, client.format), policy);
                         }
                     }
                 }
@@ -156,83 +1790,537 @@ pub async fn run_websocket_server(
                     // Client cleanup is handled when WebSocket closes
                     println!("Player {} disconnected from sim", player_id);
                 }
+                ServerToClientMessage::Kick { player_id, reason } => {
+                    // The connection hangs up without waiting for the client
+                    // to answer the close frame
+                    if let Some(client) = clients.get(&player_id) {
+                        println!("Kicking player {}: {}", player_id, reason);
+                        let error = error_message(ErrorCode::Kicked, &reason, None);
+                        client.close_with(player_id, &error, policy_close("kicked"));
+                        client.kick.kick();
+                    }
+                }
             }
         }
     });
 
-    // Accept new WebSocket connections
-    while let Ok((stream, _)) = listener.accept().await {
+    // Accept new WebSocket connections until shutdown
+    let mut connections = JoinSet::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+    let notice = loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            notice = &mut shutdown => break notice,
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+        };
         let client_to_sim_tx_clone = client_to_sim_tx.clone();
         let connected_clients_clone = connected_clients.clone();
+        let auth = auth.clone();
+        let queue_capacity = config.client_queue_capacity;
+        let rate_limits = config.rate_limits.clone();
+        let (ping_interval, pong_timeout, idle_timeout) = (
+            config.ping_interval,
+            config.pong_timeout,
+            config.idle_timeout,
+        );
+        let websocket_config = WebSocketConfig::default()
+            .max_message_size(Some(config.max_message_size))
+            .max_frame_size(Some(config.max_message_size));
 
-        tokio::spawn(async move {
-            let ws_stream = accept_async(stream).await.unwrap();
-            println!("New WebSocket connection established");
+        connections.spawn(async move {
+            let mut format = WireFormat::default();
+            // The error type is tungstenite's, and never built here
+            #[allow(clippy::result_large_err)]
+            let choose_format = |request: &Request, mut response: Response| {
+                if let Some(chosen) = negotiate_format(request) {
+                    format = chosen;
+                    response.headers_mut().insert(
+                        SEC_WEBSOCKET_PROTOCOL,
+                        HeaderValue::from_static(chosen.subprotocol()),
+                    );
+                }
+                Ok::<_, ErrorResponse>(response)
+            };
+            // Oversized messages end the connection with a read error
+            let ws_stream =
+                match accept_hdr_async_with_config(stream, choose_format, Some(websocket_config))
+                    .await
+                {
+                    Ok(ws_stream) => ws_stream,
+                    Err(e) => {
+                        eprintln!("WebSocket handshake failed: {}", e);
+                        return;
+                    }
+                };
+            println!("New WebSocket connection established ({:?})", format);
 
             let (client_sink, mut client_stream) = ws_stream.split();
-            let player_id = Uuid::new_v4();
+            let connection = Uuid::new_v4();
+            // Replaced by the account id once the client logs in
+            let mut player_id = connection;
+            let mut account: Option<AuthenticatedAccount> = None;
+            let mut greeted = false;
+            let mut left_cleanly = false;
+            let mut too_slow = false;
+            let mut limiter = RateLimiter::new(&rate_limits, Instant::now());
+            let mut timed_out = false;
+            let mut pings = time::interval_at(time::Instant::now() + ping_interval, ping_interval);
+            pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut next_ping_id: u64 = 0;
+            // The ping we're waiting on a pong for, and when it was sent
+            let mut pending_ping: Option<(u64, time::Instant)> = None;
+            let mut last_input = time::Instant::now();
+
+            let (tx, rx) = mpsc::channel(queue_capacity);
+            let writer = tokio::spawn(write_to_client(client_sink, rx));
+            let kick = Arc::new(Hangup::default());
 
             // Add client to connected clients map
             {
                 let mut clients = connected_clients_clone.write().await;
-                clients.insert(player_id, client_sink);
+                clients.insert(
+                    player_id,
+                    Client {
+                        connection,
+                        tx,
+                        kick: kick.clone(),
+                        format,
+                    },
+                );
             }
 
             // Handle incoming messages from this client
-            while let Some(msg) = client_stream.next().await {
+            loop {
+                let pong_deadline = pending_ping.map(|(_, sent)| sent + pong_timeout);
+                let msg = tokio::select! {
+                    msg = client_stream.next() => msg,
+                    _ = kick.notify.notified() => {
+                        // Kicked players leave for good, the writer still
+                        // flushes why. Slow ones may come back.
+                        if kick.kicked.load(Ordering::Relaxed) {
+                            left_cleanly = true;
+                        } else if kick.replaced.load(Ordering::Relaxed) {
+                            println!("Client {} was replaced by a newer login", player_id);
+                        } else {
+                            too_slow = true;
+                        }
+                        break;
+                    }
+                    _ = pings.tick(), if pending_ping.is_none() => {
+                        let ping_id = next_ping_id;
+                        next_ping_id += 1;
+                        let ping = Message::Ping(Bytes::copy_from_slice(&ping_id.to_be_bytes()));
+                        send_frame(&connected_clients_clone, player_id, connection, ping).await;
+                        pending_ping = Some((ping_id, time::Instant::now()));
+                        continue;
+                    }
+                    _ = time::sleep_until(pong_deadline.unwrap_or(last_input)), if pong_deadline.is_some() => {
+                        // Likely a half-open connection, treat it as dropped
+                        println!("Client {} stopped answering pings", player_id);
+                        timed_out = true;
+                        break;
+                    }
+                    _ = time::sleep_until(last_input + idle_timeout) => {
+                        println!("Client {} was idle for too long", player_id);
+                        let error =
+                            error_message(ErrorCode::IdleTimeout, "idle for too long", None);
+                        let close = policy_close("idle");
+                        close_with(&connected_clients_clone, player_id, connection, error, close).await;
+                        left_cleanly = true;
+                        break;
+                    }
+                };
+                let Some(msg) = msg else {
+                    break;
+                };
                 match msg {
-                    Ok(Message::Text(text)) => {
-                        if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                            println!("Received from client {}: {:?}", player_id, client_msg);
-
-                            match client_msg {
-                                ClientMessage::Join => {
-                                    // Join is handled by SpawnPlayer above
-                                    println!("Player {} confirmed join", player_id);
-                                    // Notify sim that player joined
-                                    let _ = client_to_sim_tx_clone
-                                        .send(EcsCommand::SpawnPlayer { player_id });
+                    Ok(frame @ (Message::Text(_) | Message::Binary(_))) => {
+                        last_input = time::Instant::now();
+                        let format = if frame.is_binary() {
+                            WireFormat::MessagePack
+                        } else {
+                            WireFormat::Json
+                        };
+                        let data = frame.into_data();
+                        let decoded = format.decode::<ClientRequest>(&data);
+                        let kind = decoded
+                            .as_ref()
+                            .map_or(MessageKind::Other, |request| MessageKind::of(&request.message));
+                        let request_id = decoded.as_ref().ok().and_then(|r| r.request_id);
+                        match limiter.check(kind, Instant::now()) {
+                            RateDecision::Allow => {}
+                            RateDecision::Warn => {
+                                let warning = error_message(
+                                    ErrorCode::RateLimited,
+                                    "slow down, message dropped",
+                                    request_id,
+                                );
+                                reply(&connected_clients_clone, player_id, connection, warning).await;
+                                continue;
+                            }
+                            RateDecision::Drop => continue,
+                            RateDecision::Disconnect => {
+                                println!("Disconnecting client {} for flooding", player_id);
+                                let error = error_message(
+                                    ErrorCode::RateLimited,
+                                    "too many messages",
+                                    request_id,
+                                );
+                                let close = policy_close("flooding");
+                                close_with(&connected_clients_clone, player_id, connection, error, close)
+                                    .await;
+                                // No grace period to come back in
+                                left_cleanly = true;
+                                break;
+                            }
+                        }
+                        let client_msg = match decoded {
+                            Ok(request) => request.message,
+                            Err(e) => {
+                                eprintln!(
+                                    "Failed to parse message from client {}: {}",
+                                    player_id, e
+                                );
+                                let error = MalformedRequest::diagnose(format, &data, &e);
+                                reply(&connected_clients_clone, player_id, connection, error.into_message())
+                                    .await;
+                                continue;
+                            }
+                        };
+
+                        if !greeted {
+                            match greet(client_msg) {
+                                Ok(welcome) => {
+                                    reply(&connected_clients_clone, player_id, connection, welcome).await;
+                                    greeted = true;
+                                    continue;
                                 }
-                                ClientMessage::Move { dx, dy } => {
-                                    let _ = client_to_sim_tx_clone
-                                        .send(EcsCommand::UpdateVelocity { player_id, dx, dy });
+                                Err(reason) => {
+                                    println!("Rejecting client {}: {}", player_id, reason);
+                                    reject(&connected_clients_clone, player_id, connection, reason).await;
+                                    break;
+                                }
+                            }
+                        }
+                        if matches!(client_msg, ClientMessage::Hello { .. }) {
+                            eprintln!("Client {} said Hello twice", player_id);
+                            continue;
+                        }
+
+                        match LoginRequest::from_message(client_msg) {
+                            Ok(request) => {
+                                if account.is_some() {
+                                    let error = error_message(
+                                        ErrorCode::AlreadyLoggedIn,
+                                        "already logged in",
+                                        request_id,
+                                    );
+                                    reply(&connected_clients_clone, player_id, connection, error).await;
+                                    continue;
+                                }
+
+                                // A resumed session goes straight back into the world
+                                let resuming = matches!(request, LoginRequest::Resume { .. });
+
+                                // Password hashing is slow, keep it off the async workers
+                                let login_auth = auth.clone();
+                                let result = tokio::task::spawn_blocking(move || {
+                                    login_auth.authenticate(request, SystemTime::now())
+                                })
+                                .await
+                                .expect("login task panicked");
+                                let authenticated = match result {
+                                    Ok(authenticated) => authenticated,
+                                    Err(e) => {
+                                        let error = auth_error(&e, request_id);
+                                        reply(&connected_clients_clone, player_id, connection, error).await;
+                                        continue;
+                                    }
+                                };
+                                println!(
+                                    "Client {} logged in as {}",
+                                    player_id, authenticated.username
+                                );
+
+                                // Re-key this connection by its account id. The
+                                // newest login wins, e.g. when a phone reconnects
+                                // before the old socket has timed out.
+                                let replaced = {
+                                    let mut clients = connected_clients_clone.write().await;
+                                    clients
+                                        .remove(&player_id)
+                                        .and_then(|client| clients.insert(authenticated.id, client))
+                                };
+                                if let Some(old) = replaced {
+                                    // Dropping `old` lets its writer flush this and hang up
+                                    let error = error_message(
+                                        ErrorCode::LoggedInElsewhere,
+                                        "logged in from another connection",
+                                        None,
+                                    );
+                                    old.queue(
+                                        authenticated.id,
+                                        to_ws_message(&error, old.format),
+                                        SlowClientPolicy::Skip,
+                                    );
+                                    old.kick.replace();
+                                    // The player stays in the world, free for
+                                    // this connection to Join or Resume into
+                                    let _ = client_to_sim_tx_clone.send(
+                                        EcsCommand::DisconnectPlayer {
+                                            player_id: authenticated.id,
+                                        },
+                                    );
+                                }
+                                player_id = authenticated.id;
+
+                                let logged_in = ServerMessage::LoggedIn {
+                                    player_id,
+                                    username: authenticated.username.clone(),
+                                    session_token: auth
+                                        .sessions
+                                        .issue(player_id, SystemTime::now()),
+                                };
+                                reply(&connected_clients_clone, player_id, connection, logged_in).await;
+                                if resuming {
+                                    // Re-binds the entity kept through the grace
+                                    // period and resends the world, no Join needed
+                                    let _ = client_to_sim_tx_clone.send(EcsCommand::SpawnPlayer {
+                                        player_id,
+                                        request_id,
+                                        account: authenticated.username.clone(),
+                                    });
+                                }
+                                account = Some(authenticated);
+                            }
+                            Err(client_msg) => {
+                                let Some(account) = &account else {
+                                    let error = error_message(
+                                        ErrorCode::NotAuthenticated,
+                                        "log in first",
+                                        request_id,
+                                    );
+                                    reply(&connected_clients_clone, player_id, connection, error).await;
+                                    continue;
+                                };
+                                println!("Received from client {}: {:?}", player_id, client_msg);
+                                if let Some(cmd) = to_ecs_command(account, request_id, client_msg)
+                                    && !forward(
+                                        &connected_clients_clone,
+                                        player_id,
+                                        connection,
+                                        &client_to_sim_tx_clone,
+                                        cmd,
+                                    )
+                                    .await
+                                {
+                                    break;
                                 }
                             }
-                        } else {
-                            eprintln!(
-                                "Failed to parse message from client {}: {}",
-                                player_id, text
-                            );
                         }
                     }
                     Ok(Message::Close(_)) => {
                         println!("Client {} disconnected", player_id);
-                        // Remove from connected clients
-                        {
-                            let mut clients = connected_clients_clone.write().await;
-                            clients.remove(&player_id);
-                        }
-                        // Notify sim of disconnection
-                        let _ =
-                            client_to_sim_tx_clone.send(EcsCommand::DespawnPlayer { player_id });
+                        left_cleanly = true;
                         break;
                     }
+                    Ok(Message::Pong(payload)) => {
+                        let Some((ping_id, sent)) = pending_ping else {
+                            continue;
+                        };
+                        if payload[..] != ping_id.to_be_bytes() {
+                            continue;
+                        }
+                        pending_ping = None;
+                        if account.is_some() {
+                            let cmd = EcsCommand::UpdateLatency {
+                                player_id,
+                                rtt: sent.elapsed(),
+                            };
+                            if !forward(
+                                &connected_clients_clone,
+                                player_id,
+                                connection,
+                                &client_to_sim_tx_clone,
+                                cmd,
+                            )
+                            .await
+                            {
+                                break;
+                            }
+                        }
+                    }
                     Ok(_) => {
-                        // Ignore other message types (ping, pong, binary, etc.)
+                        // Pings are answered by tungstenite
                     }
                     Err(e) => {
                         eprintln!("WebSocket error for client {}: {:?}", player_id, e);
-                        // Remove from connected clients on error
-                        {
-                            let mut clients = connected_clients_clone.write().await;
-                            clients.remove(&player_id);
-                        }
-                        let _ =
-                            client_to_sim_tx_clone.send(EcsCommand::DespawnPlayer { player_id });
                         break;
                     }
                 }
             }
+
+            // Remove from connected clients, unless a newer login took over.
+            // The sim is told while the lock is held, so a login taking the
+            // player over right after is queued behind this.
+            {
+                let mut clients = connected_clients_clone.write().await;
+                let ours = clients
+                    .get(&player_id)
+                    .is_some_and(|client| client.connection == connection);
+                if ours {
+                    clients.remove(&player_id);
+                    // Dropped connections get a grace period to resume in
+                    if account.is_some() {
+                        let cmd = if left_cleanly {
+                            EcsCommand::DespawnPlayer { player_id }
+                        } else {
+                            EcsCommand::DisconnectPlayer { player_id }
+                        };
+                        let _ = client_to_sim_tx_clone.send(cmd);
+                    }
+                }
+            }
+            // A stalled writer would never notice its queue closing
+            if too_slow || timed_out {
+                writer.abort();
+            }
         });
+    };
+    drop(listener);
+
+    println!("Shutting down: {}", notice.reason);
+    {
+        let message = ServerMessage::ServerShutdown {
+            reason: notice.reason,
+            reconnect_after: notice.reconnect_after,
+        };
+        let clients = connected_clients.read().await;
+        for (player_id, client) in clients.iter() {
+            let close = CloseFrame {
+                code: CloseCode::Away,
+                reason: "server shutting down".into(),
+            };
+            client.close_with(*player_id, &message, close);
+        }
+    }
+    // Connections end once their client answers the close frame
+    let drained = time::timeout(config.shutdown_grace, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        println!(
+            "{} connections didn't close in time, dropping them",
+            connections.len()
+        );
+        connections.abort_all();
     }
 }
+
+/// The simulation command for a message from a logged in client.
+fn to_ecs_command(
+    account: &AuthenticatedAccount,
+    request_id: Option<u32>,
+    client_msg: ClientMessage,
+) -> Option<EcsCommand> {
+    let player_id = account.id;
+    let cmd = match client_msg {
+        ClientMessage::Hello { .. }
+        | ClientMessage::Register { .. }
+        | ClientMessage::Login { .. }
+        | ClientMessage::Resume { .. } => return None,
+        ClientMessage::Join => EcsCommand::SpawnPlayer {
+            player_id,
+            request_id,
+            account: account.username.clone(),
+        },
+        ClientMessage::Move { dx, dy } => EcsCommand::UpdateVelocity {
+            player_id,
+            request_id,
+            dx,
+            dy,
+        },
+        ClientMessage::Input { seq, dx, dy, tick } => EcsCommand::Input {
+            player_id,
+            request_id,
+            seq,
+            dx,
+            dy,
+            tick,
+        },
+        ClientMessage::PlantCrop { x, y, crop_type } => EcsCommand::PlantCrop {
+            player_id,
+            request_id,
+            x,
+            y,
+            crop_type,
+        },
+        ClientMessage::WaterPlot { x, y } => EcsCommand::WaterPlot {
+            player_id,
+            request_id,
+            x,
+            y,
+        },
+        ClientMessage::Harvest { x, y } => EcsCommand::Harvest {
+            player_id,
+            request_id,
+            x,
+            y,
+        },
+        ClientMessage::MoveItem { from, to } => EcsCommand::MoveItem {
+            player_id,
+            request_id,
+            from,
+            to,
+        },
+        ClientMessage::SplitStack { slot, count, to } => EcsCommand::SplitStack {
+            player_id,
+            request_id,
+            slot,
+            count,
+            to,
+        },
+        ClientMessage::DropItem { slot, count } => EcsCommand::DropItem {
+            player_id,
+            request_id,
+            slot,
+            count,
+        },
+        ClientMessage::UseItem { slot, x, y } => EcsCommand::UseItem {
+            player_id,
+            request_id,
+            slot,
+            x,
+            y,
+        },
+        ClientMessage::Chat { channel, text } => EcsCommand::Chat {
+            player_id,
+            request_id,
+            channel,
+            text,
+        },
+        ClientMessage::JoinGuild { guild } => EcsCommand::JoinGuild {
+            player_id,
+            request_id,
+            guild,
+        },
+        ClientMessage::LeaveGuild => EcsCommand::LeaveGuild {
+            player_id,
+            request_id,
+        },
+        ClientMessage::AckSnapshot { seq } => EcsCommand::AckSnapshot {
+            player_id,
+            request_id,
+            seq,
+        },
+    };
+    Some(cmd)
+}
@@ -0,0 +1,645 @@
+use crate::inventory::{Inventory, MAX_STACK};
+use crate::messages::ItemStack;
+use crate::sim::{Account, Crop, FarmPlot, GuildMember, Position};
+use bevy::prelude::*;
+use rusqlite::{Connection, params};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::ServerMessage;
+    use crate::sim::{
+        CommandQueue, CropCatalog, EcsCommand, Player, ServerToClientMessage, ServerToClientQueue,
+        SimulationPlugin, Velocity,
+    };
+    use uuid::Uuid;
+
+    fn stack(item_id: &str, count: u32) -> Option<ItemStack> {
+        Some(ItemStack {
+            item_id: item_id.to_string(),
+            count,
+        })
+    }
+
+    /// An app the way `main` boots it: restored from `storage` and saving
+    /// every update.
+    fn persistent_app(storage: Storage) -> App {
+        let mut app = App::new();
+        app.insert_resource(Time::<()>::default());
+        app.insert_resource(SaveTimer {
+            interval: 0.0,
+            last_save: 0.0,
+        });
+        app.add_systems(Update, save_system);
+        restore_world(app.world_mut(), storage).unwrap();
+        app
+    }
+
+    /// The saved state of the world, sorted so it can be compared.
+    fn sorted_snapshot(world: &mut World) -> WorldSnapshot {
+        let mut snapshot = WorldSnapshot::capture(world);
+        snapshot.players.sort_by(|a, b| a.account.cmp(&b.account));
+        snapshot.plots.sort_by_key(|plot| (plot.x, plot.y));
+        snapshot
+    }
+
+    #[test]
+    fn test_save_and_restore_world() {
+        let path = std::env::temp_dir().join(format!("farmworld-{}.db", Uuid::new_v4()));
+
+        let mut app = persistent_app(Storage::open(&path).unwrap());
+        let mut inventory = Inventory::default();
+        inventory.slots[0] = stack("wheat_seed", 4);
+        inventory.slots[7] = stack("carrot", 12);
+        app.world_mut().spawn((
+            Player { id: Uuid::new_v4() },
+            Account {
+                name: "alice".to_string(),
+            },
+            Position {
+                x: 120.5,
+                y: -33.25,
+            },
+            Velocity { dx: 1.0, dy: 0.0 },
+            inventory,
+            GuildMember {
+                guild: "growers".to_string(),
+            },
+        ));
+        app.world_mut().spawn((
+            FarmPlot {
+                x: 3,
+                y: 4,
+                watered: true,
+            },
+            Crop {
+                crop_type: "wheat".to_string(),
+                stage: 2,
+                growth_timer: 12.5,
+            },
+        ));
+        app.world_mut().spawn(FarmPlot {
+            x: -1,
+            y: 0,
+            watered: false,
+        });
+        // Bob is offline and only known from an earlier save
+        app.world_mut()
+            .resource_mut::<SavedPlayers>()
+            .players
+            .insert(
+                "bob".to_string(),
+                PlayerRecord {
+                    account: "bob".to_string(),
+                    x: 10.0,
+                    y: 20.0,
+                    inventory: Inventory::default().slots,
+                    guild: None,
+                },
+            );
+        app.update();
+        let before = sorted_snapshot(app.world_mut());
+        assert_eq!(before.players.len(), 2);
+        assert_eq!(before.plots.len(), 2);
+        drop(app);
+
+        let mut app = persistent_app(Storage::open(&path).unwrap());
+        let after = sorted_snapshot(app.world_mut());
+        let _ = std::fs::remove_file(&path);
+
+        // Everyone is offline after a restart, but nothing was lost
+        assert_eq!(after, before);
+        let saved = app.world().resource::<SavedPlayers>();
+        assert_eq!(saved.players["alice"].inventory[7], stack("carrot", 12));
+        assert_eq!(saved.players["alice"].x, 120.5);
+        assert_eq!(saved.players["alice"].guild.as_deref(), Some("growers"));
+    }
+
+    #[test]
+    fn test_rejoining_player_gets_their_save_back() {
+        let path = std::env::temp_dir().join(format!("farmworld-{}.db", Uuid::new_v4()));
+
+        let mut app = persistent_app(Storage::open(&path).unwrap());
+        let mut inventory = Inventory::default();
+        inventory.slots[2] = stack("carrot", 7);
+        app.world_mut().spawn((
+            Player { id: Uuid::new_v4() },
+            Account {
+                name: "alice".to_string(),
+            },
+            Position { x: 64.0, y: 80.0 },
+            Velocity { dx: 0.0, dy: 0.0 },
+            inventory.clone(),
+        ));
+        app.world_mut().spawn((
+            FarmPlot {
+                x: 3,
+                y: 4,
+                watered: true,
+            },
+            Crop {
+                crop_type: "wheat".to_string(),
+                stage: 2,
+                growth_timer: 12.5,
+            },
+        ));
+        app.update();
+        drop(app);
+
+        // A fresh server, booted from the save the way `main` does it
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (sim_tx, mut sim_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut app = App::new();
+        app.insert_resource(CropCatalog::load("content/crops.json").unwrap())
+            .insert_resource(CommandQueue { rx })
+            .insert_resource(ServerToClientQueue { tx: sim_tx })
+            .add_plugins((MinimalPlugins, SimulationPlugin { tick_rate: 60.0 }));
+        restore_world(app.world_mut(), Storage::open(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let player_id = Uuid::new_v4();
+        tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: Some(1),
+            account: "alice".to_string(),
+        })
+        .unwrap();
+        app.world_mut().run_schedule(FixedUpdate);
+
+        let mut query = app
+            .world_mut()
+            .query::<(&Player, &Account, &Position, &Inventory)>();
+        let (player, account, pos, restored) = query.single(app.world()).unwrap();
+        assert_eq!(player.id, player_id);
+        assert_eq!(account.name, "alice");
+        assert_eq!((pos.x, pos.y), (64.0, 80.0));
+        assert_eq!(restored.slots, inventory.slots);
+
+        // They're told where they are and shown the field they left
+        let messages: Vec<_> = std::iter::from_fn(|| sim_rx.try_recv().ok())
+            .filter_map(|message| match message {
+                ServerToClientMessage::SendToClient { message, .. } => Some(message),
+                _ => None,
+            })
+            .collect();
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            ServerMessage::PlayerJoined { x, y, .. } if (*x, *y) == (64.0, 80.0)
+        )));
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            ServerMessage::CropPlanted {
+                x: 3,
+                y: 4,
+                stage: 2,
+                watered: true,
+                ..
+            }
+        )));
+        assert!(messages.contains(&ServerMessage::Ack {
+            request_id: 1,
+            result: Ok(()),
+        }));
+    }
+
+    #[test]
+    fn test_oversized_stacks_are_clamped_on_load() {
+        let storage = Storage::open_in_memory().unwrap();
+        {
+            let conn = storage.conn.lock().unwrap();
+            conn.execute(
+                "INSERT INTO players (account, x, y) VALUES ('alice', 0.0, 0.0)",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO inventory_slots (account, slot, item_id, count)
+                 VALUES ('alice', 0, 'wheat', 500), ('alice', 1, 'wheat', 10)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let snapshot = storage.load().unwrap();
+        let mut inventory = Inventory {
+            slots: snapshot.players[0].inventory.clone(),
+        };
+        assert_eq!(inventory.slots[0], stack("wheat", MAX_STACK));
+
+        // The full stack takes no more, and nothing can be merged onto it
+        assert_eq!(inventory.add("wheat", 1).unwrap(), vec![1]);
+        inventory.move_stack(1, 0).unwrap();
+        assert_eq!(inventory.slots[1], stack("wheat", 11));
+    }
+
+    #[test]
+    fn test_migrations_are_applied_once() {
+        let mut conn = prepare(Connection::open_in_memory().unwrap()).unwrap();
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        // Running them again is a no-op
+        migrate(&mut conn).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_newer_schema() {
+        let mut conn = prepare(Connection::open_in_memory().unwrap()).unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(StorageError::NewerSchema { .. })
+        ));
+    }
+}
+
+/// Schema changes, applied in order. `PRAGMA user_version` records how many
+/// have run, so only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE players (
+        account TEXT PRIMARY KEY NOT NULL,
+        x REAL NOT NULL,
+        y REAL NOT NULL
+    );
+    CREATE TABLE inventory_slots (
+        account TEXT NOT NULL REFERENCES players(account) ON DELETE CASCADE,
+        slot INTEGER NOT NULL,
+        item_id TEXT NOT NULL,
+        count INTEGER NOT NULL,
+        PRIMARY KEY (account, slot)
+    );
+    CREATE TABLE farm_plots (
+        x INTEGER NOT NULL,
+        y INTEGER NOT NULL,
+        watered INTEGER NOT NULL,
+        crop_type TEXT,
+        stage INTEGER,
+        growth_timer REAL,
+        PRIMARY KEY (x, y)
+    );",
+    "CREATE TABLE accounts (
+        id TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+        password_hash TEXT NOT NULL
+    );",
+    "ALTER TABLE players ADD COLUMN guild TEXT;",
+];
+
+#[derive(Debug)]
+pub enum StorageError {
+    Sqlite(rusqlite::Error),
+    NewerSchema { found: usize, supported: usize },
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Sqlite(e) => write!(f, "database error: {}", e),
+            StorageError::NewerSchema { found, supported } => write!(
+                f,
+                "database schema version {} is newer than this server supports ({})",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Sqlite(e)
+    }
+}
+
+/// Opens (or creates) the database at `path` and brings its schema up to date.
+/// Every part of the server that talks to the database goes through here.
+pub fn open_database(path: impl AsRef<Path>) -> Result<Connection, StorageError> {
+    prepare(Connection::open(path)?)
+}
+
+pub(crate) fn prepare(mut conn: Connection) -> Result<Connection, StorageError> {
+    conn.pragma_update(None, "foreign_keys", true)?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), StorageError> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(StorageError::NewerSchema {
+            found: version,
+            supported: MIGRATIONS.len(),
+        });
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Everything saved about one account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerRecord {
+    pub account: String,
+    pub x: f32,
+    pub y: f32,
+    pub inventory: Vec<Option<ItemStack>>,
+    pub guild: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlotRecord {
+    pub x: i32,
+    pub y: i32,
+    pub watered: bool,
+    pub crop: Option<CropRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CropRecord {
+    pub crop_type: String,
+    pub stage: u8,
+    pub growth_timer: f32,
+}
+
+/// The persistent part of the world at one point in time.
+#[derive(Debug, Default, PartialEq)]
+pub struct WorldSnapshot {
+    pub players: Vec<PlayerRecord>,
+    pub plots: Vec<PlotRecord>,
+}
+
+impl WorldSnapshot {
+    /// Collects online players, offline players and every farm plot.
+    pub fn capture(world: &mut World) -> Self {
+        let mut players = world
+            .get_resource::<SavedPlayers>()
+            .map(|saved| saved.players.clone())
+            .unwrap_or_default();
+        let mut online = world.query::<(&Account, &Position, &Inventory, Option<&GuildMember>)>();
+        for (account, pos, inventory, guild) in online.iter(world) {
+            players.insert(
+                account.name.clone(),
+                PlayerRecord::new(account, pos, inventory, guild),
+            );
+        }
+
+        let mut plots = world.query::<(&FarmPlot, Option<&Crop>)>();
+        let plots = plots
+            .iter(world)
+            .map(|(plot, crop)| PlotRecord {
+                x: plot.x,
+                y: plot.y,
+                watered: plot.watered,
+                crop: crop.map(|crop| CropRecord {
+                    crop_type: crop.crop_type.clone(),
+                    stage: crop.stage,
+                    growth_timer: crop.growth_timer,
+                }),
+            })
+            .collect();
+
+        Self {
+            players: players.into_values().collect(),
+            plots,
+        }
+    }
+}
+
+impl PlayerRecord {
+    pub fn new(
+        account: &Account,
+        pos: &Position,
+        inventory: &Inventory,
+        guild: Option<&GuildMember>,
+    ) -> Self {
+        Self {
+            account: account.name.clone(),
+            x: pos.x,
+            y: pos.y,
+            inventory: inventory.slots.clone(),
+            guild: guild.map(|member| member.guild.clone()),
+        }
+    }
+}
+
+/// Players who have been saved but aren't currently online, keyed by account.
+/// A player joining takes their record out; leaving puts it back.
+#[derive(Resource, Default)]
+pub struct SavedPlayers {
+    pub players: HashMap<String, PlayerRecord>,
+}
+
+/// How often the world is written to the database, in seconds.
+#[derive(Resource)]
+pub struct SaveTimer {
+    pub interval: f32,
+    pub last_save: f32,
+}
+
+/// The SQLite database the world is saved to.
+#[derive(Resource)]
+pub struct Storage {
+    conn: Mutex<Connection>,
+}
+
+impl Storage {
+    /// Opens (or creates) the database at `path` and brings its schema up to
+    /// date.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Ok(Self {
+            conn: Mutex::new(open_database(path)?),
+        })
+    }
+
+    pub fn open_in_memory() -> Result<Self, StorageError> {
+        Ok(Self {
+            conn: Mutex::new(prepare(Connection::open_in_memory()?)?),
+        })
+    }
+
+    /// Replaces the saved world with `snapshot` in a single transaction.
+    pub fn save(&self, snapshot: &WorldSnapshot) -> Result<(), StorageError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut save_player = tx.prepare(
+                "INSERT INTO players (account, x, y, guild) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (account) DO UPDATE
+                 SET x = excluded.x, y = excluded.y, guild = excluded.guild",
+            )?;
+            let mut clear_slots = tx.prepare("DELETE FROM inventory_slots WHERE account = ?1")?;
+            let mut save_slot = tx.prepare(
+                "INSERT INTO inventory_slots (account, slot, item_id, count)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for player in &snapshot.players {
+                save_player.execute(params![player.account, player.x, player.y, player.guild])?;
+                clear_slots.execute(params![player.account])?;
+                for (slot, stack) in player.inventory.iter().enumerate() {
+                    if let Some(stack) = stack {
+                        save_slot.execute(params![
+                            player.account,
+                            slot,
+                            stack.item_id,
+                            stack.count
+                        ])?;
+                    }
+                }
+            }
+
+            tx.execute("DELETE FROM farm_plots", [])?;
+            let mut save_plot = tx.prepare(
+                "INSERT INTO farm_plots (x, y, watered, crop_type, stage, growth_timer)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for plot in &snapshot.plots {
+                let crop = plot.crop.as_ref();
+                save_plot.execute(params![
+                    plot.x,
+                    plot.y,
+                    plot.watered,
+                    crop.map(|c| &c.crop_type),
+                    crop.map(|c| c.stage),
+                    crop.map(|c| c.growth_timer),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn load(&self) -> Result<WorldSnapshot, StorageError> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut players = conn
+            .prepare("SELECT account, x, y, guild FROM players")?
+            .query_map([], |row| {
+                Ok(PlayerRecord {
+                    account: row.get(0)?,
+                    x: row.get(1)?,
+                    y: row.get(2)?,
+                    inventory: Inventory::default().slots,
+                    guild: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut slots =
+            conn.prepare("SELECT slot, item_id, count FROM inventory_slots WHERE account = ?1")?;
+        for player in &mut players {
+            let stacks = slots.query_map(params![player.account], |row| {
+                Ok((
+                    row.get::<_, usize>(0)?,
+                    ItemStack {
+                        item_id: row.get(1)?,
+                        // Stacks never hold more than the limit, whatever the
+                        // file says
+                        count: row.get::<_, u32>(2)?.min(MAX_STACK),
+                    },
+                ))
+            })?;
+            for stack in stacks {
+                let (slot, stack) = stack?;
+                // Slots beyond the current inventory size are dropped
+                if let Some(entry) = player.inventory.get_mut(slot) {
+                    *entry = Some(stack);
+                }
+            }
+        }
+
+        let plots = conn
+            .prepare("SELECT x, y, watered, crop_type, stage, growth_timer FROM farm_plots")?
+            .query_map([], |row| {
+                let crop_type: Option<String> = row.get(3)?;
+                Ok(PlotRecord {
+                    x: row.get(0)?,
+                    y: row.get(1)?,
+                    watered: row.get(2)?,
+                    crop: match crop_type {
+                        Some(crop_type) => Some(CropRecord {
+                            crop_type,
+                            stage: row.get(4)?,
+                            growth_timer: row.get(5)?,
+                        }),
+                        None => None,
+                    },
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(WorldSnapshot { players, plots })
+    }
+}
+
+/// Loads the saved world into `world` and keeps `storage` around for saving.
+/// Farm plots are spawned right away; players wait in `SavedPlayers` until
+/// they join.
+pub fn restore_world(world: &mut World, storage: Storage) -> Result<(), StorageError> {
+    let snapshot = storage.load()?;
+    for plot in snapshot.plots {
+        let mut entity = world.spawn(FarmPlot {
+            x: plot.x,
+            y: plot.y,
+            watered: plot.watered,
+        });
+        if let Some(crop) = plot.crop {
+            entity.insert(Crop {
+                crop_type: crop.crop_type,
+                stage: crop.stage,
+                growth_timer: crop.growth_timer,
+            });
+        }
+    }
+    world.insert_resource(SavedPlayers {
+        players: snapshot
+            .players
+            .into_iter()
+            .map(|player| (player.account.clone(), player))
+            .collect(),
+    });
+    world.insert_resource(storage);
+    Ok(())
+}
+
+/// Writes the whole world to `Storage`.
+pub fn save_world(world: &mut World) -> Result<(), StorageError> {
+    let snapshot = WorldSnapshot::capture(world);
+    match world.get_resource::<Storage>() {
+        Some(storage) => storage.save(&snapshot),
+        None => Ok(()),
+    }
+}
+
+/// Saves the world every `SaveTimer::interval` seconds.
+pub fn save_system(world: &mut World) {
+    let now = world.resource::<Time>().elapsed_secs();
+    let mut timer = world.resource_mut::<SaveTimer>();
+    if now - timer.last_save < timer.interval {
+        return;
+    }
+    timer.last_save = now;
+
+    if let Err(e) = save_world(world) {
+        eprintln!("Failed to save world: {}", e);
+    }
+}
+
+/// Saves the world one last time when the app is about to exit.
+pub fn save_on_exit(world: &mut World) {
+    if world.resource::<Events<AppExit>>().is_empty() {
+        return;
+    }
+    match save_world(world) {
+        Ok(()) => println!("World saved"),
+        Err(e) => eprintln!("Failed to save world on shutdown: {}", e),
+    }
+}
@@ -1,17 +1,123 @@
-use crate::messages::{PlayerState, ServerMessage};
+use crate::config::ServerConfig;
+use crate::inventory::{INVENTORY_SLOTS, Inventory, InventoryError};
+use crate::messages::{
+    ChatChannel, CropDef, ErrorCode, Frame, PlayerState, RequestError, ServerMessage,
+};
+use crate::persistence::{PlayerRecord, SavedPlayers};
+use crate::world::{TILE_SIZE, WorldMap};
+use bevy::ecs::component::HookContext;
+use bevy::ecs::system::SystemParam;
+use bevy::ecs::world::DeferredWorld;
 use bevy::prelude::*;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::time::Duration;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use uuid::Uuid;
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bevy::prelude::*;
+    use crate::messages::WireFormat;
+
+    const TEST_CROPS: &str = r#"{"crops": [
+        {"id": "wheat", "stage_seconds": [30.0, 30.0, 30.0], "needs_water": true,
+         "seasons": ["spring"], "yield_amount": 3},
+        {"id": "weed", "stage_seconds": [5.0], "needs_water": false,
+         "seasons": [], "yield_amount": 1}
+    ]}"#;
+
+    /// App running `process_commands` with its queues and a small crop catalog.
+    fn command_app() -> (
+        App,
+        tokio::sync::mpsc::UnboundedSender<EcsCommand>,
+        tokio::sync::mpsc::UnboundedReceiver<ServerToClientMessage>,
+    ) {
+        let mut app = App::new();
+        app.add_systems(Update, (update_spatial_index, process_commands).chain());
+        app.init_resource::<SpatialIndex>();
+        app.init_resource::<PlayerRegistry>();
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (sim_tx, sim_rx) = tokio::sync::mpsc::unbounded_channel();
+        app.insert_resource(CommandQueue { rx });
+        app.insert_resource(ServerToClientQueue { tx: sim_tx });
+        app.insert_resource(CropCatalog::from_json(TEST_CROPS).unwrap());
+        app.init_resource::<ServerConfig>();
+        app.init_resource::<SimTick>();
+
+        (app, tx, sim_rx)
+    }
+
+    /// Indexes `points` as entities 0, 1, 2...
+    fn index_points(points: &[(f32, f32)]) -> (SpatialIndex, Vec<(Entity, Position)>) {
+        let entities: Vec<_> = points
+            .iter()
+            .enumerate()
+            .map(|(i, &(x, y))| (Entity::from_raw(i as u32), Position { x, y }))
+            .collect();
+        let mut index = SpatialIndex::default();
+        index.rebuild(entities.iter().map(|(entity, pos)| (*entity, pos)));
+        (index, entities)
+    }
+
+    fn coordinate() -> std::ops::Range<f32> {
+        -3000.0..3000.0
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_spatial_index_radius_matches_brute_force(
+            points in proptest::collection::vec((coordinate(), coordinate()), 0..200),
+            (x, y) in (coordinate(), coordinate()),
+            radius in proptest::prop_oneof![0.0f32..600.0, 0.0f32..1.0e7],
+        ) {
+            let (index, entities) = index_points(&points);
+            let mut found = index.within_radius(x, y, radius);
+            found.sort();
+            let expected: Vec<Entity> = entities
+                .iter()
+                .filter(|(_, p)| Vec2::new(p.x, p.y).distance_squared(Vec2::new(x, y)) <= radius * radius)
+                .map(|(entity, _)| *entity)
+                .collect();
+            proptest::prop_assert_eq!(found, expected);
+        }
+
+        #[test]
+        fn test_spatial_index_rect_matches_brute_force(
+            points in proptest::collection::vec((coordinate(), coordinate()), 0..200),
+            (x, y) in (coordinate(), coordinate()),
+            (half_width, half_height) in (0.0f32..1000.0, 0.0f32..1000.0),
+            huge in proptest::bool::ANY,
+        ) {
+            let (half_width, half_height) = if huge {
+                (half_width * 1.0e4, half_height * 1.0e4)
+            } else {
+                (half_width, half_height)
+            };
+            let (index, entities) = index_points(&points);
+            let mut found = index.within_rect(x, y, half_width, half_height);
+            found.sort();
+            let expected: Vec<Entity> = entities
+                .iter()
+                .filter(|(_, p)| {
+                    p.x >= x - half_width
+                        && p.x <= x + half_width
+                        && p.y >= y - half_height
+                        && p.y <= y + half_height
+                })
+                .map(|(entity, _)| *entity)
+                .collect();
+            proptest::prop_assert_eq!(found, expected);
+        }
+    }
 
     #[test]
     fn test_movement_system_updates_position() {
         let mut app = App::new();
         app.add_systems(Update, movement_system);
+        app.init_resource::<ServerConfig>();
         let mut time = Time::<()>::default();
         time.advance_by(std::time::Duration::from_secs_f32(1.0)); // Set delta to 1 second
         app.insert_resource(time);
@@ -35,6 +141,7 @@ mod tests {
     fn test_movement_system_with_time_delta() {
         let mut app = App::new();
         app.add_systems(Update, movement_system);
+        app.init_resource::<ServerConfig>();
         app.insert_resource(Time::<()>::default());
 
         // Set up time with a specific delta
@@ -56,19 +163,79 @@ mod tests {
         assert_eq!(pos.y, -130.0); // 20 + (-1) * 300 * 0.5
     }
 
-    #[test]
-    fn test_process_commands_spawn_player() {
+    fn walled_app(x: f32, y: f32, dx: f32, dy: f32, secs: f32) -> (App, Entity) {
         let mut app = App::new();
-        app.add_systems(Update, process_commands);
+        app.add_systems(Update, movement_system);
+        app.init_resource::<ServerConfig>();
+        let mut time = Time::<()>::default();
+        time.advance_by(std::time::Duration::from_secs_f32(secs));
+        app.insert_resource(time);
+        // Wall at tile (3, 2), i.e. x in 96..128 and y in 64..96
+        app.insert_resource(WorldMap::parse(".....\n.....\n...#.\n.....\n").unwrap());
 
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-        let (sim_tx, _sim_rx) = tokio::sync::mpsc::unbounded_channel();
+        let entity = app
+            .world_mut()
+            .spawn((Position { x, y }, Velocity { dx, dy }))
+            .id();
+        (app, entity)
+    }
 
-        app.insert_resource(CommandQueue { rx });
-        app.insert_resource(ServerToClientQueue { tx: sim_tx });
+    #[test]
+    fn test_movement_stops_at_wall() {
+        // Start on tile (1, 2) and run right into the wall for a full second
+        let (mut app, entity) = walled_app(48.0, 80.0, 1.0, 0.0, 1.0);
+        app.update();
+
+        let pos = app.world().get::<Position>(entity).unwrap();
+        assert!(pos.x < 96.0 - PLAYER_HALF_EXTENT);
+        assert!(pos.x > 96.0 - PLAYER_HALF_EXTENT - 0.01);
+        assert_eq!(pos.y, 80.0);
+    }
+
+    #[test]
+    fn test_movement_slides_along_wall() {
+        // Move diagonally up-right into the wall's left face
+        let (mut app, entity) = walled_app(80.0, 80.0, 1.0, -1.0, 0.1);
+        app.update();
+
+        let pos = app.world().get::<Position>(entity).unwrap();
+        assert!(pos.x < 96.0 - PLAYER_HALF_EXTENT);
+        assert!(pos.x > 96.0 - PLAYER_HALF_EXTENT - 0.01);
+        assert_eq!(pos.y, 50.0); // 80 - 300 * 0.1
+    }
+
+    #[test]
+    fn test_player_inside_a_wall_is_pushed_out() {
+        // Just inside the wall's left edge, trying to walk deeper in
+        let (mut app, entity) = walled_app(100.0, 80.0, 1.0, 0.0, 0.1);
+        app.update();
+
+        let pos = app.world().get::<Position>(entity).unwrap();
+        assert!(pos.x < 96.0 - PLAYER_HALF_EXTENT);
+        assert!(pos.x > 96.0 - PLAYER_HALF_EXTENT - 0.01);
+        assert_eq!(pos.y, 80.0);
+    }
+
+    #[test]
+    fn test_movement_stops_at_map_edge() {
+        let (mut app, entity) = walled_app(16.0, 16.0, -1.0, -1.0, 1.0);
+        app.update();
+
+        let pos = app.world().get::<Position>(entity).unwrap();
+        assert!(pos.x >= PLAYER_HALF_EXTENT && pos.x < PLAYER_HALF_EXTENT + 0.01);
+        assert!(pos.y >= PLAYER_HALF_EXTENT && pos.y < PLAYER_HALF_EXTENT + 0.01);
+    }
+
+    #[test]
+    fn test_process_commands_spawn_player() {
+        let (mut app, tx, _sim_rx) = command_app();
 
         let player_id = Uuid::new_v4();
-        let spawn_cmd = EcsCommand::SpawnPlayer { player_id };
+        let spawn_cmd = EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        };
 
         // Send command
         let _ = tx.send(spawn_cmd);
@@ -81,8 +248,8 @@ mod tests {
         let mut count = 0;
         for (player, pos, vel) in query.iter(app.world()) {
             assert_eq!(player.id, player_id);
-            assert_eq!(pos.x, 0.0);
-            assert_eq!(pos.y, 0.0);
+            assert_eq!(pos.x, 365.0);
+            assert_eq!(pos.y, 175.0);
             assert_eq!(vel.dx, 0.0);
             assert_eq!(vel.dy, 0.0);
             count += 1;
@@ -91,15 +258,57 @@ mod tests {
     }
 
     #[test]
-    fn test_process_commands_update_velocity() {
-        let mut app = App::new();
-        app.add_systems(Update, process_commands);
+    fn test_spawn_follows_server_config() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        app.insert_resource(ServerConfig {
+            spawn_point: crate::config::SpawnPoint { x: 48.0, y: 80.0 },
+            max_players: 1,
+            ..default()
+        });
 
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-        let (sim_tx, _sim_rx) = tokio::sync::mpsc::unbounded_channel();
+        let alice = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id: alice,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        let mut query = app.world_mut().query::<&Position>();
+        let pos = query.single(app.world()).unwrap();
+        assert_eq!((pos.x, pos.y), (48.0, 80.0));
+        while sim_rx.try_recv().is_ok() {}
+
+        // The world is full, even while alice is away
+        let _ = tx.send(EcsCommand::DisconnectPlayer { player_id: alice });
+        let bob = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id: bob,
+            request_id: Some(1),
+            account: "bob".to_string(),
+        });
+        app.update();
+        assert_rejected(&mut sim_rx, bob, ErrorCode::ServerFull, Some(1));
+        assert_eq!(query.iter(app.world()).count(), 1);
+
+        // Alice can still come back
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id: alice,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        assert!(matches!(
+            sim_rx.try_recv(),
+            Ok(ServerToClientMessage::SendToClient {
+                message: ServerMessage::PlayerJoined { .. },
+                ..
+            })
+        ));
+    }
 
-        app.insert_resource(CommandQueue { rx });
-        app.insert_resource(ServerToClientQueue { tx: sim_tx });
+    #[test]
+    fn test_process_commands_update_velocity() {
+        let (mut app, tx, _sim_rx) = command_app();
 
         let player_id = Uuid::new_v4();
 
@@ -116,8 +325,9 @@ mod tests {
         // Send update velocity command
         let update_cmd = EcsCommand::UpdateVelocity {
             player_id,
-            dx: 3.0,
-            dy: -1.5,
+            request_id: None,
+            dx: 0.5,
+            dy: -0.75,
         };
         let _ = tx.send(update_cmd);
 
@@ -125,20 +335,97 @@ mod tests {
 
         // Check velocity was updated
         let vel = app.world().get::<Velocity>(entity).unwrap();
-        assert_eq!(vel.dx, 3.0);
-        assert_eq!(vel.dy, -1.5);
+        assert_eq!(vel.dx, 0.5);
+        assert_eq!(vel.dy, -0.75);
     }
 
     #[test]
-    fn test_process_commands_despawn_player() {
-        let mut app = App::new();
-        app.add_systems(Update, process_commands);
+    fn test_movement_input_is_validated() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        let player_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        let entity = app
+            .world()
+            .resource::<PlayerRegistry>()
+            .get(player_id)
+            .unwrap();
+        let send_move = |app: &mut App, dx: f32, dy: f32| {
+            let _ = tx.send(EcsCommand::UpdateVelocity {
+                player_id,
+                request_id: None,
+                dx,
+                dy,
+            });
+            app.update();
+            let vel = app.world().get::<Velocity>(entity).unwrap();
+            let violations = app.world().get::<MovementViolations>(entity).unwrap();
+            (vel.dx, vel.dy, violations.count)
+        };
 
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-        let (sim_tx, _sim_rx) = tokio::sync::mpsc::unbounded_channel();
+        // Diagonals are normalized but not held against anyone
+        let (dx, dy, violations) = send_move(&mut app, 1.0, 1.0);
+        assert!((dx - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert!((dy - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert_eq!(violations, 0);
 
-        app.insert_resource(CommandQueue { rx });
-        app.insert_resource(ServerToClientQueue { tx: sim_tx });
+        // Huge values move no faster than the player speed
+        assert_eq!(send_move(&mut app, 1000.0, 0.0), (1.0, 0.0, 1));
+
+        // NaN and infinity are ignored
+        assert_eq!(send_move(&mut app, f32::NAN, 0.0), (1.0, 0.0, 2));
+        assert_eq!(send_move(&mut app, 0.0, f32::INFINITY), (1.0, 0.0, 3));
+        let _ = tx.send(EcsCommand::Input {
+            player_id,
+            request_id: None,
+            seq: 1,
+            dx: f32::NAN,
+            dy: f32::NEG_INFINITY,
+            tick: 1,
+        });
+        app.update();
+        assert!(
+            app.world()
+                .get::<InputBuffer>(entity)
+                .unwrap()
+                .pending
+                .is_empty()
+        );
+        while sim_rx.try_recv().is_ok() {}
+
+        // Enough violations get the player kicked, once
+        let mut kicks = || -> Vec<Uuid> {
+            std::iter::from_fn(|| sim_rx.try_recv().ok())
+                .filter_map(|msg| match msg {
+                    ServerToClientMessage::Kick { player_id, .. } => Some(player_id),
+                    _ => None,
+                })
+                .collect()
+        };
+        for _ in 0..MAX_MOVEMENT_VIOLATIONS {
+            send_move(&mut app, 1000.0, -1000.0);
+        }
+        assert_eq!(kicks(), vec![player_id]);
+
+        // The count started over, so if they're somehow still here they get
+        // kicked again
+        let count = app.world().get::<MovementViolations>(entity).unwrap().count;
+        assert!(count < MAX_MOVEMENT_VIOLATIONS);
+        for _ in count + 1..MAX_MOVEMENT_VIOLATIONS {
+            send_move(&mut app, 1000.0, -1000.0);
+        }
+        assert!(kicks().is_empty());
+        send_move(&mut app, 1000.0, -1000.0);
+        assert_eq!(kicks(), vec![player_id]);
+    }
+
+    #[test]
+    fn test_process_commands_despawn_player() {
+        let (mut app, tx, _sim_rx) = command_app();
 
         let player_id = Uuid::new_v4();
 
@@ -163,73 +450,607 @@ mod tests {
     }
 
     #[test]
-    fn test_broadcast_positions() {
-        let mut app = App::new();
-        app.add_systems(Update, broadcast_positions);
+    fn test_player_registry_follows_players() {
+        let (mut app, tx, _sim_rx) = command_app();
 
-        let (sim_tx, mut sim_rx) = tokio::sync::mpsc::unbounded_channel();
-        app.insert_resource(ServerToClientQueue { tx: sim_tx });
-        app.insert_resource(BroadcastTimer {
-            last_broadcast: 0.0,
+        // Players spawned outside `process_commands` are registered too
+        let player_id = Uuid::new_v4();
+        let entity = app.world_mut().spawn(Player { id: player_id }).id();
+        assert_eq!(
+            app.world().resource::<PlayerRegistry>().get(player_id),
+            Some(entity)
+        );
+
+        let joined_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id: joined_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        let joined = app
+            .world()
+            .resource::<PlayerRegistry>()
+            .get(joined_id)
+            .unwrap();
+        assert_eq!(app.world().get::<Player>(joined).unwrap().id, joined_id);
+
+        let _ = tx.send(EcsCommand::DespawnPlayer {
+            player_id: joined_id,
         });
+        app.update();
+        app.world_mut().despawn(entity);
+        let registry = app.world().resource::<PlayerRegistry>();
+        assert!(!registry.contains(joined_id));
+        assert!(!registry.contains(player_id));
+    }
 
-        // Insert Time with elapsed_seconds > 0.1 to trigger broadcast
-        let mut time = Time::<()>::default();
-        time.advance_by(std::time::Duration::from_secs_f32(0.2));
-        app.insert_resource(time);
+    #[test]
+    fn test_duplicate_spawn_is_rejected() {
+        let (mut app, tx, _sim_rx) = command_app();
 
-        let player_id1 = Uuid::new_v4();
-        let player_id2 = Uuid::new_v4();
+        // Both arrive before the first spawn is applied
+        let player_id = Uuid::new_v4();
+        for _ in 0..2 {
+            let _ = tx.send(EcsCommand::SpawnPlayer {
+                player_id,
+                request_id: None,
+                account: "alice".to_string(),
+            });
+        }
+        app.update();
+        // Someone else can't take over the id
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "mallory".to_string(),
+        });
+        app.update();
 
-        // Spawn two players
-        app.world_mut()
-            .spawn((Player { id: player_id1 }, Position { x: 1.0, y: 2.0 }));
+        let mut query = app.world_mut().query::<(Entity, &Player, &Account)>();
+        let players: Vec<_> = query.iter(app.world()).collect();
+        assert_eq!(players.len(), 1);
+        let (entity, player, account) = players[0];
+        assert_eq!(player.id, player_id);
+        assert_eq!(account.name, "alice");
+        assert_eq!(
+            app.world().resource::<PlayerRegistry>().get(player_id),
+            Some(entity)
+        );
+    }
 
-        app.world_mut()
-            .spawn((Player { id: player_id2 }, Position { x: 3.0, y: 4.0 }));
+    #[test]
+    fn test_join_while_connected_is_rejected() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        let player_id = Uuid::new_v4();
+        let join = |request_id| EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: Some(request_id),
+            account: "alice".to_string(),
+        };
+        let _ = tx.send(join(1));
+        app.update();
+        while sim_rx.try_recv().is_ok() {}
 
+        // Their connection is still live, so this isn't a reconnect
+        let _ = tx.send(join(2));
         app.update();
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::AlreadyInWorld, Some(2));
+        assert!(sim_rx.try_recv().is_err());
 
-        // Check broadcast message was sent
-        let msg = sim_rx.try_recv().unwrap();
-        match msg {
-            ServerToClientMessage::Broadcast { message } => {
-                if let ServerMessage::PlayerState { players } = message {
-                    assert_eq!(players.len(), 2);
-                    let player_states: std::collections::HashMap<Uuid, &PlayerState> =
-                        players.iter().map(|p| (p.player_id, p)).collect();
+        // Once it's gone the same account can take the player back
+        // Even when both arrive in the same tick
+        let _ = tx.send(EcsCommand::DisconnectPlayer { player_id });
+        let _ = tx.send(join(3));
+        app.update();
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                message: ServerMessage::PlayerJoined { .. },
+                ..
+            }
+        ));
+    }
 
-                    let p1 = player_states.get(&player_id1).unwrap();
-                    assert_eq!(p1.x, 1.0);
-                    assert_eq!(p1.y, 2.0);
+    #[test]
+    fn test_leaving_in_the_tick_of_joining() {
+        let (mut app, tx, _sim_rx) = command_app();
+        let (dropped, left) = (Uuid::new_v4(), Uuid::new_v4());
+        for (player_id, account) in [(dropped, "alice"), (left, "bob")] {
+            let _ = tx.send(EcsCommand::SpawnPlayer {
+                player_id,
+                request_id: None,
+                account: account.to_string(),
+            });
+        }
+        let _ = tx.send(EcsCommand::DisconnectPlayer { player_id: dropped });
+        let _ = tx.send(EcsCommand::DespawnPlayer { player_id: left });
+        app.update();
 
-                    let p2 = player_states.get(&player_id2).unwrap();
-                    assert_eq!(p2.x, 3.0);
-                    assert_eq!(p2.y, 4.0);
-                } else {
-                    panic!("Expected PlayerState message");
+        let registry = app.world().resource::<PlayerRegistry>();
+        assert!(!registry.contains(left));
+        let entity = registry.get(dropped).unwrap();
+        assert!(app.world().get::<Disconnected>(entity).is_some());
+    }
+
+    /// Runs `broadcast_positions` once more and returns what each player was
+    /// sent.
+    fn next_broadcast(
+        app: &mut App,
+        sim_rx: &mut tokio::sync::mpsc::UnboundedReceiver<ServerToClientMessage>,
+    ) -> Vec<(Uuid, ServerMessage)> {
+        app.update();
+        let mut sent = Vec::new();
+        while let Ok(msg) = sim_rx.try_recv() {
+            match msg {
+                ServerToClientMessage::SendToClient { player_id, message } => {
+                    sent.push((player_id, message))
+                }
+                ServerToClientMessage::SendFrame { player_ids, frame } => {
+                    for player_id in player_ids {
+                        sent.push((player_id, frame.decode(WireFormat::Json).unwrap()));
+                    }
                 }
+                _ => panic!("Expected a message for particular players"),
             }
-            _ => panic!("Expected Broadcast message"),
         }
+        sent
+    }
+
+    fn broadcast_app() -> (
+        App,
+        tokio::sync::mpsc::UnboundedReceiver<ServerToClientMessage>,
+    ) {
+        let mut app = App::new();
+        app.add_systems(
+            Update,
+            (advance_tick, update_spatial_index, broadcast_positions).chain(),
+        );
+        app.init_resource::<SpatialIndex>();
+
+        let (sim_tx, sim_rx) = tokio::sync::mpsc::unbounded_channel();
+        app.insert_resource(ServerToClientQueue { tx: sim_tx });
+        app.init_resource::<SimTick>();
+        app.init_resource::<SnapshotInterval>();
+        app.init_resource::<SnapshotHistory>();
+        (app, sim_rx)
+    }
+
+    fn spawn_viewer(app: &mut App, player_id: Uuid, x: f32, y: f32) -> Entity {
+        app.world_mut()
+            .spawn((
+                Player { id: player_id },
+                Position { x, y },
+                SnapshotAck::default(),
+                Interest::default(),
+            ))
+            .id()
+    }
+
+    #[test]
+    fn test_broadcast_positions() {
+        let (mut app, mut sim_rx) = broadcast_app();
+        let player_id1 = Uuid::new_v4();
+        let player_id2 = Uuid::new_v4();
+        let player1 = spawn_viewer(&mut app, player_id1, 1.0, 2.0);
+        let player2 = spawn_viewer(&mut app, player_id2, 3.0, 4.0);
+
+        // Nobody has acknowledged anything yet, so both get a keyframe
+        let sent = next_broadcast(&mut app, &mut sim_rx);
+        let snapshots: Vec<_> = sent
+            .iter()
+            .filter(|(_, message)| matches!(message, ServerMessage::Snapshot { .. }))
+            .collect();
+        assert_eq!(snapshots.len(), 2);
+        let ServerMessage::Snapshot {
+            seq,
+            baseline: None,
+            players,
+            ..
+        } = &snapshots[0].1
+        else {
+            panic!("Expected a keyframe");
+        };
+        let player_states: HashMap<Uuid, &PlayerState> =
+            players.iter().map(|p| (p.player_id, p)).collect();
+        let p1 = player_states.get(&player_id1).unwrap();
+        assert_eq!((p1.x, p1.y), (1.0, 2.0));
+        let p2 = player_states.get(&player_id2).unwrap();
+        assert_eq!((p2.x, p2.y), (3.0, 4.0));
+
+        // Once acknowledged, idle players produce empty deltas
+        let acked = *seq;
+        for player in [player1, player2] {
+            app.world_mut()
+                .entity_mut(player)
+                .insert(SnapshotAck { seq: Some(acked) });
+        }
+        let sent = next_broadcast(&mut app, &mut sim_rx);
+        assert_eq!(sent.len(), 2);
+        for (_, message) in &sent {
+            assert!(matches!(
+                message,
+                ServerMessage::Snapshot { baseline: Some(b), players, .. }
+                    if *b == acked && players.is_empty()
+            ));
+        }
+
+        // Only what changed since the baseline is sent
+        app.world_mut().get_mut::<Position>(player2).unwrap().x = 5.0;
+        let sent = next_broadcast(&mut app, &mut sim_rx);
+        let ServerMessage::Snapshot { players, .. } = &sent[0].1 else {
+            panic!("Expected Snapshot");
+        };
+        assert_eq!(players.len(), 1);
+        assert_eq!((players[0].player_id, players[0].x), (player_id2, 5.0));
+
+        // Keyframes come around regularly regardless of acknowledgements
+        let keyframes = (0..KEYFRAME_INTERVAL)
+            .flat_map(|_| next_broadcast(&mut app, &mut sim_rx))
+            .filter(|(_, message)| {
+                matches!(
+                    message,
+                    ServerMessage::Snapshot { baseline: None, players, .. } if players.len() == 2
+                )
+            })
+            .count();
+        assert_eq!(keyframes, 2);
     }
 
     #[test]
-    fn test_graceful_failure_invalid_player_update() {
+    fn test_crowds_share_one_encoded_snapshot() {
+        let (mut app, mut sim_rx) = broadcast_app();
+        let crowd: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for (i, player_id) in crowd.iter().enumerate() {
+            spawn_viewer(&mut app, *player_id, i as f32, 0.0);
+        }
+        let loner = Uuid::new_v4();
+        spawn_viewer(&mut app, loner, VIEW_HALF_WIDTH * 3.0, 0.0);
+
+        app.update();
+        let mut frames = Vec::new();
+        let mut snapshots = Vec::new();
+        while let Ok(msg) = sim_rx.try_recv() {
+            match msg {
+                ServerToClientMessage::SendFrame { player_ids, frame } => {
+                    frames.push((player_ids, frame.decode(WireFormat::Json).unwrap()))
+                }
+                ServerToClientMessage::SendToClient {
+                    player_id,
+                    message: message @ ServerMessage::Snapshot { .. },
+                } => snapshots.push((player_id, message)),
+                _ => {}
+            }
+        }
+
+        // The crowd sees the same players, so their keyframe is encoded once
+        assert_eq!(frames.len(), 1);
+        let (mut player_ids, message) = frames.remove(0);
+        player_ids.sort();
+        let mut expected = crowd.clone();
+        expected.sort();
+        assert_eq!(player_ids, expected);
+        let ServerMessage::Snapshot { players, .. } = message else {
+            panic!("Expected Snapshot");
+        };
+        let listed: Vec<Uuid> = players.iter().map(|p| p.player_id).collect();
+        assert_eq!(listed, expected);
+
+        // Anyone whose snapshot is their own gets it as usual
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].0, loner);
+    }
+
+    #[test]
+    fn test_snapshots_follow_the_interval() {
+        let (mut app, mut sim_rx) = broadcast_app();
+        app.insert_resource(SnapshotInterval { ticks: 3 });
+        spawn_viewer(&mut app, Uuid::new_v4(), 0.0, 0.0);
+
+        let ticks: Vec<u64> = (0..7)
+            .flat_map(|_| next_broadcast(&mut app, &mut sim_rx))
+            .filter_map(|(_, message)| match message {
+                ServerMessage::Snapshot { tick, .. } => Some(tick),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ticks, vec![3, 6]);
+    }
+
+    /// App running the whole `SimulationPlugin` on a manual clock that moves
+    /// one tick per update.
+    fn fixed_app(
+        tick_rate: f64,
+    ) -> (
+        App,
+        tokio::sync::mpsc::UnboundedSender<EcsCommand>,
+        tokio::sync::mpsc::UnboundedReceiver<ServerToClientMessage>,
+    ) {
         let mut app = App::new();
-        app.add_systems(Update, process_commands);
+        app.add_plugins((bevy::time::TimePlugin, SimulationPlugin { tick_rate }));
+        app.insert_resource(bevy::time::TimeUpdateStrategy::ManualDuration(
+            std::time::Duration::from_secs_f64(1.0 / tick_rate),
+        ));
 
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-        let (sim_tx, _sim_rx) = tokio::sync::mpsc::unbounded_channel();
-
+        let (sim_tx, sim_rx) = tokio::sync::mpsc::unbounded_channel();
         app.insert_resource(CommandQueue { rx });
         app.insert_resource(ServerToClientQueue { tx: sim_tx });
+        app.insert_resource(CropCatalog::from_json(TEST_CROPS).unwrap());
+        // The first update only starts the clock
+        app.update();
+        (app, tx, sim_rx)
+    }
+
+    /// Walks a player for 30 ticks at 30 Hz, `ticks_per_frame` ticks per app
+    /// update, and returns the final tick, position and snapshot ticks.
+    fn walk_for_a_second(ticks_per_frame: u32) -> (u64, f32, f32, Vec<u64>) {
+        let (mut app, tx, mut sim_rx) = fixed_app(30.0);
+        let player_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        let _ = tx.send(EcsCommand::UpdateVelocity {
+            player_id,
+            request_id: None,
+            dx: 0.8,
+            dy: 0.6,
+        });
+
+        // A slow host runs several ticks per update to catch up
+        let tick_length = app.world().resource::<Time<Fixed>>().timestep();
+        app.insert_resource(bevy::time::TimeUpdateStrategy::ManualDuration(
+            tick_length * ticks_per_frame,
+        ));
+        for _ in 0..30 / ticks_per_frame {
+            app.update();
+        }
+
+        let mut snapshot_ticks = Vec::new();
+        while let Ok(msg) = sim_rx.try_recv() {
+            if let ServerToClientMessage::SendToClient {
+                message: ServerMessage::Snapshot { tick, .. },
+                ..
+            } = msg
+            {
+                snapshot_ticks.push(tick);
+            }
+        }
+        let entity = app
+            .world()
+            .resource::<PlayerRegistry>()
+            .get(player_id)
+            .unwrap();
+        let pos = app.world().get::<Position>(entity).unwrap();
+        (
+            app.world().resource::<SimTick>().0,
+            pos.x,
+            pos.y,
+            snapshot_ticks,
+        )
+    }
+
+    #[test]
+    fn test_simulation_steps_in_fixed_ticks() {
+        let (tick, x, y, snapshot_ticks) = walk_for_a_second(1);
+        assert_eq!(tick, 31);
+        assert_eq!(snapshot_ticks, (1..=31).collect::<Vec<_>>());
+        // A second of walking, give or take float rounding
+        assert!((x - (365.0 + DEFAULT_PLAYER_SPEED * 0.8)).abs() < 0.01);
+        assert!((y - (175.0 + DEFAULT_PLAYER_SPEED * 0.6)).abs() < 0.01);
+
+        // Exactly the same outcome however the ticks are spread over updates
+        assert_eq!(walk_for_a_second(3), (tick, x, y, snapshot_ticks));
+    }
+
+    /// Input `seq` of a made-up walk, meant for tick `seq + 1`.
+    fn walk_input(player_id: Uuid, seq: u32) -> EcsCommand {
+        let (dx, dy) = match seq % 3 {
+            0 => (1.0, 0.0),
+            1 => (0.0, 1.0),
+            _ => (-0.5, 0.5),
+        };
+        EcsCommand::Input {
+            player_id,
+            request_id: None,
+            seq,
+            dx,
+            dy,
+            tick: seq as u64 + 1,
+        }
+    }
+
+    /// Spawns a player on tick 1, then runs ticks 2 to 31 sending the inputs
+    /// in `arrivals[i]` just before tick `i + 2`. Returns where the player
+    /// ended up and the last input their final snapshot reported.
+    fn replay_inputs(arrivals: &[Vec<u32>]) -> (f32, f32, Option<u32>) {
+        let (mut app, tx, mut sim_rx) = fixed_app(30.0);
+        let player_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        for seqs in arrivals {
+            for seq in seqs {
+                let _ = tx.send(walk_input(player_id, *seq));
+            }
+            app.update();
+        }
+
+        let mut last_processed_input = None;
+        while let Ok(msg) = sim_rx.try_recv() {
+            if let ServerToClientMessage::SendToClient {
+                message: ServerMessage::Snapshot { players, .. },
+                ..
+            } = msg
+            {
+                last_processed_input = players[0].last_processed_input;
+            }
+        }
+        let entity = app
+            .world()
+            .resource::<PlayerRegistry>()
+            .get(player_id)
+            .unwrap();
+        let pos = app.world().get::<Position>(entity).unwrap();
+        (pos.x, pos.y, last_processed_input)
+    }
+
+    #[test]
+    fn test_jittered_inputs_are_applied_on_their_tick() {
+        // Every input arrives right before its tick
+        let steady: Vec<Vec<u32>> = (1..=30).map(|seq| vec![seq]).collect();
+        let (x, y, last) = replay_inputs(&steady);
+        assert_eq!(last, Some(30));
+        let tick = 1.0 / 30.0;
+        let (expected_x, expected_y) = (1..=30).fold((365.0, 175.0), |(x, y), seq| {
+            let EcsCommand::Input { dx, dy, .. } = walk_input(Uuid::nil(), seq) else {
+                unreachable!()
+            };
+            (
+                x + dx * DEFAULT_PLAYER_SPEED * tick,
+                y + dy * DEFAULT_PLAYER_SPEED * tick,
+            )
+        });
+        assert!((x - expected_x).abs() < 0.01 && (y - expected_y).abs() < 0.01);
+
+        // Bunched up, out of order and repeated, but never late
+        let mut jittered: Vec<Vec<u32>> = vec![Vec::new(); 30];
+        for first in (1..=30).step_by(4) {
+            let bunch = (first..(first + 4).min(31)).rev();
+            jittered[first as usize - 1].extend(bunch);
+            if first > 1 {
+                jittered[first as usize - 1].push(first - 1);
+            }
+        }
+        assert_eq!(replay_inputs(&jittered), (x, y, Some(30)));
+    }
+
+    #[test]
+    fn test_latest_due_input_wins() {
+        let mut buffer = InputBuffer::default();
+        buffer.push(1, 5, 1.0, 0.0);
+        buffer.push(2, 6, 0.0, 1.0);
+        buffer.push(3, 7, -1.0, 0.0);
+        assert_eq!(buffer.take_due(4), None);
+
+        // The late input and the current one are both due; only the current
+        // one's direction is used, but both count as processed
+        assert_eq!(buffer.take_due(6), Some((0.0, 1.0)));
+        assert_eq!(buffer.last_processed, Some(2));
+        assert_eq!(buffer.take_due(7), Some((-1.0, 0.0)));
+        assert_eq!(buffer.last_processed, Some(3));
+    }
+
+    #[test]
+    fn test_inputs_too_far_ahead_are_rejected() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        let player_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        while sim_rx.try_recv().is_ok() {}
+
+        // Would otherwise hold up every later input until its tick came
+        let _ = tx.send(EcsCommand::Input {
+            player_id,
+            request_id: Some(1),
+            seq: 1,
+            dx: 1.0,
+            dy: 0.0,
+            tick: MAX_INPUT_LEAD_TICKS + 1,
+        });
+        let _ = tx.send(EcsCommand::Input {
+            player_id,
+            request_id: None,
+            seq: 2,
+            dx: 0.0,
+            dy: 1.0,
+            tick: MAX_INPUT_LEAD_TICKS,
+        });
+        app.update();
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::InvalidMovement, Some(1));
+
+        let entity = app
+            .world()
+            .resource::<PlayerRegistry>()
+            .get(player_id)
+            .unwrap();
+        let mut buffer = app.world_mut().get_mut::<InputBuffer>(entity).unwrap();
+        assert_eq!(buffer.take_due(MAX_INPUT_LEAD_TICKS), Some((0.0, 1.0)));
+        assert_eq!(buffer.last_processed, Some(2));
+    }
+
+    #[test]
+    fn test_only_players_in_view_are_sent() {
+        let (mut app, mut sim_rx) = broadcast_app();
+        let near_id = Uuid::new_v4();
+        let far_id = Uuid::new_v4();
+        spawn_viewer(&mut app, near_id, 0.0, 0.0);
+        let far = spawn_viewer(&mut app, far_id, VIEW_HALF_WIDTH * 3.0, 0.0);
+
+        // Everyone only sees themselves
+        for (player_id, message) in next_broadcast(&mut app, &mut sim_rx) {
+            match message {
+                ServerMessage::EntityEntered { entity_id, .. } => {
+                    assert_eq!(entity_id, player_id)
+                }
+                ServerMessage::Snapshot { players, .. } => {
+                    assert_eq!(players.len(), 1);
+                    assert_eq!(players[0].player_id, player_id);
+                }
+                other => panic!("Unexpected {:?}", other),
+            }
+        }
+
+        // Walking into view is announced to both sides, once
+        app.world_mut().get_mut::<Position>(far).unwrap().x = VIEW_HALF_WIDTH - 10.0;
+        let sent = next_broadcast(&mut app, &mut sim_rx);
+        assert!(sent.iter().any(|(to, message)| *to == near_id
+            && matches!(message, ServerMessage::EntityEntered { entity_id, .. } if *entity_id == far_id)));
+        assert!(sent.iter().any(|(to, message)| *to == far_id
+            && matches!(message, ServerMessage::EntityEntered { entity_id, .. } if *entity_id == near_id)));
+        assert!(sent.iter().all(|(_, message)| match message {
+            ServerMessage::Snapshot { players, .. } => players.len() == 2,
+            _ => true,
+        }));
+        let sent = next_broadcast(&mut app, &mut sim_rx);
+        assert!(
+            sent.iter()
+                .all(|(_, message)| matches!(message, ServerMessage::Snapshot { .. }))
+        );
+
+        // Walking away or leaving the world takes them out of view again
+        app.world_mut().get_mut::<Position>(far).unwrap().y = VIEW_HALF_HEIGHT * 2.0;
+        let sent = next_broadcast(&mut app, &mut sim_rx);
+        assert!(sent.iter().any(|(to, message)| *to == near_id
+            && matches!(message, ServerMessage::EntityLeft { entity_id } if *entity_id == far_id)));
+        app.world_mut().get_mut::<Position>(far).unwrap().y = 0.0;
+        next_broadcast(&mut app, &mut sim_rx);
+        app.world_mut().despawn(far);
+        let sent = next_broadcast(&mut app, &mut sim_rx);
+        assert!(sent.iter().any(|(to, message)| *to == near_id
+            && matches!(message, ServerMessage::EntityLeft { entity_id } if *entity_id == far_id)));
+    }
+
+    #[test]
+    fn test_graceful_failure_invalid_player_update() {
+        let (mut app, tx, _sim_rx) = command_app();
 
         let invalid_player_id = Uuid::new_v4();
 
         // Try to update velocity for non-existent player
         let update_cmd = EcsCommand::UpdateVelocity {
             player_id: invalid_player_id,
+            request_id: None,
             dx: 1.0,
             dy: 1.0,
         };
@@ -242,17 +1063,1103 @@ mod tests {
         let mut query = app.world_mut().query::<&Player>();
         assert_eq!(query.iter(app.world()).count(), 0);
     }
-}
 
-#[derive(Resource)]
-pub struct CommandQueue {
-    pub rx: UnboundedReceiver<EcsCommand>,
-}
+    fn farming_app() -> (
+        App,
+        tokio::sync::mpsc::UnboundedSender<EcsCommand>,
+        tokio::sync::mpsc::UnboundedReceiver<ServerToClientMessage>,
+        Uuid,
+    ) {
+        let (mut app, tx, sim_rx) = command_app();
 
-#[derive(Resource)]
-pub struct ServerToClientQueue {
-    pub tx: UnboundedSender<ServerToClientMessage>,
-}
+        // Player standing on the center of tile (1, 1) with a few seeds
+        let player_id = Uuid::new_v4();
+        let mut inventory = Inventory::default();
+        inventory.add("wheat_seed", 2).unwrap();
+        app.world_mut().spawn((
+            Player { id: player_id },
+            Position { x: 48.0, y: 48.0 },
+            Velocity { dx: 0.0, dy: 0.0 },
+            inventory,
+        ));
+
+        (app, tx, sim_rx, player_id)
+    }
+
+    /// Asserts the sim's next message turns down one of `player_id`'s
+    /// requests: in its ack if it had an id, in an error otherwise.
+    fn assert_rejected(
+        sim_rx: &mut tokio::sync::mpsc::UnboundedReceiver<ServerToClientMessage>,
+        player_id: Uuid,
+        code: ErrorCode,
+        request_id: Option<u32>,
+    ) {
+        let ServerToClientMessage::SendToClient {
+            player_id: pid,
+            message,
+        } = sim_rx.try_recv().unwrap()
+        else {
+            panic!("Expected a {:?} error for the player", code);
+        };
+        assert_eq!(pid, player_id);
+        match (message, request_id) {
+            (
+                ServerMessage::Ack {
+                    request_id: rid,
+                    result: Err(error),
+                },
+                Some(request_id),
+            ) => {
+                assert_eq!(rid, request_id);
+                assert_eq!(error.code, code);
+            }
+            (
+                ServerMessage::Error {
+                    code: c,
+                    request_id: None,
+                    ..
+                },
+                None,
+            ) => assert_eq!(c, code),
+            _ => panic!("Expected a {:?} error", code),
+        }
+    }
+
+    /// Asserts the sim's next message acknowledges `request_id` as done.
+    fn assert_acked(
+        sim_rx: &mut tokio::sync::mpsc::UnboundedReceiver<ServerToClientMessage>,
+        player_id: Uuid,
+        request_id: u32,
+    ) {
+        match sim_rx.try_recv().unwrap() {
+            ServerToClientMessage::SendToClient {
+                player_id: pid,
+                message:
+                    ServerMessage::Ack {
+                        request_id: rid,
+                        result: Ok(()),
+                    },
+            } => assert_eq!((pid, rid), (player_id, request_id)),
+            _ => panic!("Expected request {} to be acknowledged", request_id),
+        }
+    }
+
+    #[test]
+    fn test_plant_water_and_harvest_crop() {
+        let (mut app, tx, mut sim_rx, player_id) = farming_app();
+
+        let _ = tx.send(EcsCommand::PlantCrop {
+            player_id,
+            request_id: None,
+            x: 1,
+            y: 1,
+            crop_type: "wheat".to_string(),
+        });
+        // Second plant on the same tile is rejected
+        let _ = tx.send(EcsCommand::PlantCrop {
+            player_id,
+            request_id: None,
+            x: 1,
+            y: 1,
+            crop_type: "weed".to_string(),
+        });
+        app.update();
+
+        // The seed is taken from the planter's inventory
+        match sim_rx.try_recv().unwrap() {
+            ServerToClientMessage::SendToClient {
+                player_id: pid,
+                message: ServerMessage::InventoryUpdated { slots },
+            } => {
+                assert_eq!(pid, player_id);
+                assert_eq!(slots[0].stack.as_ref().unwrap().count, 1);
+            }
+            _ => panic!("Expected InventoryUpdated for the planter"),
+        }
+        match sim_rx.try_recv().unwrap() {
+            ServerToClientMessage::Broadcast {
+                message:
+                    ServerMessage::CropPlanted {
+                        x,
+                        y,
+                        crop_type,
+                        stage,
+                        ..
+                    },
+            } => {
+                assert_eq!((x, y), (1, 1));
+                assert_eq!(crop_type, "wheat");
+                assert_eq!(stage, 0);
+            }
+            _ => panic!("Expected CropPlanted broadcast"),
+        }
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::TileOccupied, None);
+        assert!(sim_rx.try_recv().is_err());
+
+        let _ = tx.send(EcsCommand::WaterPlot {
+            player_id,
+            request_id: None,
+            x: 1,
+            y: 1,
+        });
+        // Unripe crops can't be harvested
+        let _ = tx.send(EcsCommand::Harvest {
+            player_id,
+            request_id: None,
+            x: 1,
+            y: 1,
+        });
+        app.update();
+
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::Broadcast {
+                message: ServerMessage::PlotWatered { x: 1, y: 1 }
+            }
+        ));
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::NotRipe, None);
+        assert!(sim_rx.try_recv().is_err());
+
+        // Ripen the crop and harvest it
+        let mut query = app.world_mut().query::<&mut Crop>();
+        query.single_mut(app.world_mut()).unwrap().stage = 3;
+        let _ = tx.send(EcsCommand::Harvest {
+            player_id,
+            request_id: None,
+            x: 1,
+            y: 1,
+        });
+        app.update();
+
+        // The yield goes into the first free slot
+        match sim_rx.try_recv().unwrap() {
+            ServerToClientMessage::SendToClient {
+                player_id: pid,
+                message: ServerMessage::InventoryUpdated { slots },
+            } => {
+                assert_eq!(pid, player_id);
+                assert_eq!(slots[0].slot, 1);
+                assert_eq!(slots[0].stack.as_ref().unwrap().item_id, "wheat");
+                assert_eq!(slots[0].stack.as_ref().unwrap().count, 3);
+            }
+            _ => panic!("Expected InventoryUpdated for the harvester"),
+        }
+        match sim_rx.try_recv().unwrap() {
+            ServerToClientMessage::Broadcast {
+                message:
+                    ServerMessage::CropHarvested {
+                        x,
+                        y,
+                        crop_type,
+                        player_id: pid,
+                    },
+            } => {
+                assert_eq!((x, y), (1, 1));
+                assert_eq!(crop_type, "wheat");
+                assert_eq!(pid, player_id);
+            }
+            _ => panic!("Expected CropHarvested broadcast"),
+        }
+
+        // The plot stays, the crop is gone
+        let mut query = app.world_mut().query::<(&FarmPlot, Option<&Crop>)>();
+        let (plot, crop) = query.single(app.world()).unwrap();
+        assert_eq!((plot.x, plot.y), (1, 1));
+        assert!(crop.is_none());
+    }
+
+    #[test]
+    fn test_harvest_handler_skips_tiles_planted_this_run() {
+        use bevy::ecs::system::RunSystemOnce;
+
+        let (mut app, _tx, mut sim_rx, player_id) = farming_app();
+        app.world_mut().spawn((
+            FarmPlot {
+                x: 1,
+                y: 1,
+                watered: true,
+            },
+            Crop {
+                crop_type: "wheat".to_string(),
+                stage: 3,
+                growth_timer: 0.0,
+            },
+        ));
+
+        // A crop planted earlier in the run isn't in the world yet, so the
+        // ripe one found there must not be harvested in its place
+        let result = app
+            .world_mut()
+            .run_system_once(move |mut ctx: CommandContext| {
+                let mut pending = PendingChanges::default();
+                pending.touched_plots.insert((1, 1));
+                ctx.harvest(&mut pending, player_id, 1, 1)
+            })
+            .unwrap();
+        assert_eq!(result.unwrap_err().code, ErrorCode::NothingToHarvest);
+
+        let result = app
+            .world_mut()
+            .run_system_once(move |mut ctx: CommandContext| {
+                ctx.harvest(&mut PendingChanges::default(), player_id, 1, 1)
+            })
+            .unwrap();
+        assert!(result.is_ok());
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                message: ServerMessage::InventoryUpdated { .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_farming_out_of_reach_is_rejected() {
+        let (mut app, tx, mut sim_rx, player_id) = farming_app();
+
+        let _ = tx.send(EcsCommand::PlantCrop {
+            player_id,
+            request_id: Some(1),
+            x: 20,
+            y: 20,
+            crop_type: "wheat".to_string(),
+        });
+        let _ = tx.send(EcsCommand::PlantCrop {
+            player_id,
+            request_id: Some(2),
+            x: 1,
+            y: 1,
+            crop_type: "not_a_crop".to_string(),
+        });
+        app.update();
+
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::OutOfReach, Some(1));
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::UnknownCrop, Some(2));
+        assert!(sim_rx.try_recv().is_err());
+        let mut query = app.world_mut().query::<&FarmPlot>();
+        assert_eq!(query.iter(app.world()).count(), 0);
+    }
+
+    #[test]
+    fn test_planting_requires_seed() {
+        let (mut app, tx, mut sim_rx, player_id) = farming_app();
+
+        // The player only carries wheat seeds
+        let _ = tx.send(EcsCommand::PlantCrop {
+            player_id,
+            request_id: None,
+            x: 1,
+            y: 1,
+            crop_type: "weed".to_string(),
+        });
+        // Using a slot that holds nothing plants nothing
+        let _ = tx.send(EcsCommand::UseItem {
+            player_id,
+            request_id: None,
+            slot: 5,
+            x: 1,
+            y: 1,
+        });
+        app.update();
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::MissingItem, None);
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::InvalidSlot, None);
+        assert!(sim_rx.try_recv().is_err());
+
+        // Using the seed slot plants the seed's crop
+        let _ = tx.send(EcsCommand::UseItem {
+            player_id,
+            request_id: None,
+            slot: 0,
+            x: 1,
+            y: 2,
+        });
+        app.update();
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                message: ServerMessage::InventoryUpdated { .. },
+                ..
+            }
+        ));
+        match sim_rx.try_recv().unwrap() {
+            ServerToClientMessage::Broadcast {
+                message:
+                    ServerMessage::CropPlanted {
+                        x, y, crop_type, ..
+                    },
+            } => {
+                assert_eq!((x, y), (1, 2));
+                assert_eq!(crop_type, "wheat");
+            }
+            _ => panic!("Expected CropPlanted broadcast"),
+        }
+    }
+
+    #[test]
+    fn test_inventory_commands_update_only_the_owner() {
+        let (mut app, tx, mut sim_rx, player_id) = farming_app();
+
+        let _ = tx.send(EcsCommand::SplitStack {
+            player_id,
+            request_id: None,
+            slot: 0,
+            count: 1,
+            to: 3,
+        });
+        // Out of range and empty slots are rejected
+        let _ = tx.send(EcsCommand::MoveItem {
+            player_id,
+            request_id: None,
+            from: 0,
+            to: INVENTORY_SLOTS,
+        });
+        let _ = tx.send(EcsCommand::DropItem {
+            player_id,
+            request_id: None,
+            slot: 7,
+            count: 1,
+        });
+        let _ = tx.send(EcsCommand::DropItem {
+            player_id,
+            request_id: None,
+            slot: 3,
+            count: 1,
+        });
+        app.update();
+
+        match sim_rx.try_recv().unwrap() {
+            ServerToClientMessage::SendToClient {
+                player_id: pid,
+                message: ServerMessage::InventoryUpdated { slots },
+            } => {
+                assert_eq!(pid, player_id);
+                assert_eq!(slots.len(), 2);
+                assert_eq!(slots[1].slot, 3);
+                assert_eq!(slots[1].stack.as_ref().unwrap().count, 1);
+            }
+            _ => panic!("Expected InventoryUpdated for the owner"),
+        }
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::InvalidSlot, None);
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::InvalidSlot, None);
+        match sim_rx.try_recv().unwrap() {
+            ServerToClientMessage::SendToClient {
+                player_id: pid,
+                message: ServerMessage::InventoryUpdated { slots },
+            } => {
+                assert_eq!(pid, player_id);
+                assert_eq!(slots.len(), 1);
+                assert!(slots[0].stack.is_none());
+            }
+            _ => panic!("Expected InventoryUpdated for the owner"),
+        }
+        assert!(sim_rx.try_recv().is_err());
+
+        let mut query = app.world_mut().query::<&Inventory>();
+        let inventory = query.single(app.world()).unwrap();
+        assert_eq!(inventory.count("wheat_seed"), 1);
+    }
+
+    #[test]
+    fn test_invalid_requests_are_answered_with_errors() {
+        let (mut app, tx, mut sim_rx, player_id) = farming_app();
+        app.world_mut().spawn((FarmPlot {
+            x: 2,
+            y: 1,
+            watered: true,
+        },));
+
+        let stranger = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::Harvest {
+            player_id: stranger,
+            request_id: Some(1),
+            x: 1,
+            y: 1,
+        });
+        let _ = tx.send(EcsCommand::WaterPlot {
+            player_id,
+            request_id: Some(2),
+            x: 1,
+            y: 1,
+        });
+        let _ = tx.send(EcsCommand::WaterPlot {
+            player_id,
+            request_id: Some(3),
+            x: 2,
+            y: 1,
+        });
+        let _ = tx.send(EcsCommand::Harvest {
+            player_id,
+            request_id: Some(4),
+            x: 2,
+            y: 1,
+        });
+        let _ = tx.send(EcsCommand::Chat {
+            player_id,
+            request_id: Some(5),
+            channel: ChatChannel::Whisper { to: stranger },
+            text: "hi".to_string(),
+        });
+        let _ = tx.send(EcsCommand::Chat {
+            player_id,
+            request_id: Some(6),
+            channel: ChatChannel::Guild,
+            text: "hi".to_string(),
+        });
+        let _ = tx.send(EcsCommand::Chat {
+            player_id,
+            request_id: Some(7),
+            channel: ChatChannel::Say,
+            text: " \n ".to_string(),
+        });
+        app.update();
+
+        // Each reply goes back to whoever asked, under their request id
+        assert_rejected(&mut sim_rx, stranger, ErrorCode::NotInWorld, Some(1));
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::NoPlot, Some(2));
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::AlreadyWatered, Some(3));
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::NothingToHarvest, Some(4));
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::PlayerNotFound, Some(5));
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::NotInGuild, Some(6));
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::EmptyMessage, Some(7));
+        assert!(sim_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_tagged_requests_are_acknowledged() {
+        let (mut app, tx, mut sim_rx, player_id) = farming_app();
+
+        let _ = tx.send(EcsCommand::PlantCrop {
+            player_id,
+            request_id: Some(1),
+            x: 1,
+            y: 1,
+            crop_type: "wheat".to_string(),
+        });
+        // Untagged requests aren't acknowledged
+        let _ = tx.send(EcsCommand::MoveItem {
+            player_id,
+            request_id: None,
+            from: 0,
+            to: 5,
+        });
+        let _ = tx.send(EcsCommand::UpdateVelocity {
+            player_id,
+            request_id: Some(2),
+            dx: f32::NAN,
+            dy: 0.0,
+        });
+        let _ = tx.send(EcsCommand::UpdateVelocity {
+            player_id,
+            request_id: Some(3),
+            dx: 0.0,
+            dy: 1.0,
+        });
+        app.update();
+
+        // The ack follows the request's effects
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                message: ServerMessage::InventoryUpdated { .. },
+                ..
+            }
+        ));
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::Broadcast {
+                message: ServerMessage::CropPlanted { .. }
+            }
+        ));
+        assert_acked(&mut sim_rx, player_id, 1);
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                message: ServerMessage::InventoryUpdated { .. },
+                ..
+            }
+        ));
+        assert_rejected(&mut sim_rx, player_id, ErrorCode::InvalidMovement, Some(2));
+        assert_acked(&mut sim_rx, player_id, 3);
+        assert!(sim_rx.try_recv().is_err());
+    }
+
+    /// Spawns a player at the given position and returns their id.
+    fn spawn_chatter(app: &mut App, x: f32, y: f32, guild: Option<&str>) -> Uuid {
+        let player_id = Uuid::new_v4();
+        let mut entity = app.world_mut().spawn((
+            Player { id: player_id },
+            Position { x, y },
+            Velocity { dx: 0.0, dy: 0.0 },
+        ));
+        if let Some(guild) = guild {
+            entity.insert(GuildMember {
+                guild: guild.to_string(),
+            });
+        }
+        player_id
+    }
+
+    /// Collects who received chat messages and what they said.
+    fn chat_recipients(
+        sim_rx: &mut tokio::sync::mpsc::UnboundedReceiver<ServerToClientMessage>,
+    ) -> Vec<(Uuid, String)> {
+        let mut received = Vec::new();
+        while let Ok(msg) = sim_rx.try_recv() {
+            if let ServerToClientMessage::SendToClient {
+                player_id,
+                message: ServerMessage::ChatMessage { text, .. },
+            } = msg
+            {
+                received.push((player_id, text));
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn test_say_chat_reaches_only_nearby_players() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        let speaker = spawn_chatter(&mut app, 100.0, 100.0, None);
+        let nearby = spawn_chatter(&mut app, 100.0 + SAY_RADIUS - 1.0, 100.0, None);
+        let far_away = spawn_chatter(&mut app, 100.0 + SAY_RADIUS + 1.0, 100.0, None);
+
+        let _ = tx.send(EcsCommand::Chat {
+            player_id: speaker,
+            request_id: None,
+            channel: ChatChannel::Say,
+            text: "hello".to_string(),
+        });
+        app.update();
+
+        let recipients: Vec<Uuid> = chat_recipients(&mut sim_rx)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(recipients.len(), 2);
+        assert!(recipients.contains(&speaker));
+        assert!(recipients.contains(&nearby));
+        assert!(!recipients.contains(&far_away));
+    }
+
+    #[test]
+    fn test_whisper_and_guild_chat_recipients() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        let speaker = spawn_chatter(&mut app, 0.0, 0.0, Some("growers"));
+        let guildmate = spawn_chatter(&mut app, 5000.0, 0.0, Some("growers"));
+        let rival = spawn_chatter(&mut app, 10.0, 0.0, Some("harvesters"));
+
+        let _ = tx.send(EcsCommand::Chat {
+            player_id: speaker,
+            request_id: None,
+            channel: ChatChannel::Whisper { to: rival },
+            text: "psst".to_string(),
+        });
+        app.update();
+        let mut recipients: Vec<Uuid> = chat_recipients(&mut sim_rx)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        recipients.sort();
+        let mut expected = vec![speaker, rival];
+        expected.sort();
+        assert_eq!(recipients, expected);
+
+        let _ = tx.send(EcsCommand::Chat {
+            player_id: speaker,
+            request_id: None,
+            channel: ChatChannel::Guild,
+            text: "meet at the barn".to_string(),
+        });
+        app.update();
+        let recipients: Vec<Uuid> = chat_recipients(&mut sim_rx)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(recipients.len(), 2);
+        assert!(recipients.contains(&guildmate));
+        assert!(!recipients.contains(&rival));
+    }
+
+    #[test]
+    fn test_measured_latency_is_kept_and_reported() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        let player_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        while sim_rx.try_recv().is_ok() {}
+
+        let _ = tx.send(EcsCommand::UpdateLatency {
+            player_id,
+            rtt: Duration::from_micros(42_700),
+        });
+        app.update();
+        let entity = app
+            .world()
+            .resource::<PlayerRegistry>()
+            .get(player_id)
+            .unwrap();
+        let latency = app.world().get::<Latency>(entity).unwrap();
+        assert_eq!(latency.rtt, Duration::from_micros(42_700));
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                player_id: pid,
+                message: ServerMessage::Latency { rtt_ms: 42 },
+            } if pid == player_id
+        ));
+    }
+
+    #[test]
+    fn test_guilds_can_be_joined_left_and_kept() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        app.init_resource::<SavedPlayers>();
+        let join = |player_id, account: &str| EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: account.to_string(),
+        };
+        let guild_chat = |player_id| EcsCommand::Chat {
+            player_id,
+            request_id: None,
+            channel: ChatChannel::Guild,
+            text: "meet at the barn".to_string(),
+        };
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let _ = tx.send(join(alice, "alice"));
+        let _ = tx.send(join(bob, "bob"));
+        app.update();
+        while sim_rx.try_recv().is_ok() {}
+
+        for (player_id, request_id) in [(alice, 1), (bob, 2)] {
+            let _ = tx.send(EcsCommand::JoinGuild {
+                player_id,
+                request_id: Some(request_id),
+                guild: "growers".to_string(),
+            });
+        }
+        let _ = tx.send(EcsCommand::JoinGuild {
+            player_id: alice,
+            request_id: Some(3),
+            guild: "no spaces!".to_string(),
+        });
+        app.update();
+        for (player_id, request_id) in [(alice, 1), (bob, 2)] {
+            assert!(matches!(
+                sim_rx.try_recv().unwrap(),
+                ServerToClientMessage::SendToClient {
+                    player_id: pid,
+                    message: ServerMessage::GuildChanged { guild: Some(guild) },
+                } if pid == player_id && guild == "growers"
+            ));
+            assert_acked(&mut sim_rx, player_id, request_id);
+        }
+        assert_rejected(&mut sim_rx, alice, ErrorCode::InvalidGuildName, Some(3));
+
+        let _ = tx.send(guild_chat(alice));
+        app.update();
+        assert_eq!(chat_recipients(&mut sim_rx).len(), 2);
+
+        // Bob leaves, and then has no guild to leave or talk to
+        let leave = |request_id| EcsCommand::LeaveGuild {
+            player_id: bob,
+            request_id: Some(request_id),
+        };
+        let _ = tx.send(leave(4));
+        app.update();
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                message: ServerMessage::GuildChanged { guild: None },
+                ..
+            }
+        ));
+        assert_acked(&mut sim_rx, bob, 4);
+        let _ = tx.send(leave(5));
+        let _ = tx.send(guild_chat(bob));
+        app.update();
+        assert_rejected(&mut sim_rx, bob, ErrorCode::NotInGuild, Some(5));
+        assert_rejected(&mut sim_rx, bob, ErrorCode::NotInGuild, None);
+
+        // Alice is still in the guild after leaving the world and coming back
+        let _ = tx.send(EcsCommand::DespawnPlayer { player_id: alice });
+        app.update();
+        let saved = &app.world().resource::<SavedPlayers>().players["alice"];
+        assert_eq!(saved.guild.as_deref(), Some("growers"));
+        while sim_rx.try_recv().is_ok() {}
+        let alice = Uuid::new_v4();
+        let _ = tx.send(join(alice, "alice"));
+        app.update();
+        let guilds: Vec<_> = std::iter::from_fn(|| sim_rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                ServerToClientMessage::SendToClient {
+                    message: ServerMessage::GuildChanged { guild },
+                    ..
+                } => Some(guild),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(guilds, vec![Some("growers".to_string())]);
+        let _ = tx.send(guild_chat(alice));
+        app.update();
+        assert_eq!(chat_recipients(&mut sim_rx).len(), 1);
+    }
+
+    #[test]
+    fn test_chat_is_sanitized() {
+        assert_eq!(
+            sanitize_chat("  hi\u{7}\nthere\t "),
+            Some("hithere".to_string())
+        );
+        assert_eq!(sanitize_chat("\u{0}\r\n  "), None);
+        let long = "a".repeat(MAX_CHAT_LENGTH + 50);
+        assert_eq!(
+            sanitize_chat(&long).unwrap().chars().count(),
+            MAX_CHAT_LENGTH
+        );
+
+        let (mut app, tx, mut sim_rx) = command_app();
+        let speaker = spawn_chatter(&mut app, 0.0, 0.0, None);
+        let _ = tx.send(EcsCommand::Chat {
+            player_id: speaker,
+            request_id: None,
+            channel: ChatChannel::Global,
+            text: "\u{1b}[31mred".to_string(),
+        });
+        let _ = tx.send(EcsCommand::Chat {
+            player_id: speaker,
+            request_id: None,
+            channel: ChatChannel::Global,
+            text: "\n\n".to_string(),
+        });
+        app.update();
+        assert_eq!(
+            chat_recipients(&mut sim_rx),
+            vec![(speaker, "[31mred".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_crop_growth_requires_water() {
+        let mut app = App::new();
+        app.add_systems(Update, crop_growth_system);
+
+        let (sim_tx, mut sim_rx) = tokio::sync::mpsc::unbounded_channel();
+        app.insert_resource(ServerToClientQueue { tx: sim_tx });
+        app.insert_resource(CropCatalog::from_json(TEST_CROPS).unwrap());
+        let mut time = Time::<()>::default();
+        time.advance_by(std::time::Duration::from_secs_f32(30.0));
+        app.insert_resource(time);
+
+        let entity = app
+            .world_mut()
+            .spawn((
+                FarmPlot {
+                    x: 2,
+                    y: 3,
+                    watered: false,
+                },
+                Crop {
+                    crop_type: "wheat".to_string(),
+                    stage: 0,
+                    growth_timer: 0.0,
+                },
+            ))
+            .id();
+
+        // Dry plot does not grow
+        app.update();
+        assert_eq!(app.world().get::<Crop>(entity).unwrap().stage, 0);
+        assert!(sim_rx.try_recv().is_err());
+
+        app.world_mut().get_mut::<FarmPlot>(entity).unwrap().watered = true;
+        app.update();
+
+        assert_eq!(app.world().get::<Crop>(entity).unwrap().stage, 1);
+        assert!(!app.world().get::<FarmPlot>(entity).unwrap().watered);
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::Broadcast {
+                message: ServerMessage::CropGrew {
+                    x: 2,
+                    y: 3,
+                    stage: 1
+                }
+            }
+        ));
+    }
+
+    #[test]
+    fn test_shipped_crop_catalog_is_valid() {
+        let catalog = CropCatalog::from_json(include_str!("../content/crops.json")).unwrap();
+        assert!(catalog.get("wheat").is_some());
+    }
+
+    #[test]
+    fn test_crop_catalog_validation_errors() {
+        let crop = |id: &str, stages: &str, seasons: &str| {
+            format!(
+                r#"{{"id": "{}", "stage_seconds": {}, "needs_water": true,
+                    "seasons": {}, "yield_amount": 1}}"#,
+                id, stages, seasons
+            )
+        };
+
+        let duplicate = format!(
+            r#"{{"crops": [{}, {}]}}"#,
+            crop("wheat", "[1.0]", "[]"),
+            crop("wheat", "[2.0]", "[]")
+        );
+        assert!(matches!(
+            CropCatalog::from_json(&duplicate),
+            Err(CatalogError::DuplicateId(id)) if id == "wheat"
+        ));
+
+        let zero_stage = format!(r#"{{"crops": [{}]}}"#, crop("corn", "[10.0, 0.0]", "[]"));
+        assert!(matches!(
+            CropCatalog::from_json(&zero_stage),
+            Err(CatalogError::ZeroLengthStage { stage: 1, .. })
+        ));
+
+        let bad_season = format!(
+            r#"{{"crops": [{}]}}"#,
+            crop("corn", "[10.0]", r#"["spring", "monsoon"]"#)
+        );
+        let err = CropCatalog::from_json(&bad_season).unwrap_err();
+        assert!(matches!(&err, CatalogError::UnknownSeason { season, .. } if season == "monsoon"));
+        assert!(err.to_string().contains("monsoon"));
+    }
+
+    #[test]
+    fn test_content_manifest_sent_after_join() {
+        let (mut app, tx, mut sim_rx) = command_app();
+
+        let player_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+
+        // Only the new player hears about it, others once they're in view
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                player_id: pid,
+                message: ServerMessage::PlayerJoined { .. }
+            } if pid == player_id
+        ));
+        match sim_rx.try_recv().unwrap() {
+            ServerToClientMessage::SendToClient {
+                player_id: pid,
+                message: ServerMessage::ContentManifest { crops },
+            } => {
+                assert_eq!(pid, player_id);
+                assert_eq!(crops.len(), 2);
+            }
+            _ => panic!("Expected ContentManifest sent to the new player"),
+        }
+    }
+
+    #[test]
+    fn test_rejoining_restores_saved_player() {
+        let (mut app, tx, _sim_rx) = command_app();
+        app.insert_resource(SavedPlayers::default());
+
+        // Names that can't be saved are turned away
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id: Uuid::new_v4(),
+            request_id: None,
+            account: "../alice".to_string(),
+        });
+        app.update();
+        assert_eq!(
+            app.world_mut().query::<&Player>().iter(app.world()).count(),
+            0
+        );
+
+        let first_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id: first_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        let entity = app
+            .world_mut()
+            .query_filtered::<Entity, With<Player>>()
+            .single(app.world())
+            .unwrap();
+        app.world_mut().get_mut::<Position>(entity).unwrap().x = 200.0;
+        app.world_mut()
+            .get_mut::<Inventory>(entity)
+            .unwrap()
+            .remove("wheat_seed", 2)
+            .unwrap();
+
+        // Joining again while in the world doesn't create a second player
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id: first_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        assert_eq!(
+            app.world_mut().query::<&Player>().iter(app.world()).count(),
+            1
+        );
+
+        let _ = tx.send(EcsCommand::DespawnPlayer {
+            player_id: first_id,
+        });
+        app.update();
+        assert_eq!(
+            app.world_mut().query::<&Player>().iter(app.world()).count(),
+            0
+        );
+        let saved = &app.world().resource::<SavedPlayers>().players["alice"];
+        assert_eq!(saved.x, 200.0);
+
+        // Joining again on a new connection picks up where they left off
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id: Uuid::new_v4(),
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        let mut query = app.world_mut().query::<(&Account, &Position, &Inventory)>();
+        let (account, pos, inventory) = query.single(app.world()).unwrap();
+        assert_eq!(account.name, "alice");
+        assert_eq!(pos.x, 200.0);
+        assert_eq!(inventory.count("wheat_seed"), STARTER_SEEDS - 2);
+        assert!(app.world().resource::<SavedPlayers>().players.is_empty());
+    }
+
+    #[test]
+    fn test_disconnected_player_can_resume() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        app.add_systems(Update, expire_disconnected_players.after(process_commands));
+        app.insert_resource(Time::<()>::default());
+
+        let player_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        let _ = tx.send(EcsCommand::UpdateVelocity {
+            player_id,
+            request_id: None,
+            dx: 1.0,
+            dy: 0.0,
+        });
+        app.update();
+        let _ = tx.send(EcsCommand::DisconnectPlayer { player_id });
+        app.update();
+
+        let entity = app
+            .world_mut()
+            .query_filtered::<Entity, With<Player>>()
+            .single(app.world())
+            .unwrap();
+        assert!(app.world().get::<Disconnected>(entity).is_some());
+        assert_eq!(app.world().get::<Velocity>(entity).unwrap().dx, 0.0);
+        while sim_rx.try_recv().is_ok() {}
+
+        // Coming back re-binds the same entity and replays the world
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        assert!(app.world().get::<Disconnected>(entity).is_none());
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                player_id: pid,
+                message: ServerMessage::PlayerJoined { .. },
+            } if pid == player_id
+        ));
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                message: ServerMessage::ContentManifest { .. },
+                ..
+            }
+        ));
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::SendToClient {
+                message: ServerMessage::InventoryUpdated { .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_disconnected_player_expires() {
+        let (mut app, tx, mut sim_rx) = command_app();
+        app.add_systems(Update, expire_disconnected_players.after(process_commands));
+        app.insert_resource(SavedPlayers::default());
+        let mut time = Time::<()>::default();
+        // Every update takes a bit over a third of the grace period
+        time.advance_by(std::time::Duration::from_secs_f32(
+            DISCONNECT_GRACE_SECS / 3.0 + 0.1,
+        ));
+        app.insert_resource(time);
+
+        let player_id = Uuid::new_v4();
+        let _ = tx.send(EcsCommand::SpawnPlayer {
+            player_id,
+            request_id: None,
+            account: "alice".to_string(),
+        });
+        app.update();
+        let _ = tx.send(EcsCommand::DisconnectPlayer { player_id });
+        app.update();
+        while sim_rx.try_recv().is_ok() {}
+
+        // Still waiting two thirds into the grace period
+        app.update();
+        assert_eq!(
+            app.world_mut().query::<&Player>().iter(app.world()).count(),
+            1
+        );
+        app.update();
+        assert_eq!(
+            app.world_mut().query::<&Player>().iter(app.world()).count(),
+            0
+        );
+        assert!(matches!(
+            sim_rx.try_recv().unwrap(),
+            ServerToClientMessage::Broadcast {
+                message: ServerMessage::PlayerLeft { player_id: pid },
+            } if pid == player_id
+        ));
+        assert!(
+            app.world()
+                .resource::<SavedPlayers>()
+                .players
+                .contains_key("alice")
+        );
+    }
+}
+
+#[derive(Resource)]
+pub struct CommandQueue {
+    pub rx: UnboundedReceiver<EcsCommand>,
+}
+
+#[derive(Resource)]
+pub struct ServerToClientQueue {
+    pub tx: UnboundedSender<ServerToClientMessage>,
+}
 
 pub enum ServerToClientMessage {
     SendToClient {
@@ -262,141 +2169,1839 @@ pub enum ServerToClientMessage {
     Broadcast {
         message: ServerMessage,
     },
+    /// A message for several players that the sim already encoded, so it's
+    /// serialized once however many of them there are.
+    SendFrame {
+        player_ids: Vec<Uuid>,
+        frame: Frame,
+    },
     PlayerDisconnected {
         player_id: Uuid,
     },
+    /// Disconnects a misbehaving player, telling them why.
+    Kick {
+        player_id: Uuid,
+        reason: String,
+    },
 }
 
+/// Commands from clients carry the `request_id` the client tagged them with,
+/// so the sim can acknowledge them once they're processed.
 pub enum EcsCommand {
-    SpawnPlayer { player_id: Uuid },
-    DespawnPlayer { player_id: Uuid },
-    UpdateVelocity { player_id: Uuid, dx: f32, dy: f32 },
+    SpawnPlayer {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        account: String,
+    },
+    /// The player's connection dropped; they may still resume.
+    DisconnectPlayer {
+        player_id: Uuid,
+    },
+    DespawnPlayer {
+        player_id: Uuid,
+    },
+    UpdateVelocity {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        dx: f32,
+        dy: f32,
+    },
+    /// A sequenced movement input, buffered until `tick`.
+    Input {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        seq: u32,
+        dx: f32,
+        dy: f32,
+        tick: u64,
+    },
+    PlantCrop {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        x: i32,
+        y: i32,
+        crop_type: String,
+    },
+    WaterPlot {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        x: i32,
+        y: i32,
+    },
+    Harvest {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        x: i32,
+        y: i32,
+    },
+    MoveItem {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        from: usize,
+        to: usize,
+    },
+    SplitStack {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        slot: usize,
+        count: u32,
+        to: usize,
+    },
+    DropItem {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        slot: usize,
+        count: u32,
+    },
+    UseItem {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        slot: usize,
+        x: i32,
+        y: i32,
+    },
+    Chat {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        channel: ChatChannel,
+        text: String,
+    },
+    JoinGuild {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        guild: String,
+    },
+    LeaveGuild {
+        player_id: Uuid,
+        request_id: Option<u32>,
+    },
+    AckSnapshot {
+        player_id: Uuid,
+        request_id: Option<u32>,
+        seq: u32,
+    },
+    /// A fresh round-trip time measured by the net layer's pings.
+    UpdateLatency {
+        player_id: Uuid,
+        rtt: Duration,
+    },
+    /// Stops the simulation so the world can be saved one last time.
+    Shutdown,
+}
+
+#[derive(Component)]
+#[component(on_insert = register_player, on_replace = unregister_player)]
+pub struct Player {
+    pub id: Uuid,
+}
+
+/// Finds the entity playing as a `Player::id` without scanning every player.
+/// Kept up to date by `Player`'s component hooks, so spawning, re-binding and
+/// despawning players anywhere keeps it right.
+#[derive(Resource, Default, Debug)]
+pub struct PlayerRegistry {
+    entities: HashMap<Uuid, Entity>,
+}
+
+impl PlayerRegistry {
+    pub fn get(&self, player_id: Uuid) -> Option<Entity> {
+        self.entities.get(&player_id).copied()
+    }
+
+    pub fn contains(&self, player_id: Uuid) -> bool {
+        self.entities.contains_key(&player_id)
+    }
+
+    /// Players in the world, including ones waiting to reconnect.
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+fn register_player(mut world: DeferredWorld, context: HookContext) {
+    let Some(player_id) = world.get::<Player>(context.entity).map(|p| p.id) else {
+        return;
+    };
+    if let Some(mut registry) = world.get_resource_mut::<PlayerRegistry>() {
+        registry.entities.insert(player_id, context.entity);
+    }
+}
+
+fn unregister_player(mut world: DeferredWorld, context: HookContext) {
+    let Some(player_id) = world.get::<Player>(context.entity).map(|p| p.id) else {
+        return;
+    };
+    if let Some(mut registry) = world.get_resource_mut::<PlayerRegistry>() {
+        // Only forget the id if nothing else took it over in the meantime
+        if registry.get(player_id) == Some(context.entity) {
+            registry.entities.remove(&player_id);
+        }
+    }
+}
+
+/// The account name a player entity is playing as. Saved progress is keyed
+/// by it, and only a login with the same name can take the entity back.
+#[derive(Component)]
+pub struct Account {
+    pub name: String,
+}
+
+/// Marks a player whose connection dropped. They stay in the world, standing
+/// still, until they resume or the grace period runs out.
+#[derive(Component)]
+pub struct Disconnected {
+    pub grace_left: f32,
+}
+
+/// A player's latest measured round-trip time to the server.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct Latency {
+    pub rtt: Duration,
+}
+
+/// How often a player sent movement no honest client would.
+#[derive(Component, Debug, Default)]
+pub struct MovementViolations {
+    pub count: u32,
+}
+
+/// Membership in a guild, used to route guild chat. Players join and leave
+/// guilds themselves, and membership is saved with the rest of the player.
+#[derive(Component, Debug, Clone)]
+pub struct GuildMember {
+    pub guild: String,
+}
+
+#[derive(Component)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Component)]
+pub struct Velocity {
+    pub dx: f32,
+    pub dy: f32,
+}
+
+/// A tilled tile of soil. Plots are created the first time something is
+/// planted on a tile and stay around after the crop is harvested.
+#[derive(Component)]
+pub struct FarmPlot {
+    pub x: i32,
+    pub y: i32,
+    pub watered: bool,
+}
+
+/// A crop growing on a `FarmPlot` entity.
+#[derive(Component)]
+pub struct Crop {
+    pub crop_type: String,
+    pub stage: u8,
+    pub growth_timer: f32,
+}
+
+/// How many fixed steps the simulation has taken. Snapshots are stamped with
+/// it so clients can tell which step they show.
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimTick(pub u64);
+
+/// Most inputs a player can have waiting for their tick.
+pub const MAX_BUFFERED_INPUTS: usize = 64;
+/// How many ticks ahead of the simulation an input may be meant for. Inputs
+/// are applied in `seq` order, so one far in the future would hold up every
+/// input after it.
+pub const MAX_INPUT_LEAD_TICKS: u64 = 30;
+
+#[derive(Debug, Clone, Copy)]
+struct BufferedInput {
+    tick: u64,
+    dx: f32,
+    dy: f32,
+}
+
+/// A player's movement inputs waiting for the tick they were sent for, by
+/// `seq`.
+#[derive(Component, Debug, Default)]
+pub struct InputBuffer {
+    pending: BTreeMap<u32, BufferedInput>,
+    pub last_processed: Option<u32>,
+}
+
+impl InputBuffer {
+    pub fn push(&mut self, seq: u32, tick: u64, dx: f32, dy: f32) {
+        // Repeats of inputs that were already applied
+        if self.last_processed.is_some_and(|last| seq <= last) {
+            return;
+        }
+        if self.pending.len() >= MAX_BUFFERED_INPUTS && !self.pending.contains_key(&seq) {
+            return;
+        }
+        self.pending.insert(seq, BufferedInput { tick, dx, dy });
+    }
+
+    /// Takes the inputs due by `tick`, in `seq` order, and returns the last
+    /// one's direction. A player only has one direction per tick, so when
+    /// several are due at once (late ones bunched up with the current one)
+    /// the latest wins and the others only count as processed.
+    fn take_due(&mut self, tick: u64) -> Option<(f32, f32)> {
+        let mut latest = None;
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.get().tick > tick {
+                break;
+            }
+            self.last_processed = Some(*entry.key());
+            let input = entry.remove();
+            latest = Some((input.dx, input.dy));
+        }
+        latest
+    }
+}
+
+/// Snapshots go out every this many ticks.
+#[derive(Resource)]
+pub struct SnapshotInterval {
+    pub ticks: u64,
+}
+
+impl Default for SnapshotInterval {
+    fn default() -> Self {
+        Self { ticks: 1 }
+    }
+}
+
+/// The newest snapshot a client has acknowledged. Its next snapshot is a
+/// delta against this one.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct SnapshotAck {
+    pub seq: Option<u32>,
+}
+
+/// Where every player was in one snapshot, and their last applied input.
+type SnapshotPositions = HashMap<Uuid, (f32, f32, Option<u32>)>;
+
+/// Player positions in recently sent snapshots, to diff new ones against.
+#[derive(Resource, Default)]
+pub struct SnapshotHistory {
+    next_seq: u32,
+    recent: VecDeque<(u32, SnapshotPositions)>,
+}
+
+impl SnapshotHistory {
+    fn get(&self, seq: u32) -> Option<&SnapshotPositions> {
+        self.recent
+            .iter()
+            .find(|(s, _)| *s == seq)
+            .map(|(_, positions)| positions)
+    }
+
+    fn push(&mut self, seq: u32, positions: SnapshotPositions) {
+        if self.recent.len() == SNAPSHOT_HISTORY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((seq, positions));
+    }
+}
+
+/// Every crop type the server knows about, loaded from the content file at
+/// startup and sent to clients in `ServerMessage::ContentManifest`.
+#[derive(Resource, Default, Debug)]
+pub struct CropCatalog {
+    crops: Vec<CropDef>,
+}
+
+#[derive(Deserialize)]
+struct CropFile {
+    crops: Vec<CropDef>,
+}
+
+#[derive(Debug)]
+pub enum CatalogError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    DuplicateId(String),
+    NoStages { crop: String },
+    TooManyStages { crop: String },
+    ZeroLengthStage { crop: String, stage: usize },
+    UnknownSeason { crop: String, season: String },
+    ZeroYield { crop: String },
+}
+
+impl std::fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CatalogError::Io(e) => write!(f, "could not read crop catalog: {}", e),
+            CatalogError::Parse(e) => write!(f, "invalid crop catalog: {}", e),
+            CatalogError::DuplicateId(id) => write!(f, "crop '{}' is defined more than once", id),
+            CatalogError::NoStages { crop } => write!(f, "crop '{}' has no growth stages", crop),
+            CatalogError::TooManyStages { crop } => {
+                write!(f, "crop '{}' has more than {} growth stages", crop, u8::MAX)
+            }
+            CatalogError::ZeroLengthStage { crop, stage } => {
+                write!(
+                    f,
+                    "crop '{}' stage {} must last longer than 0 seconds",
+                    crop, stage
+                )
+            }
+            CatalogError::UnknownSeason { crop, season } => write!(
+                f,
+                "crop '{}' has unknown season '{}' (expected one of {})",
+                crop,
+                season,
+                SEASONS.join(", ")
+            ),
+            CatalogError::ZeroYield { crop } => write!(f, "crop '{}' yields nothing", crop),
+        }
+    }
+}
+
+impl std::error::Error for CatalogError {}
+
+pub const SEASONS: &[&str] = &["spring", "summer", "autumn", "winter"];
+
+impl CropCatalog {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CatalogError> {
+        let json = std::fs::read_to_string(path).map_err(CatalogError::Io)?;
+        Self::from_json(&json)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, CatalogError> {
+        let file: CropFile = serde_json::from_str(json).map_err(CatalogError::Parse)?;
+        Self::new(file.crops)
+    }
+
+    pub fn new(crops: Vec<CropDef>) -> Result<Self, CatalogError> {
+        let mut ids = HashSet::new();
+        for crop in &crops {
+            if !ids.insert(crop.id.as_str()) {
+                return Err(CatalogError::DuplicateId(crop.id.clone()));
+            }
+            if crop.stage_seconds.is_empty() {
+                return Err(CatalogError::NoStages {
+                    crop: crop.id.clone(),
+                });
+            }
+            // Stages are tracked in a u8 on the Crop component
+            if crop.stage_seconds.len() > u8::MAX as usize {
+                return Err(CatalogError::TooManyStages {
+                    crop: crop.id.clone(),
+                });
+            }
+            if let Some(stage) = crop
+                .stage_seconds
+                .iter()
+                .position(|secs| !(*secs > 0.0 && secs.is_finite()))
+            {
+                return Err(CatalogError::ZeroLengthStage {
+                    crop: crop.id.clone(),
+                    stage,
+                });
+            }
+            if let Some(season) = crop.seasons.iter().find(|s| !SEASONS.contains(&s.as_str())) {
+                return Err(CatalogError::UnknownSeason {
+                    crop: crop.id.clone(),
+                    season: season.clone(),
+                });
+            }
+            if crop.yield_amount == 0 {
+                return Err(CatalogError::ZeroYield {
+                    crop: crop.id.clone(),
+                });
+            }
+        }
+        Ok(Self { crops })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&CropDef> {
+        self.crops.iter().find(|c| c.id == id)
+    }
+
+    pub fn crops(&self) -> &[CropDef] {
+        &self.crops
+    }
+}
+
+/// Seeds are items named after their crop, e.g. `wheat_seed` plants `wheat`.
+const SEED_SUFFIX: &str = "_seed";
+/// How many seeds of every crop a new player starts with.
+const STARTER_SEEDS: u32 = 5;
+
+pub fn seed_item_id(crop_type: &str) -> String {
+    format!("{}{}", crop_type, SEED_SUFFIX)
+}
+
+/// The stage at which a crop of this type can be harvested.
+fn ripe_stage(def: &CropDef) -> u8 {
+    def.stage_seconds.len() as u8
+}
+
+/// How fast players walk unless `ServerConfig::player_speed` says otherwise,
+/// in pixels per second.
+pub const DEFAULT_PLAYER_SPEED: f32 = 300.0;
+/// Half the side of the square a player collides with, in pixels.
+pub const PLAYER_HALF_EXTENT: f32 = 4.0;
+/// Gap left between a player and a wall they were stopped against.
+const CONTACT_EPSILON: f32 = 0.001;
+/// How far (in pixels) from a tile's center a player may be to farm it.
+pub const INTERACT_RANGE: f32 = 64.0;
+
+fn within_reach(pos: &Position, x: i32, y: i32) -> bool {
+    let dx = (x as f32 + 0.5) * TILE_SIZE - pos.x;
+    let dy = (y as f32 + 0.5) * TILE_SIZE - pos.y;
+    dx * dx + dy * dy <= INTERACT_RANGE * INTERACT_RANGE
+}
+
+/// Side (in pixels) of the grid cells `SpatialIndex` buckets entities into.
+pub const SPATIAL_CELL_SIZE: f32 = 256.0;
+
+/// Every entity with a `Position`, bucketed into grid cells so proximity
+/// lookups only look at entities nearby. Rebuilt after movement each frame.
+#[derive(Resource, Default)]
+pub struct SpatialIndex {
+    cells: HashMap<IVec2, Vec<(Entity, Vec2)>>,
+}
+
+impl SpatialIndex {
+    fn cell(point: Vec2) -> IVec2 {
+        (point / SPATIAL_CELL_SIZE).floor().as_ivec2()
+    }
+
+    pub fn rebuild<'a>(&mut self, entities: impl IntoIterator<Item = (Entity, &'a Position)>) {
+        self.cells.clear();
+        for (entity, pos) in entities {
+            let point = Vec2::new(pos.x, pos.y);
+            self.cells
+                .entry(Self::cell(point))
+                .or_default()
+                .push((entity, point));
+        }
+    }
+
+    /// Entities at most `radius` away from `x`, `y`.
+    pub fn within_radius(&self, x: f32, y: f32, radius: f32) -> Vec<Entity> {
+        let center = Vec2::new(x, y);
+        self.candidates(center - radius, center + radius)
+            .filter(|(_, point)| point.distance_squared(center) <= radius * radius)
+            .map(|(entity, _)| *entity)
+            .collect()
+    }
+
+    /// Entities inside the rectangle centered on `x`, `y`, edges included.
+    pub fn within_rect(&self, x: f32, y: f32, half_width: f32, half_height: f32) -> Vec<Entity> {
+        let min = Vec2::new(x - half_width, y - half_height);
+        let max = Vec2::new(x + half_width, y + half_height);
+        self.candidates(min, max)
+            .filter(|(_, point)| point.cmpge(min).all() && point.cmple(max).all())
+            .map(|(entity, _)| *entity)
+            .collect()
+    }
+
+    /// Everything in the cells overlapping `min`..`max`.
+    fn candidates(&self, min: Vec2, max: Vec2) -> Box<dyn Iterator<Item = &(Entity, Vec2)> + '_> {
+        let (low, high) = (Self::cell(min), Self::cell(max));
+        let span = |low: i32, high: i32| (i64::from(high) - i64::from(low) + 1).max(0);
+        let cell_count = span(low.x, high.x) * span(low.y, high.y);
+        // Huge areas would visit more empty cells than there are entities
+        if cell_count > self.cells.len() as i64 {
+            return Box::new(self.cells.values().flatten());
+        }
+        Box::new(
+            (low.x..=high.x)
+                .flat_map(move |cx| (low.y..=high.y).map(move |cy| IVec2::new(cx, cy)))
+                .filter_map(|cell| self.cells.get(&cell))
+                .flatten(),
+        )
+    }
 }
 
-#[derive(Component)]
-pub struct Player {
-    pub id: Uuid,
+pub fn update_spatial_index(mut index: ResMut<SpatialIndex>, query: Query<(Entity, &Position)>) {
+    index.rebuild(query.iter());
 }
 
-#[derive(Component)]
-pub struct Position {
-    pub x: f32,
-    pub y: f32,
+/// Longest movement direction a client may send without it counting as a
+/// violation. Leaves room for unnormalized diagonals (about 1.41).
+pub const MAX_INPUT_LENGTH: f32 = 1.5;
+/// Movement violations after which a player is kicked.
+pub const MAX_MOVEMENT_VIOLATIONS: u32 = 10;
+
+/// Scales a client's movement direction down to at most unit length, so
+/// nobody moves faster than `ServerConfig::player_speed`.
+///
+/// NaN and infinite values would poison `Position` and give `None`.
+pub fn clamp_direction(dx: f32, dy: f32) -> Option<Vec2> {
+    let direction = Vec2::new(dx, dy);
+    direction
+        .is_finite()
+        .then(|| direction.clamp_length_max(1.0))
 }
 
-#[derive(Component)]
-pub struct Velocity {
-    pub dx: f32,
-    pub dy: f32,
+/// Whether a movement direction is one no honest client sends.
+fn is_movement_violation(dx: f32, dy: f32) -> bool {
+    !Vec2::new(dx, dy).is_finite() || Vec2::new(dx, dy).length() > MAX_INPUT_LENGTH
 }
 
-#[derive(Resource)]
-pub struct BroadcastTimer {
-    pub last_broadcast: f32,
+/// Counts a movement violation against a player and kicks them once they
+/// reach `MAX_MOVEMENT_VIOLATIONS`. The count starts over after a kick, so a
+/// player still around after one (e.g. the kick got lost) is kicked again.
+fn record_violation(
+    violations: &mut Query<&mut MovementViolations>,
+    entity: Entity,
+    player_id: Uuid,
+    sim_to_client: &ServerToClientQueue,
+) {
+    let Ok(mut violations) = violations.get_mut(entity) else {
+        return;
+    };
+    violations.count += 1;
+    if violations.count >= MAX_MOVEMENT_VIOLATIONS {
+        violations.count = 0;
+        let _ = sim_to_client.tx.send(ServerToClientMessage::Kick {
+            player_id,
+            reason: "invalid movement".to_string(),
+        });
+    }
 }
 
-const PLAYER_SPEED: f32 = 300.0;
+/// Applies each player's buffered inputs on the tick they were meant for.
+/// Inputs that arrive late are applied on the next tick instead.
+pub fn apply_inputs(mut query: Query<(&mut InputBuffer, &mut Velocity)>, tick: Res<SimTick>) {
+    for (mut buffer, mut velocity) in query.iter_mut() {
+        if let Some((dx, dy)) = buffer.take_due(tick.0) {
+            velocity.dx = dx;
+            velocity.dy = dy;
+        }
+    }
+}
 
-pub fn movement_system(mut query: Query<(&mut Position, &Velocity)>, time: Res<Time>) {
+pub fn movement_system(
+    mut query: Query<(&mut Position, &Velocity)>,
+    time: Res<Time>,
+    map: Option<Res<WorldMap>>,
+    config: Res<ServerConfig>,
+) {
     for (mut pos, vel) in query.iter_mut() {
-        pos.x += vel.dx * PLAYER_SPEED * time.delta_secs();
-        pos.y += vel.dy * PLAYER_SPEED * time.delta_secs();
+        let step_x = vel.dx * config.player_speed * time.delta_secs();
+        let step_y = vel.dy * config.player_speed * time.delta_secs();
+
+        let Some(map) = map.as_deref() else {
+            pos.x += step_x;
+            pos.y += step_y;
+            continue;
+        };
+        // Players inside a solid tile, e.g. saved there before a map edit,
+        // are pushed out onto the nearest open ground first
+        if !map.is_box_free(pos.x, pos.y, PLAYER_HALF_EXTENT)
+            && let Some(free) = nearest_free_spot(map, pos.x, pos.y)
+        {
+            pos.x = free.x;
+            pos.y = free.y;
+        }
+        // Resolve each axis on its own so players slide along walls
+        pos.x = sweep_axis(map, pos.x, pos.y, step_x, true);
+        pos.y = sweep_axis(map, pos.y, pos.x, step_y, false);
     }
 }
 
-pub fn process_commands(
+/// Moves `from` by `step` along one axis, stopping flush against the first
+/// solid tile. `other` is the player's coordinate on the other axis.
+fn sweep_axis(map: &WorldMap, from: f32, other: f32, step: f32, horizontal: bool) -> f32 {
+    let is_free = |v: f32| {
+        if horizontal {
+            map.is_box_free(v, other, PLAYER_HALF_EXTENT)
+        } else {
+            map.is_box_free(other, v, PLAYER_HALF_EXTENT)
+        }
+    };
+    // Advance in increments smaller than a tile so fast movers can't tunnel
+    let increments = (step.abs() / (TILE_SIZE / 4.0)).ceil().max(1.0);
+    let delta = step / increments;
+    let mut current = from;
+    for _ in 0..increments as u32 {
+        let next = current + delta;
+        if is_free(next) {
+            current = next;
+            continue;
+        }
+        let contact = if delta > 0.0 {
+            ((next + PLAYER_HALF_EXTENT) / TILE_SIZE).floor() * TILE_SIZE
+                - PLAYER_HALF_EXTENT
+                - CONTACT_EPSILON
+        } else {
+            ((next - PLAYER_HALF_EXTENT) / TILE_SIZE).floor() * TILE_SIZE
+                + TILE_SIZE
+                + PLAYER_HALF_EXTENT
+                + CONTACT_EPSILON
+        };
+        if is_free(contact) {
+            current = contact;
+        }
+        break;
+    }
+    current
+}
+
+/// How many tiles around a stuck player are searched for open ground.
+const UNSTUCK_SEARCH_TILES: i32 = 8;
+
+/// The spot closest to (`x`, `y`) where a player's box fits on one walkable
+/// tile, or `None` if there is none within `UNSTUCK_SEARCH_TILES`.
+fn nearest_free_spot(map: &WorldMap, x: f32, y: f32) -> Option<Vec2> {
+    let stuck = Vec2::new(x, y);
+    let tile = (stuck / TILE_SIZE).floor().as_ivec2();
+    let inset = PLAYER_HALF_EXTENT + CONTACT_EPSILON;
+    let range = -UNSTUCK_SEARCH_TILES..=UNSTUCK_SEARCH_TILES;
+    range
+        .clone()
+        .flat_map(|dy| range.clone().map(move |dx| tile + IVec2::new(dx, dy)))
+        .filter(|t| map.is_walkable(t.x, t.y))
+        .map(|t| {
+            let min = t.as_vec2() * TILE_SIZE + inset;
+            let max = (t + 1).as_vec2() * TILE_SIZE - inset;
+            stuck.clamp(min, max)
+        })
+        .min_by(|a, b| {
+            a.distance_squared(stuck)
+                .total_cmp(&b.distance_squared(stuck))
+        })
+}
+
+/// How long (in seconds) a dropped player waits in the world to resume.
+pub const DISCONNECT_GRACE_SECS: f32 = 30.0;
+
+/// How many sent snapshots are kept for clients to acknowledge.
+pub const SNAPSHOT_HISTORY: usize = 32;
+/// Every this many snapshots, everyone gets a full keyframe.
+pub const KEYFRAME_INTERVAL: u32 = 20;
+
+/// Despawns players whose grace period ran out, as if they had left.
+#[allow(clippy::type_complexity)]
+pub fn expire_disconnected_players(
     mut commands: Commands,
-    mut queue: ResMut<CommandQueue>,
+    mut query: Query<(
+        Entity,
+        &Player,
+        &mut Disconnected,
+        &Position,
+        Option<&Account>,
+        Option<&Inventory>,
+        Option<&GuildMember>,
+    )>,
+    time: Res<Time>,
     sim_to_client: Res<ServerToClientQueue>,
-    query: Query<(Entity, &Player, &Position)>,
+    mut saved_players: Option<ResMut<SavedPlayers>>,
+) {
+    for (entity, player, mut disconnected, pos, account, inventory, guild) in query.iter_mut() {
+        disconnected.grace_left -= time.delta_secs();
+        if disconnected.grace_left > 0.0 {
+            continue;
+        }
+
+        // Keep their progress around until the next save
+        if let (Some(saved), Some(account), Some(inventory)) =
+            (saved_players.as_mut(), account, inventory)
+        {
+            saved.players.insert(
+                account.name.clone(),
+                PlayerRecord::new(account, pos, inventory, guild),
+            );
+        }
+        commands.entity(entity).despawn();
+
+        let leave_msg = ServerMessage::PlayerLeft {
+            player_id: player.id,
+        };
+        let _ = sim_to_client
+            .tx
+            .send(ServerToClientMessage::Broadcast { message: leave_msg });
+    }
+}
+
+/// How far (in pixels) "say" chat carries from the speaker.
+pub const SAY_RADIUS: f32 = 320.0;
+/// Longest chat message, in characters, that gets delivered.
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+/// Strips control characters and surrounding whitespace from a chat message
+/// and cuts it to `MAX_CHAT_LENGTH` characters. Returns `None` if nothing is
+/// left to say.
+pub fn sanitize_chat(text: &str) -> Option<String> {
+    let cleaned: String = text.chars().filter(|c| !c.is_control()).collect();
+    let trimmed: String = cleaned.trim().chars().take(MAX_CHAT_LENGTH).collect();
+    let trimmed = trimmed.trim_end();
+    (!trimmed.is_empty()).then(|| trimmed.to_string())
+}
+
+/// Longest account name, in characters.
+pub const MAX_ACCOUNT_NAME_LENGTH: usize = 32;
+
+/// Account names key saved progress, so only plain ASCII letters, digits,
+/// `_` and `-` are allowed.
+pub fn is_valid_account_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ACCOUNT_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn send_guild(sim_to_client: &ServerToClientQueue, player_id: Uuid, member: Option<&GuildMember>) {
+    let _ = sim_to_client.tx.send(ServerToClientMessage::SendToClient {
+        player_id,
+        message: ServerMessage::GuildChanged {
+            guild: member.map(|member| member.guild.clone()),
+        },
+    });
+}
+
+fn send_inventory_update(
+    sim_to_client: &ServerToClientQueue,
+    player_id: Uuid,
+    inventory: &Inventory,
+    changed: &[usize],
+) {
+    if changed.is_empty() {
+        return;
+    }
+    let _ = sim_to_client.tx.send(ServerToClientMessage::SendToClient {
+        player_id,
+        message: ServerMessage::InventoryUpdated {
+            slots: inventory.updates(changed),
+        },
+    });
+}
+
+/// Sends a newly (re)connected client the content, their inventory and the
+/// field. Other players follow as they come into view.
+fn send_world_snapshot(
+    sim_to_client: &ServerToClientQueue,
+    player_id: Uuid,
+    catalog: &CropCatalog,
+    inventory: &Inventory,
+    plots: &Query<(Entity, &mut FarmPlot, Option<&Crop>)>,
 ) {
+    // Tell the new client which content this server runs with
+    let _ = sim_to_client.tx.send(ServerToClientMessage::SendToClient {
+        player_id,
+        message: ServerMessage::ContentManifest {
+            crops: catalog.crops().to_vec(),
+        },
+    });
+    let all_slots: Vec<usize> = (0..INVENTORY_SLOTS).collect();
+    let _ = sim_to_client.tx.send(ServerToClientMessage::SendToClient {
+        player_id,
+        message: ServerMessage::InventoryUpdated {
+            slots: inventory.updates(&all_slots),
+        },
+    });
+
+    // Send the current state of the field to the new client
+    for (_, plot, crop) in plots.iter() {
+        if let Some(crop) = crop {
+            let crop_msg = ServerMessage::CropPlanted {
+                x: plot.x,
+                y: plot.y,
+                crop_type: crop.crop_type.clone(),
+                stage: crop.stage,
+                watered: plot.watered,
+            };
+            let _ = sim_to_client.tx.send(ServerToClientMessage::SendToClient {
+                player_id,
+                message: crop_msg,
+            });
+        }
+    }
+}
+
+const NOT_IN_WORLD: &str = "join the world first";
+const OUT_OF_REACH: &str = "that tile is out of reach";
+const TILE_OCCUPIED: &str = "something is already growing there";
+const IN_WORLD: &str = "already in the world";
+const INVALID_DIRECTION: &str = "movement must be finite";
+
+fn refused(code: ErrorCode, message: impl Into<String>) -> RequestError {
+    RequestError {
+        code,
+        message: message.into(),
+    }
+}
+
+/// What earlier commands in the same `process_commands` run changed. Spawns,
+/// despawns and crop insertions/removals are deferred, so later commands
+/// can't see them in the world yet.
+#[derive(Default)]
+struct PendingChanges {
+    /// Tiles planted or harvested during this run
+    touched_plots: HashSet<(i32, i32)>,
+    /// Players who joined during this run, who aren't registered yet
+    spawned: HashSet<Uuid>,
+    /// The entities spawned for them
+    new_entities: HashMap<Uuid, Entity>,
+    /// Players who lost their connection during this run, who aren't marked
+    /// as disconnected yet
+    dropped: HashSet<Uuid>,
+    /// How many new players took a place on the server
+    joined: usize,
+}
+
+/// Everything the command handlers read and change.
+#[derive(SystemParam)]
+pub struct CommandContext<'w, 's> {
+    commands: Commands<'w, 's>,
+    sim_to_client: Res<'w, ServerToClientQueue>,
+    catalog: Res<'w, CropCatalog>,
+    config: Res<'w, ServerConfig>,
+    sim_tick: Res<'w, SimTick>,
+    map: Option<Res<'w, WorldMap>>,
+    index: Res<'w, SpatialIndex>,
+    registry: Res<'w, PlayerRegistry>,
+    saved_players: Option<ResMut<'w, SavedPlayers>>,
+    exit: EventWriter<'w, AppExit>,
+    query: Query<'w, 's, (Entity, &'static Player, &'static Position)>,
+    plots: Query<'w, 's, (Entity, &'static mut FarmPlot, Option<&'static Crop>)>,
+    inventories: Query<'w, 's, &'static mut Inventory>,
+    guild_members: Query<'w, 's, (&'static Player, &'static GuildMember)>,
+    accounts: Query<'w, 's, (&'static Account, Has<Disconnected>)>,
+    inputs: Query<'w, 's, &'static mut InputBuffer>,
+    violations: Query<'w, 's, &'static mut MovementViolations>,
+}
+
+pub fn process_commands(mut queue: ResMut<CommandQueue>, mut ctx: CommandContext) {
+    let mut pending = PendingChanges::default();
+
     while let Ok(cmd) = queue.rx.try_recv() {
         match cmd {
-            EcsCommand::SpawnPlayer { player_id } => {
-                commands.spawn((
-                    Player { id: player_id },
-                    Position { x: 365.0, y: 175.0 },
-                    Velocity { dx: 0.0, dy: 0.0 },
-                ));
+            EcsCommand::SpawnPlayer {
+                player_id,
+                request_id,
+                account,
+            } => {
+                let result = ctx.spawn_player(&mut pending, player_id, account);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::DisconnectPlayer { player_id } => {
+                ctx.disconnect_player(&mut pending, player_id);
+            }
+            EcsCommand::DespawnPlayer { player_id } => {
+                ctx.despawn_player(&pending, player_id);
+            }
+            EcsCommand::AckSnapshot {
+                player_id,
+                request_id,
+                seq,
+            } => {
+                let result = ctx.ack_snapshot(player_id, seq);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::UpdateLatency { player_id, rtt } => {
+                ctx.update_latency(player_id, rtt);
+            }
+            EcsCommand::UpdateVelocity {
+                player_id,
+                request_id,
+                dx,
+                dy,
+            } => {
+                let result = ctx.update_velocity(player_id, dx, dy);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::Input {
+                player_id,
+                request_id,
+                seq,
+                dx,
+                dy,
+                tick,
+            } => {
+                let result = ctx.buffer_input(player_id, seq, dx, dy, tick);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::PlantCrop {
+                player_id,
+                request_id,
+                x,
+                y,
+                crop_type,
+            } => {
+                let result = ctx.plant_crop(&mut pending, player_id, x, y, crop_type);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::WaterPlot {
+                player_id,
+                request_id,
+                x,
+                y,
+            } => {
+                let result = ctx.water_plot(player_id, x, y);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::Harvest {
+                player_id,
+                request_id,
+                x,
+                y,
+            } => {
+                let result = ctx.harvest(&mut pending, player_id, x, y);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::MoveItem {
+                player_id,
+                request_id,
+                from,
+                to,
+            } => {
+                let result =
+                    ctx.change_inventory(player_id, |inventory| inventory.move_stack(from, to));
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::SplitStack {
+                player_id,
+                request_id,
+                slot,
+                count,
+                to,
+            } => {
+                let result =
+                    ctx.change_inventory(player_id, |inventory| inventory.split(slot, count, to));
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::DropItem {
+                player_id,
+                request_id,
+                slot,
+                count,
+            } => {
+                let result =
+                    ctx.change_inventory(player_id, |inventory| inventory.take(slot, count));
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::UseItem {
+                player_id,
+                request_id,
+                slot,
+                x,
+                y,
+            } => {
+                let result = ctx.use_item(&mut pending, player_id, slot, x, y);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::Shutdown => {
+                ctx.exit.write(AppExit::Success);
+            }
+            EcsCommand::Chat {
+                player_id,
+                request_id,
+                channel,
+                text,
+            } => {
+                let result = ctx.chat(player_id, channel, &text);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::JoinGuild {
+                player_id,
+                request_id,
+                guild,
+            } => {
+                let result = ctx.join_guild(player_id, guild);
+                ctx.answer(player_id, request_id, result);
+            }
+            EcsCommand::LeaveGuild {
+                player_id,
+                request_id,
+            } => {
+                let result = ctx.leave_guild(player_id);
+                ctx.answer(player_id, request_id, result);
+            }
+        }
+    }
+}
+
+impl CommandContext<'_, '_> {
+    fn send_to(&self, player_id: Uuid, message: ServerMessage) {
+        let _ = self
+            .sim_to_client
+            .tx
+            .send(ServerToClientMessage::SendToClient { player_id, message });
+    }
+
+    fn broadcast(&self, message: ServerMessage) {
+        let _ = self
+            .sim_to_client
+            .tx
+            .send(ServerToClientMessage::Broadcast { message });
+    }
+
+    /// Tells a player how their request went: in its ack if they asked for
+    /// one, in an error if it was turned down and they didn't
+    fn answer(&self, player_id: Uuid, request_id: Option<u32>, result: Result<(), RequestError>) {
+        match (request_id, result) {
+            (Some(request_id), result) => {
+                self.send_to(player_id, ServerMessage::Ack { request_id, result })
+            }
+            (None, Err(RequestError { code, message })) => self.send_to(
+                player_id,
+                ServerMessage::Error {
+                    code,
+                    message,
+                    request_id: None,
+                },
+            ),
+            (None, Ok(())) => {}
+        }
+    }
+
+    fn entity(&self, player_id: Uuid) -> Result<Entity, RequestError> {
+        self.registry
+            .get(player_id)
+            .ok_or_else(|| refused(ErrorCode::NotInWorld, NOT_IN_WORLD))
+    }
+
+    fn position(&self, player_id: Uuid) -> Result<&Position, RequestError> {
+        self.registry
+            .get(player_id)
+            .and_then(|entity| self.query.get(entity).ok())
+            .map(|(_, _, pos)| pos)
+            .ok_or_else(|| refused(ErrorCode::NotInWorld, NOT_IN_WORLD))
+    }
 
-                // Notify clients about new player
-                let join_msg = ServerMessage::PlayerJoined {
+    fn spawn_player(
+        &mut self,
+        pending: &mut PendingChanges,
+        player_id: Uuid,
+        account: String,
+    ) -> Result<(), RequestError> {
+        if !is_valid_account_name(&account) {
+            return Err(refused(ErrorCode::InvalidUsername, "invalid account name"));
+        }
+        // A player id never gets a second entity, not even when two joins
+        // arrive before the first spawn is applied
+        if !pending.spawned.insert(player_id) {
+            return Err(refused(ErrorCode::AlreadyInWorld, IN_WORLD));
+        }
+        // Players still in the world after losing their connection (e.g.
+        // reconnecting within the grace period) are re-bound instead of
+        // spawned twice
+        if let Some(entity) = self.registry.get(player_id) {
+            // Only the account playing this id can take it back, and only
+            // once its old connection is gone
+            let (Ok((_, _, pos)), Ok(inventory), true) = (
+                self.query.get(entity),
+                self.inventories.get(entity),
+                self.accounts.get(entity).is_ok_and(|(a, disconnected)| {
+                    a.name == account && (disconnected || pending.dropped.contains(&player_id))
+                }),
+            ) else {
+                return Err(refused(ErrorCode::AlreadyInWorld, IN_WORLD));
+            };
+            // The new connection starts over with a keyframe
+            self.commands
+                .entity(entity)
+                .remove::<Disconnected>()
+                .insert((
+                    SnapshotAck::default(),
+                    Interest::default(),
+                    InputBuffer::default(),
+                ));
+            self.send_to(
+                player_id,
+                ServerMessage::PlayerJoined {
                     player_id,
-                    x: 365.0,
-                    y: 175.0,
-                };
-                let _ = sim_to_client
-                    .tx
-                    .send(ServerToClientMessage::Broadcast { message: join_msg });
-
-                // Send PlayerJoined for each existing player to the new client
-                for (_, existing_player, pos) in query.iter() {
-                    if existing_player.id != player_id {
-                        let existing_join_msg = ServerMessage::PlayerJoined {
-                            player_id: existing_player.id,
-                            x: pos.x,
-                            y: pos.y,
-                        };
-                        let _ = sim_to_client.tx.send(ServerToClientMessage::SendToClient {
-                            player_id,
-                            message: existing_join_msg,
-                        });
-                    }
-                }
+                    x: pos.x,
+                    y: pos.y,
+                },
+            );
+            send_world_snapshot(
+                &self.sim_to_client,
+                player_id,
+                &self.catalog,
+                inventory,
+                &self.plots,
+            );
+            if let Ok((_, member)) = self.guild_members.get(entity) {
+                send_guild(&self.sim_to_client, player_id, Some(member));
             }
-            EcsCommand::DespawnPlayer { player_id } => {
-                // Find and despawn entity by player_id
-                let mut to_despawn = Vec::new();
-                for (entity, player, _) in query.iter() {
-                    if player.id == player_id {
-                        to_despawn.push(entity);
-                    }
-                }
-                for entity in to_despawn {
-                    commands.entity(entity).despawn();
+            return Ok(());
+        }
+
+        // Disconnected players count too, their place is kept
+        if self.registry.len() + pending.joined >= self.config.max_players {
+            return Err(refused(ErrorCode::ServerFull, "the server is full"));
+        }
+        pending.joined += 1;
+
+        // Returning players continue from their last save
+        let saved = self
+            .saved_players
+            .as_mut()
+            .and_then(|saved| saved.players.remove(&account));
+        let (x, y, inventory, guild) = match saved {
+            Some(record) => (
+                record.x,
+                record.y,
+                Inventory {
+                    slots: record.inventory,
+                },
+                record.guild.map(|guild| GuildMember { guild }),
+            ),
+            None => {
+                let mut inventory = Inventory::default();
+                for crop in self.catalog.crops() {
+                    let _ = inventory.add(&seed_item_id(&crop.id), STARTER_SEEDS);
                 }
+                let spawn = self.config.spawn_point;
+                (spawn.x, spawn.y, inventory, None)
+            }
+        };
 
-                // Notify clients about player leaving
-                let leave_msg = ServerMessage::PlayerLeft { player_id };
-                let _ = sim_to_client
-                    .tx
-                    .send(ServerToClientMessage::Broadcast { message: leave_msg });
-            }
-            EcsCommand::UpdateVelocity { player_id, dx, dy } => {
-                // Find entity by player_id and update velocity
-                for (entity, player, _) in query.iter() {
-                    if player.id == player_id {
-                        commands.entity(entity).insert(Velocity { dx, dy });
-                        break;
-                    }
+        // Players nearby find out through `EntityEntered`
+        self.send_to(player_id, ServerMessage::PlayerJoined { player_id, x, y });
+        send_world_snapshot(
+            &self.sim_to_client,
+            player_id,
+            &self.catalog,
+            &inventory,
+            &self.plots,
+        );
+        if let Some(member) = &guild {
+            send_guild(&self.sim_to_client, player_id, Some(member));
+        }
+
+        let mut entity = self.commands.spawn((
+            Player { id: player_id },
+            Account { name: account },
+            Position { x, y },
+            Velocity { dx: 0.0, dy: 0.0 },
+            inventory,
+            SnapshotAck::default(),
+            Interest::default(),
+            InputBuffer::default(),
+            MovementViolations::default(),
+        ));
+        if let Some(member) = guild {
+            entity.insert(member);
+        }
+        pending.new_entities.insert(player_id, entity.id());
+        Ok(())
+    }
+
+    fn disconnect_player(&mut self, pending: &mut PendingChanges, player_id: Uuid) {
+        // Keep them in the world for a while in case they come back. They
+        // may have joined earlier this run.
+        let entity = self
+            .registry
+            .get(player_id)
+            .or_else(|| pending.new_entities.get(&player_id).copied());
+        if let Some(entity) = entity {
+            pending.dropped.insert(player_id);
+            self.commands.entity(entity).insert((
+                Disconnected {
+                    grace_left: DISCONNECT_GRACE_SECS,
+                },
+                Velocity { dx: 0.0, dy: 0.0 },
+            ));
+        }
+    }
+
+    fn despawn_player(&mut self, pending: &PendingChanges, player_id: Uuid) {
+        let entity = self
+            .registry
+            .get(player_id)
+            .or_else(|| pending.new_entities.get(&player_id).copied());
+        if let Some(entity) = entity {
+            // Keep their progress around until the next save
+            if let (Some(saved), Ok((account, _)), Ok((_, _, pos)), Ok(inventory)) = (
+                self.saved_players.as_mut(),
+                self.accounts.get(entity),
+                self.query.get(entity),
+                self.inventories.get(entity),
+            ) {
+                let guild = self
+                    .guild_members
+                    .get(entity)
+                    .ok()
+                    .map(|(_, member)| member);
+                saved.players.insert(
+                    account.name.clone(),
+                    PlayerRecord::new(account, pos, inventory, guild),
+                );
+            }
+            self.commands.entity(entity).despawn();
+        }
+
+        // Notify clients about player leaving
+        self.broadcast(ServerMessage::PlayerLeft { player_id });
+    }
+
+    fn ack_snapshot(&mut self, player_id: Uuid, seq: u32) -> Result<(), RequestError> {
+        let entity = self.entity(player_id)?;
+        self.commands
+            .entity(entity)
+            .insert(SnapshotAck { seq: Some(seq) });
+        Ok(())
+    }
+
+    fn update_latency(&mut self, player_id: Uuid, rtt: Duration) {
+        if let Some(entity) = self.registry.get(player_id) {
+            self.commands.entity(entity).insert(Latency { rtt });
+        }
+        let rtt_ms = u32::try_from(rtt.as_millis()).unwrap_or(u32::MAX);
+        self.send_to(player_id, ServerMessage::Latency { rtt_ms });
+    }
+
+    /// Checks a movement direction from a client, counting the ones no
+    /// honest client sends against them.
+    fn movement_direction(
+        &mut self,
+        entity: Entity,
+        player_id: Uuid,
+        dx: f32,
+        dy: f32,
+    ) -> Result<Vec2, RequestError> {
+        if is_movement_violation(dx, dy) {
+            record_violation(&mut self.violations, entity, player_id, &self.sim_to_client);
+        }
+        clamp_direction(dx, dy)
+            .ok_or_else(|| refused(ErrorCode::InvalidMovement, INVALID_DIRECTION))
+    }
+
+    fn update_velocity(&mut self, player_id: Uuid, dx: f32, dy: f32) -> Result<(), RequestError> {
+        let entity = self.entity(player_id)?;
+        let direction = self.movement_direction(entity, player_id, dx, dy)?;
+        self.commands.entity(entity).insert(Velocity {
+            dx: direction.x,
+            dy: direction.y,
+        });
+        Ok(())
+    }
+
+    fn buffer_input(
+        &mut self,
+        player_id: Uuid,
+        seq: u32,
+        dx: f32,
+        dy: f32,
+        tick: u64,
+    ) -> Result<(), RequestError> {
+        let entity = self.entity(player_id)?;
+        let direction = self.movement_direction(entity, player_id, dx, dy)?;
+        if tick > self.sim_tick.0 + MAX_INPUT_LEAD_TICKS {
+            let message = "input is for a tick too far ahead";
+            return Err(refused(ErrorCode::InvalidMovement, message));
+        }
+        if let Ok(mut buffer) = self.inputs.get_mut(entity) {
+            buffer.push(seq, tick, direction.x, direction.y);
+        }
+        Ok(())
+    }
+
+    fn plant_crop(
+        &mut self,
+        pending: &mut PendingChanges,
+        player_id: Uuid,
+        x: i32,
+        y: i32,
+        crop_type: String,
+    ) -> Result<(), RequestError> {
+        if self.catalog.get(&crop_type).is_none() {
+            let message = format!("there is no crop called {:?}", crop_type);
+            return Err(refused(ErrorCode::UnknownCrop, message));
+        }
+        // Nothing grows inside walls or water
+        if self.map.as_ref().is_some_and(|map| !map.is_walkable(x, y)) {
+            return Err(refused(ErrorCode::NotFarmable, "nothing grows there"));
+        }
+        if !within_reach(self.position(player_id)?, x, y) {
+            return Err(refused(ErrorCode::OutOfReach, OUT_OF_REACH));
+        }
+        if pending.touched_plots.contains(&(x, y)) {
+            return Err(refused(ErrorCode::TileOccupied, TILE_OCCUPIED));
+        }
+        // Planting costs one seed
+        let seed = seed_item_id(&crop_type);
+        let Some(mut inventory) = self
+            .registry
+            .get(player_id)
+            .and_then(|entity| self.inventories.get_mut(entity).ok())
+        else {
+            return Err(refused(ErrorCode::NotInWorld, NOT_IN_WORLD));
+        };
+        if inventory.count(&seed) == 0 {
+            let message = format!("no {} seeds left", crop_type);
+            return Err(refused(ErrorCode::MissingItem, message));
+        }
+
+        let crop = Crop {
+            crop_type: crop_type.clone(),
+            stage: 0,
+            growth_timer: 0.0,
+        };
+        let watered = match self
+            .plots
+            .iter()
+            .find(|(_, plot, _)| plot.x == x && plot.y == y)
+        {
+            Some((_, _, Some(_))) => {
+                return Err(refused(ErrorCode::TileOccupied, TILE_OCCUPIED));
+            }
+            Some((entity, plot, None)) => {
+                self.commands.entity(entity).insert(crop);
+                plot.watered
+            }
+            None => {
+                self.commands.spawn((
+                    FarmPlot {
+                        x,
+                        y,
+                        watered: false,
+                    },
+                    crop,
+                ));
+                false
+            }
+        };
+        pending.touched_plots.insert((x, y));
+        if let Ok(changed) = inventory.remove(&seed, 1) {
+            send_inventory_update(&self.sim_to_client, player_id, &inventory, &changed);
+        }
+
+        self.broadcast(ServerMessage::CropPlanted {
+            x,
+            y,
+            crop_type,
+            stage: 0,
+            watered,
+        });
+        Ok(())
+    }
+
+    fn water_plot(&mut self, player_id: Uuid, x: i32, y: i32) -> Result<(), RequestError> {
+        if !within_reach(self.position(player_id)?, x, y) {
+            return Err(refused(ErrorCode::OutOfReach, OUT_OF_REACH));
+        }
+        let Some((_, mut plot, _)) = self
+            .plots
+            .iter_mut()
+            .find(|(_, plot, _)| plot.x == x && plot.y == y)
+        else {
+            return Err(refused(ErrorCode::NoPlot, "nothing is planted there"));
+        };
+        if plot.watered {
+            let message = "that plot is already watered";
+            return Err(refused(ErrorCode::AlreadyWatered, message));
+        }
+        plot.watered = true;
+
+        self.broadcast(ServerMessage::PlotWatered { x, y });
+        Ok(())
+    }
+
+    fn harvest(
+        &mut self,
+        pending: &mut PendingChanges,
+        player_id: Uuid,
+        x: i32,
+        y: i32,
+    ) -> Result<(), RequestError> {
+        if !within_reach(self.position(player_id)?, x, y) {
+            return Err(refused(ErrorCode::OutOfReach, OUT_OF_REACH));
+        }
+        let crop = self
+            .plots
+            .iter()
+            .find(|(_, plot, _)| plot.x == x && plot.y == y)
+            .and_then(|(entity, _, crop)| Some((entity, crop?)))
+            .filter(|_| !pending.touched_plots.contains(&(x, y)));
+        let Some((entity, crop)) = crop else {
+            let message = "nothing to harvest there";
+            return Err(refused(ErrorCode::NothingToHarvest, message));
+        };
+        let Some(def) = self
+            .catalog
+            .get(&crop.crop_type)
+            .filter(|def| crop.stage >= ripe_stage(def))
+        else {
+            return Err(refused(ErrorCode::NotRipe, "not ripe yet"));
+        };
+        // The harvest has to fit in the player's inventory
+        let Some(mut inventory) = self
+            .registry
+            .get(player_id)
+            .and_then(|entity| self.inventories.get_mut(entity).ok())
+        else {
+            return Err(refused(ErrorCode::NotInWorld, NOT_IN_WORLD));
+        };
+        let changed = inventory.add(&crop.crop_type, def.yield_amount)?;
+        send_inventory_update(&self.sim_to_client, player_id, &inventory, &changed);
+        self.commands.entity(entity).remove::<Crop>();
+        pending.touched_plots.insert((x, y));
+
+        self.broadcast(ServerMessage::CropHarvested {
+            x,
+            y,
+            crop_type: crop.crop_type.clone(),
+            player_id,
+        });
+        Ok(())
+    }
+
+    /// Applies a change a player makes to their own inventory and tells them
+    /// which slots it touched.
+    fn change_inventory(
+        &mut self,
+        player_id: Uuid,
+        change: impl FnOnce(&mut Inventory) -> Result<Vec<usize>, InventoryError>,
+    ) -> Result<(), RequestError> {
+        let Some(mut inventory) = self
+            .registry
+            .get(player_id)
+            .and_then(|entity| self.inventories.get_mut(entity).ok())
+        else {
+            return Err(refused(ErrorCode::NotInWorld, NOT_IN_WORLD));
+        };
+        let changed = change(&mut inventory)?;
+        send_inventory_update(&self.sim_to_client, player_id, &inventory, &changed);
+        Ok(())
+    }
+
+    /// Using a seed plants it on the target tile.
+    fn use_item(
+        &mut self,
+        pending: &mut PendingChanges,
+        player_id: Uuid,
+        slot: usize,
+        x: i32,
+        y: i32,
+    ) -> Result<(), RequestError> {
+        let inventory = self
+            .registry
+            .get(player_id)
+            .and_then(|entity| self.inventories.get(entity).ok())
+            .ok_or_else(|| refused(ErrorCode::NotInWorld, NOT_IN_WORLD))?;
+        let Some(stack) = inventory.slot(slot) else {
+            return Err(refused(ErrorCode::InvalidSlot, "that slot is empty"));
+        };
+        let Some(crop_type) = stack.item_id.strip_suffix(SEED_SUFFIX) else {
+            return Err(refused(ErrorCode::NotUsable, "only seeds can be used"));
+        };
+        let crop_type = crop_type.to_string();
+        self.plant_crop(pending, player_id, x, y, crop_type)
+    }
+
+    fn chat(
+        &mut self,
+        player_id: Uuid,
+        channel: ChatChannel,
+        text: &str,
+    ) -> Result<(), RequestError> {
+        let Some(text) = sanitize_chat(text) else {
+            return Err(refused(ErrorCode::EmptyMessage, "nothing to say"));
+        };
+        let speaker_pos = self.position(player_id)?;
+
+        let recipients: Vec<Uuid> = match &channel {
+            ChatChannel::Say => self
+                .index
+                .within_radius(speaker_pos.x, speaker_pos.y, SAY_RADIUS)
+                .into_iter()
+                .filter_map(|entity| self.query.get(entity).ok())
+                .map(|(_, p, _)| p.id)
+                .collect(),
+            ChatChannel::Global => self.query.iter().map(|(_, p, _)| p.id).collect(),
+            ChatChannel::Whisper { to } => {
+                if !self.registry.contains(*to) {
+                    let message = "that player isn't online";
+                    return Err(refused(ErrorCode::PlayerNotFound, message));
+                }
+                if *to == player_id {
+                    vec![player_id]
+                } else {
+                    vec![*to, player_id]
                 }
             }
+            ChatChannel::Guild => {
+                let Some((_, member)) = self
+                    .registry
+                    .get(player_id)
+                    .and_then(|entity| self.guild_members.get(entity).ok())
+                else {
+                    return Err(refused(ErrorCode::NotInGuild, "you aren't in a guild"));
+                };
+                self.guild_members
+                    .iter()
+                    .filter(|(_, other)| other.guild == member.guild)
+                    .map(|(p, _)| p.id)
+                    .collect()
+            }
+        };
+
+        for recipient in recipients {
+            self.send_to(
+                recipient,
+                ServerMessage::ChatMessage {
+                    channel: channel.clone(),
+                    player_id,
+                    text: text.clone(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn join_guild(&mut self, player_id: Uuid, guild: String) -> Result<(), RequestError> {
+        // Guild names follow the same rules as account names
+        if !is_valid_account_name(&guild) {
+            return Err(refused(ErrorCode::InvalidGuildName, "invalid guild name"));
         }
+        let entity = self.entity(player_id)?;
+        let member = GuildMember { guild };
+        send_guild(&self.sim_to_client, player_id, Some(&member));
+        self.commands.entity(entity).insert(member);
+        Ok(())
+    }
+
+    fn leave_guild(&mut self, player_id: Uuid) -> Result<(), RequestError> {
+        let entity = self.entity(player_id)?;
+        if self.guild_members.get(entity).is_err() {
+            return Err(refused(ErrorCode::NotInGuild, "you aren't in a guild"));
+        }
+        send_guild(&self.sim_to_client, player_id, None);
+        self.commands.entity(entity).remove::<GuildMember>();
+        Ok(())
     }
 }
 
-pub fn broadcast_positions(
-    query: Query<(&Player, &Position)>,
+/// Advances crops through their growth stages. Crops that need water only
+/// grow while their plot is watered, and each stage needs watering again.
+pub fn crop_growth_system(
+    mut query: Query<(&mut FarmPlot, &mut Crop)>,
     sim_to_client: Res<ServerToClientQueue>,
+    catalog: Res<CropCatalog>,
     time: Res<Time>,
-    mut timer: ResMut<BroadcastTimer>,
 ) {
-    if time.elapsed_secs() - timer.last_broadcast < 0.05 {
-        return; // Skip if not enough time has passed
+    for (mut plot, mut crop) in query.iter_mut() {
+        let Some(def) = catalog.get(&crop.crop_type) else {
+            continue;
+        };
+        if crop.stage >= ripe_stage(def) || (def.needs_water && !plot.watered) {
+            continue;
+        }
+
+        crop.growth_timer += time.delta_secs();
+        if crop.growth_timer < def.stage_seconds[crop.stage as usize] {
+            continue;
+        }
+        crop.stage += 1;
+        crop.growth_timer = 0.0;
+        plot.watered = false;
+
+        let grew_msg = ServerMessage::CropGrew {
+            x: plot.x,
+            y: plot.y,
+            stage: crop.stage,
+        };
+        let _ = sim_to_client
+            .tx
+            .send(ServerToClientMessage::Broadcast { message: grew_msg });
+    }
+}
+
+/// How far (in pixels) players can see from where they stand, left/right
+/// and up/down. A bit more than a landscape phone screen.
+pub const VIEW_HALF_WIDTH: f32 = 640.0;
+pub const VIEW_HALF_HEIGHT: f32 = 400.0;
+
+/// Which players a client can currently see, each with the snapshot it came
+/// into view in.
+#[derive(Component, Debug, Default)]
+pub struct Interest {
+    pub visible: HashMap<Uuid, u32>,
+}
+
+/// The visible players that changed since the `baseline` snapshot, or all of
+/// them for a keyframe. Players that came into view after the baseline count
+/// as changed, since the client's copy of it doesn't have them. Sorted, so
+/// clients that see the same players get the same snapshot.
+fn snapshot_players(
+    baseline: Option<(u32, &SnapshotPositions)>,
+    current: &SnapshotPositions,
+    interest: &Interest,
+) -> Vec<Uuid> {
+    let mut players: Vec<Uuid> = interest
+        .visible
+        .iter()
+        .filter(|(id, entered)| match baseline {
+            Some((base_seq, base)) => **entered > base_seq || base.get(*id) != current.get(*id),
+            None => true,
+        })
+        .map(|(id, _)| *id)
+        .filter(|id| current.contains_key(id))
+        .collect();
+    players.sort_unstable();
+    players
+}
+
+fn snapshot_message(
+    seq: u32,
+    tick: u64,
+    baseline: Option<u32>,
+    players: &[Uuid],
+    current: &SnapshotPositions,
+) -> ServerMessage {
+    let players = players
+        .iter()
+        .map(|id| {
+            let (x, y, last_processed_input) = current[id];
+            PlayerState {
+                player_id: *id,
+                x,
+                y,
+                last_processed_input,
+            }
+        })
+        .collect();
+    ServerMessage::Snapshot {
+        seq,
+        tick,
+        baseline,
+        players,
+    }
+}
+
+/// Sends every connected player the players around them: `EntityEntered` and
+/// `EntityLeft` as their view changes, then a snapshot of positions in view.
+/// Players in the same crowd usually get the same snapshot, which is encoded
+/// once and shared between them.
+pub fn broadcast_positions(
+    query: Query<(&Player, &Position, Option<&InputBuffer>)>,
+    mut recipients: Query<
+        (&Player, &Position, &mut Interest, Option<&SnapshotAck>),
+        Without<Disconnected>,
+    >,
+    sim_to_client: Res<ServerToClientQueue>,
+    tick: Res<SimTick>,
+    interval: Res<SnapshotInterval>,
+    mut history: ResMut<SnapshotHistory>,
+    index: Res<SpatialIndex>,
+) {
+    if !tick.0.is_multiple_of(interval.ticks.max(1)) {
+        return;
     }
-    timer.last_broadcast = time.elapsed_secs();
 
-    let players: Vec<PlayerState> = query
+    let seq = history.next_seq;
+    history.next_seq = seq.wrapping_add(1);
+    let current: SnapshotPositions = query
         .iter()
-        .map(|(p, pos)| PlayerState {
-            player_id: p.id,
-            x: pos.x,
-            y: pos.y,
+        .map(|(p, pos, inputs)| {
+            let last_processed = inputs.and_then(|inputs| inputs.last_processed);
+            (p.id, (pos.x, pos.y, last_processed))
         })
         .collect();
+    let keyframe = seq.is_multiple_of(KEYFRAME_INTERVAL);
+    // Who gets which snapshot, by baseline and the players it lists
+    let mut snapshots: HashMap<(Option<u32>, Vec<Uuid>), Vec<Uuid>> = HashMap::new();
+
+    for (player, pos, mut interest, ack) in recipients.iter_mut() {
+        let send = |message| {
+            let _ = sim_to_client.tx.send(ServerToClientMessage::SendToClient {
+                player_id: player.id,
+                message,
+            });
+        };
+
+        let in_view: HashSet<Uuid> = index
+            .within_rect(pos.x, pos.y, VIEW_HALF_WIDTH, VIEW_HALF_HEIGHT)
+            .into_iter()
+            .filter_map(|entity| query.get(entity).ok())
+            .map(|(other, _, _)| other.id)
+            .collect();
+        interest.visible.retain(|id, _| {
+            let still_visible = in_view.contains(id);
+            if !still_visible {
+                send(ServerMessage::EntityLeft { entity_id: *id });
+            }
+            still_visible
+        });
+        for id in in_view {
+            if interest.visible.contains_key(&id) {
+                continue;
+            }
+            let (x, y, _) = current[&id];
+            send(ServerMessage::EntityEntered {
+                entity_id: id,
+                x,
+                y,
+            });
+            interest.visible.insert(id, seq);
+        }
+
+        let baseline = ack
+            .and_then(|ack| ack.seq)
+            .filter(|_| !keyframe)
+            .and_then(|acked| Some((acked, history.get(acked)?)));
+        let players = snapshot_players(baseline, &current, &interest);
+        snapshots
+            .entry((baseline.map(|(seq, _)| seq), players))
+            .or_default()
+            .push(player.id);
+    }
+
+    // Sent after every view change, so each client still gets its
+    // `EntityEntered` before the snapshot listing that player
+    for ((baseline, players), recipients) in snapshots {
+        let message = snapshot_message(seq, tick.0, baseline, &players, &current);
+        let msg = match <[Uuid; 1]>::try_from(recipients) {
+            Ok([player_id]) => ServerToClientMessage::SendToClient { player_id, message },
+            Err(player_ids) => ServerToClientMessage::SendFrame {
+                player_ids,
+                frame: Frame::encode(&message),
+            },
+        };
+        let _ = sim_to_client.tx.send(msg);
+    }
+    history.push(seq, current);
+}
+
+/// Simulation steps per second unless configured otherwise.
+pub const DEFAULT_TICK_RATE: f64 = 20.0;
+
+pub fn advance_tick(mut tick: ResMut<SimTick>) {
+    tick.0 += 1;
+}
+
+/// The simulation, stepped `tick_rate` times per second in `FixedUpdate` so
+/// every step covers the same time no matter how fast the app loop spins.
+/// The queues, crop catalog and world map still have to be inserted, and
+/// `ServerConfig` too unless the defaults will do.
+pub struct SimulationPlugin {
+    pub tick_rate: f64,
+}
 
-    let msg = ServerMessage::PlayerState { players };
-    let _ = sim_to_client
-        .tx
-        .send(ServerToClientMessage::Broadcast { message: msg });
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Time::<Fixed>::from_hz(self.tick_rate))
+            .init_resource::<SimTick>()
+            .init_resource::<SnapshotInterval>()
+            .init_resource::<SnapshotHistory>()
+            .init_resource::<SpatialIndex>()
+            .init_resource::<PlayerRegistry>()
+            .init_resource::<ServerConfig>()
+            .add_systems(
+                FixedUpdate,
+                (
+                    advance_tick,
+                    process_commands.after(advance_tick),
+                    expire_disconnected_players.after(process_commands),
+                    apply_inputs.after(process_commands),
+                    movement_system.after(apply_inputs),
+                    // Also serves next tick's commands, nothing moves in between
+                    update_spatial_index.after(movement_system),
+                    crop_growth_system.after(advance_tick),
+                    broadcast_positions.after(update_spatial_index),
+                ),
+            );
+    }
 }
@@ -0,0 +1,212 @@
+use bevy::prelude::*;
+use std::path::Path;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_map_with_origin() {
+        let map = WorldMap::parse("; comment\norigin -1 2\n.#\n~+\n").unwrap();
+        assert_eq!(map.tile(-1, 2), Some(TileKind::Ground));
+        assert_eq!(map.tile(0, 2), Some(TileKind::Wall));
+        assert_eq!(map.tile(-1, 3), Some(TileKind::Water));
+        assert_eq!(map.tile(0, 3), Some(TileKind::Fence));
+        assert_eq!(map.tile(1, 2), None);
+        assert!(map.is_walkable(-1, 2));
+        assert!(!map.is_walkable(0, 3));
+        // Outside the map counts as solid
+        assert!(!map.is_walkable(-2, 2));
+    }
+
+    #[test]
+    fn test_parse_map_errors() {
+        assert!(matches!(
+            WorldMap::parse("..\n.x\n"),
+            Err(MapError::UnknownTile {
+                line: 2,
+                column: 2,
+                tile: 'x'
+            })
+        ));
+        assert!(matches!(
+            WorldMap::parse("...\n..\n"),
+            Err(MapError::RaggedRow { line: 2 })
+        ));
+        assert!(matches!(
+            WorldMap::parse("; nothing\n"),
+            Err(MapError::Empty)
+        ));
+    }
+
+    #[test]
+    fn test_shipped_world_map_is_valid() {
+        let map = WorldMap::parse(include_str!("../content/world.map")).unwrap();
+        // The spawn point must be on open ground
+        assert!(map.is_walkable(365 / TILE_SIZE as i32, 175 / TILE_SIZE as i32));
+    }
+}
+
+/// Size of one map tile in pixels, matching the Godot tilesets.
+pub const TILE_SIZE: f32 = 32.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileKind {
+    Ground,
+    Wall,
+    Water,
+    Fence,
+}
+
+impl TileKind {
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            '.' => Some(TileKind::Ground),
+            '#' => Some(TileKind::Wall),
+            '~' => Some(TileKind::Water),
+            '+' => Some(TileKind::Fence),
+            _ => None,
+        }
+    }
+
+    pub fn is_walkable(self) -> bool {
+        matches!(self, TileKind::Ground)
+    }
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(std::io::Error),
+    BadOrigin {
+        line: usize,
+    },
+    UnknownTile {
+        line: usize,
+        column: usize,
+        tile: char,
+    },
+    RaggedRow {
+        line: usize,
+    },
+    Empty,
+}
+
+impl std::fmt::Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapError::Io(e) => write!(f, "could not read world map: {}", e),
+            MapError::BadOrigin { line } => {
+                write!(f, "line {}: expected `origin <x> <y>`", line)
+            }
+            MapError::UnknownTile { line, column, tile } => {
+                write!(
+                    f,
+                    "line {}, column {}: unknown tile '{}'",
+                    line, column, tile
+                )
+            }
+            MapError::RaggedRow { line } => {
+                write!(f, "line {}: row width differs from the first row", line)
+            }
+            MapError::Empty => write!(f, "world map has no tiles"),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+/// The authoritative tile grid players move around on.
+///
+/// Loaded from a plain text grid: one character per tile, `;` starts a comment
+/// line and an optional `origin <x> <y>` line sets the tile coordinates of the
+/// top-left character. Tiles outside the grid are treated as solid.
+#[derive(Resource, Debug)]
+pub struct WorldMap {
+    origin_x: i32,
+    origin_y: i32,
+    width: i32,
+    height: i32,
+    tiles: Vec<TileKind>,
+}
+
+impl WorldMap {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MapError> {
+        let text = std::fs::read_to_string(path).map_err(MapError::Io)?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, MapError> {
+        let (mut origin_x, mut origin_y) = (0, 0);
+        let mut width = None;
+        let mut tiles = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line_no = index + 1;
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix("origin") {
+                let coords: Vec<i32> = rest
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| MapError::BadOrigin { line: line_no })?;
+                let [x, y] = coords[..] else {
+                    return Err(MapError::BadOrigin { line: line_no });
+                };
+                (origin_x, origin_y) = (x, y);
+                continue;
+            }
+
+            let row_start = tiles.len();
+            for (column, c) in line.chars().enumerate() {
+                let kind = TileKind::from_char(c).ok_or(MapError::UnknownTile {
+                    line: line_no,
+                    column: column + 1,
+                    tile: c,
+                })?;
+                tiles.push(kind);
+            }
+            let row_width = tiles.len() - row_start;
+            if *width.get_or_insert(row_width) != row_width {
+                return Err(MapError::RaggedRow { line: line_no });
+            }
+        }
+
+        let width = width.unwrap_or(0) as i32;
+        if width == 0 {
+            return Err(MapError::Empty);
+        }
+        Ok(Self {
+            origin_x,
+            origin_y,
+            width,
+            height: tiles.len() as i32 / width,
+            tiles,
+        })
+    }
+
+    /// The tile at the given tile coordinates, or `None` outside the map.
+    pub fn tile(&self, x: i32, y: i32) -> Option<TileKind> {
+        let (col, row) = (x - self.origin_x, y - self.origin_y);
+        if col < 0 || row < 0 || col >= self.width || row >= self.height {
+            return None;
+        }
+        Some(self.tiles[(row * self.width + col) as usize])
+    }
+
+    pub fn is_walkable(&self, x: i32, y: i32) -> bool {
+        self.tile(x, y).is_some_and(TileKind::is_walkable)
+    }
+
+    /// Whether an axis-aligned box centered on (`x`, `y`) in pixels only
+    /// overlaps walkable tiles.
+    pub fn is_box_free(&self, x: f32, y: f32, half_extent: f32) -> bool {
+        let min_x = ((x - half_extent) / TILE_SIZE).floor() as i32;
+        let max_x = ((x + half_extent) / TILE_SIZE).floor() as i32;
+        let min_y = ((y - half_extent) / TILE_SIZE).floor() as i32;
+        let max_y = ((y + half_extent) / TILE_SIZE).floor() as i32;
+        (min_y..=max_y).all(|ty| (min_x..=max_x).all(|tx| self.is_walkable(tx, ty)))
+    }
+}
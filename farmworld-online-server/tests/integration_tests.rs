@@ -1,6 +1,19 @@
-use farmworld_online_server::messages::{ClientMessage, ServerMessage, PlayerState};
-use farmworld_online_server::sim::{EcsCommand, ServerToClientMessage};
-use tokio::sync::mpsc;
+// The channel flow tests are kept as first written, clippy's style lints and all
+#![allow(unused_variables, clippy::bool_comparison, clippy::redundant_pattern_matching)]
+
+use bevy::prelude::*;
+use farmworld_online_server::auth::{Accounts, Auth, SessionKeys};
+use farmworld_online_server::messages::{ClientMessage, ServerMessage, PlayerState, PROTOCOL_VERSION};
+use farmworld_online_server::net::{self, NetConfig, ShutdownNotice};
+use farmworld_online_server::persistence::{self, Storage};
+use farmworld_online_server::sim::{self, EcsCommand, ServerToClientMessage};
+use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use uuid::Uuid;
 
 #[tokio::test]
@@ -18,20 +31,22 @@ async fn test_client_to_sim_channel_flow() {
     if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&json) {
         match client_msg {
             ClientMessage::Join => {
-                // Join handled by SpawnPlayer
-                let _ = client_to_sim_tx.send(EcsCommand::SpawnPlayer { player_id });
+                // Join handled by SpawnPlayer, for the account the client logged in as
+                let _ = client_to_sim_tx.send(EcsCommand::SpawnPlayer { player_id, request_id: None, account: "alice".to_string() });
             }
             ClientMessage::Move { dx, dy } => {
-                let _ = client_to_sim_tx.send(EcsCommand::UpdateVelocity { player_id, dx, dy });
+                let _ = client_to_sim_tx.send(EcsCommand::UpdateVelocity { player_id, request_id: None, dx, dy });
             }
+            _ => {}
         }
     }
 
     // Verify command was received by sim
     let received_cmd = client_to_sim_rx.recv().await.unwrap();
     match received_cmd {
-        EcsCommand::SpawnPlayer { player_id: pid } => {
+        EcsCommand::SpawnPlayer { player_id: pid, account, .. } => {
             assert_eq!(pid, player_id);
+            assert_eq!(account, "alice");
         }
         _ => panic!("Expected SpawnPlayer command"),
     }
@@ -80,14 +95,15 @@ async fn test_multiple_clients_channel_flow() {
     let player_id2 = Uuid::new_v4();
 
     // Client 1 joins
-    let _ = client_to_sim_tx.send(EcsCommand::SpawnPlayer { player_id: player_id1 });
+    let _ = client_to_sim_tx.send(EcsCommand::SpawnPlayer { player_id: player_id1, request_id: None, account: "alice".to_string() });
 
     // Client 2 joins
-    let _ = client_to_sim_tx.send(EcsCommand::SpawnPlayer { player_id: player_id2 });
+    let _ = client_to_sim_tx.send(EcsCommand::SpawnPlayer { player_id: player_id2, request_id: None, account: "bob".to_string() });
 
     // Client 1 moves
     let _ = client_to_sim_tx.send(EcsCommand::UpdateVelocity {
         player_id: player_id1,
+        request_id: None,
         dx: 1.0,
         dy: 2.0,
     });
@@ -99,9 +115,9 @@ async fn test_multiple_clients_channel_flow() {
 
     match (cmd1, cmd2, cmd3) {
         (
-            EcsCommand::SpawnPlayer { player_id: p1 },
-            EcsCommand::SpawnPlayer { player_id: p2 },
-            EcsCommand::UpdateVelocity { player_id: p3, dx, dy }
+            EcsCommand::SpawnPlayer { player_id: p1, .. },
+            EcsCommand::SpawnPlayer { player_id: p2, .. },
+            EcsCommand::UpdateVelocity { player_id: p3, dx, dy, .. }
         ) => {
             assert_eq!(p1, player_id1);
             assert_eq!(p2, player_id2);
@@ -113,17 +129,22 @@ async fn test_multiple_clients_channel_flow() {
     }
 
     // Simulate sim broadcasting to all clients
-    let broadcast_msg = ServerMessage::PlayerState {
+    let broadcast_msg = ServerMessage::Snapshot {
+        seq: 0,
+        tick: 0,
+        baseline: None,
         players: vec![
             PlayerState {
                 player_id: player_id1,
                 x: 1.0,
                 y: 2.0,
+                last_processed_input: None,
             },
             PlayerState {
                 player_id: player_id2,
                 x: 3.0,
                 y: 4.0,
+                last_processed_input: None,
             },
         ],
     };
@@ -135,12 +156,12 @@ async fn test_multiple_clients_channel_flow() {
     let received = sim_to_client_rx.recv().await.unwrap();
     match received {
         ServerToClientMessage::Broadcast { message } => {
-            if let ServerMessage::PlayerState { players } = message {
+            if let ServerMessage::Snapshot { players, .. } = message {
                 assert_eq!(players.len(), 2);
                 assert!(players.iter().any(|p| p.player_id == player_id1 && p.x == 1.0 && p.y == 2.0));
                 assert!(players.iter().any(|p| p.player_id == player_id2 && p.x == 3.0 && p.y == 4.0));
             } else {
-                panic!("Expected PlayerState message");
+                panic!("Expected Snapshot message");
             }
         }
         _ => panic!("Expected Broadcast message"),
@@ -154,7 +175,7 @@ async fn test_channel_capacity_and_error_handling() {
     // Send many commands to test unbounded channel
     for i in 0..1000 {
         let player_id = Uuid::new_v4();
-        let cmd = EcsCommand::SpawnPlayer { player_id };
+        let cmd = EcsCommand::SpawnPlayer { player_id, request_id: None, account: player_id.to_string() };
         let _ = client_to_sim_tx.send(cmd);
     }
 
@@ -191,7 +212,7 @@ async fn test_player_disconnect_flow() {
     let player_id = Uuid::new_v4();
 
     // Player joins
-    let _ = client_to_sim_tx.send(EcsCommand::SpawnPlayer { player_id });
+    let _ = client_to_sim_tx.send(EcsCommand::SpawnPlayer { player_id, request_id: None, account: "alice".to_string() });
 
     // Player disconnects
     let _ = client_to_sim_tx.send(EcsCommand::DespawnPlayer { player_id });
@@ -202,7 +223,7 @@ async fn test_player_disconnect_flow() {
 
     match (join_cmd, disconnect_cmd) {
         (
-            EcsCommand::SpawnPlayer { player_id: j_id },
+            EcsCommand::SpawnPlayer { player_id: j_id, .. },
             EcsCommand::DespawnPlayer { player_id: d_id }
         ) => {
             assert_eq!(j_id, player_id);
@@ -228,4 +249,256 @@ async fn test_player_disconnect_flow() {
         }
         _ => panic!("Expected Broadcast message"),
     }
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_shutdown_notifies_and_closes_clients() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let auth = Arc::new(Auth {
+        accounts: Accounts::open_in_memory().unwrap(),
+        sessions: SessionKeys::generate(),
+    });
+    let (client_to_sim_tx, mut client_to_sim_rx) = mpsc::unbounded_channel();
+    let (_sim_to_net_tx, sim_to_net_rx) = mpsc::unbounded_channel();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<ShutdownNotice>();
+    let server = tokio::spawn(net::serve(
+        listener,
+        NetConfig::default(),
+        auth,
+        client_to_sim_tx,
+        sim_to_net_rx,
+        async { shutdown_rx.await.unwrap() },
+    ));
+
+    let (mut client, _) = tokio_tungstenite::connect_async(url.as_str()).await.unwrap();
+    let send = |msg: &ClientMessage| Message::Text(serde_json::to_string(msg).unwrap().into());
+    let hello = ClientMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_build: "test".to_string(),
+        capabilities: vec![],
+    };
+    client.send(send(&hello)).await.unwrap();
+    let register = ClientMessage::Register {
+        username: "alice".to_string(),
+        password: "correct horse".to_string(),
+    };
+    client.send(send(&register)).await.unwrap();
+
+    // Read server messages until the next one that isn't a control frame
+    type Client = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+    async fn next_message(client: &mut Client) -> Message {
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                Message::Ping(_) | Message::Pong(_) => continue,
+                msg => return msg,
+            }
+        }
+    }
+    let parse = |msg: Message| match msg {
+        Message::Text(text) => serde_json::from_str::<ServerMessage>(&text).unwrap(),
+        other => panic!("Expected a text message, got {:?}", other),
+    };
+    assert!(matches!(parse(next_message(&mut client).await), ServerMessage::Welcome { .. }));
+    let ServerMessage::LoggedIn { player_id, .. } = parse(next_message(&mut client).await) else {
+        panic!("Expected LoggedIn");
+    };
+
+    shutdown_tx
+        .send(ShutdownNotice {
+            reason: "maintenance".to_string(),
+            reconnect_after: Some(60),
+        })
+        .unwrap();
+
+    // The client is told why, then the connection is closed
+    assert_eq!(
+        parse(next_message(&mut client).await),
+        ServerMessage::ServerShutdown {
+            reason: "maintenance".to_string(),
+            reconnect_after: Some(60),
+        }
+    );
+    let Message::Close(Some(close)) = next_message(&mut client).await else {
+        panic!("Expected a close frame");
+    };
+    assert_eq!(close.code, CloseCode::Away);
+    // Answering the close frame lets the server finish
+    while client.next().await.is_some() {}
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server didn't stop")
+        .unwrap();
+
+    // The player left cleanly, so the sim can save them before it stops
+    assert!(matches!(
+        client_to_sim_rx.recv().await,
+        Some(EcsCommand::DespawnPlayer { player_id: pid }) if pid == player_id
+    ));
+
+    // Nobody else gets in
+    assert!(tokio_tungstenite::connect_async(url.as_str()).await.is_err());
+}
+
+/// Runs the real simulation on its own thread until it gets `EcsCommand::Shutdown`.
+fn spawn_sim(client_to_sim_rx: mpsc::UnboundedReceiver<EcsCommand>, sim_to_net_tx: mpsc::UnboundedSender<ServerToClientMessage>, setup: impl FnOnce(&mut App) + Send + 'static) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut app = App::new();
+        app.insert_resource(sim::CropCatalog::load("content/crops.json").unwrap())
+            .insert_resource(sim::CommandQueue { rx: client_to_sim_rx })
+            .insert_resource(sim::ServerToClientQueue { tx: sim_to_net_tx })
+            .add_plugins(MinimalPlugins)
+            .add_plugins(sim::SimulationPlugin { tick_rate: 60.0 });
+        setup(&mut app);
+        app.run();
+    })
+}
+
+#[tokio::test]
+async fn test_resume_puts_the_player_back_without_join() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let auth = Arc::new(Auth {
+        accounts: Accounts::open_in_memory().unwrap(),
+        sessions: SessionKeys::generate(),
+    });
+    let (client_to_sim_tx, client_to_sim_rx) = mpsc::unbounded_channel();
+    let (sim_to_net_tx, sim_to_net_rx) = mpsc::unbounded_channel();
+    let sim_thread = spawn_sim(client_to_sim_rx, sim_to_net_tx, |_| {});
+    let shutdown_tx = client_to_sim_tx.clone();
+    let (stop_tx, stop_rx) = oneshot::channel::<ShutdownNotice>();
+    let server = tokio::spawn(net::serve(
+        listener,
+        NetConfig::default(),
+        auth,
+        client_to_sim_tx,
+        sim_to_net_rx,
+        async { stop_rx.await.unwrap() },
+    ));
+
+    type Client = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+    let send = |msg: &ClientMessage| Message::Text(serde_json::to_string(msg).unwrap().into());
+    // Skips control frames and anything the test isn't waiting for
+    async fn wait_for(client: &mut Client, wanted: impl Fn(&ServerMessage) -> bool) -> ServerMessage {
+        let read = async {
+            loop {
+                if let Message::Text(text) = client.next().await.unwrap().unwrap() {
+                    let msg = serde_json::from_str::<ServerMessage>(&text).unwrap();
+                    if wanted(&msg) {
+                        return msg;
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read).await.expect("message never arrived")
+    }
+    let hello = ClientMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_build: "test".to_string(),
+        capabilities: vec!["resume".to_string()],
+    };
+
+    let (mut client, _) = tokio_tungstenite::connect_async(url.as_str()).await.unwrap();
+    client.send(send(&hello)).await.unwrap();
+    let register = ClientMessage::Register {
+        username: "alice".to_string(),
+        password: "correct horse".to_string(),
+    };
+    client.send(send(&register)).await.unwrap();
+    let ServerMessage::LoggedIn { player_id, session_token, .. } = wait_for(&mut client, |msg| matches!(msg, ServerMessage::LoggedIn { .. })).await else {
+        unreachable!();
+    };
+    client.send(send(&ClientMessage::Join)).await.unwrap();
+    wait_for(&mut client, |msg| matches!(msg, ServerMessage::PlayerJoined { player_id: pid, .. } if *pid == player_id)).await;
+
+    // The socket goes away without a close frame, the player stays in the world
+    drop(client);
+
+    let (mut resumed, _) = tokio_tungstenite::connect_async(url.as_str()).await.unwrap();
+    resumed.send(send(&hello)).await.unwrap();
+    resumed.send(send(&ClientMessage::Resume { session_token })).await.unwrap();
+    wait_for(&mut resumed, |msg| matches!(msg, ServerMessage::LoggedIn { player_id: pid, .. } if *pid == player_id)).await;
+    // No Join: the kept entity is re-bound and the world is sent again
+    wait_for(&mut resumed, |msg| matches!(msg, ServerMessage::PlayerJoined { player_id: pid, .. } if *pid == player_id)).await;
+    wait_for(&mut resumed, |msg| matches!(msg, ServerMessage::ContentManifest { .. })).await;
+    wait_for(&mut resumed, |msg| matches!(msg, ServerMessage::InventoryUpdated { .. })).await;
+
+    stop_tx.send(ShutdownNotice { reason: "test over".to_string(), reconnect_after: None }).unwrap();
+    while resumed.next().await.is_some() {}
+    server.await.unwrap();
+    shutdown_tx.send(EcsCommand::Shutdown).unwrap();
+    sim_thread.join().unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_saves_the_world_once_more() {
+    let db_path = std::env::temp_dir().join(format!("farmworld-{}.db", Uuid::new_v4()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let auth = Arc::new(Auth {
+        accounts: Accounts::open_in_memory().unwrap(),
+        sessions: SessionKeys::generate(),
+    });
+    let (client_to_sim_tx, client_to_sim_rx) = mpsc::unbounded_channel();
+    let (sim_to_net_tx, sim_to_net_rx) = mpsc::unbounded_channel();
+    // Only the save on exit writes to the database, like main.rs without its timer
+    let storage = Storage::open(&db_path).unwrap();
+    let sim_thread = spawn_sim(client_to_sim_rx, sim_to_net_tx, move |app| {
+        persistence::restore_world(app.world_mut(), storage).unwrap();
+        app.add_systems(Last, persistence::save_on_exit);
+    });
+    let shutdown_tx = client_to_sim_tx.clone();
+    let (stop_tx, stop_rx) = oneshot::channel::<ShutdownNotice>();
+    let server = tokio::spawn(net::serve(
+        listener,
+        NetConfig::default(),
+        auth,
+        client_to_sim_tx,
+        sim_to_net_rx,
+        async { stop_rx.await.unwrap() },
+    ));
+
+    let (mut client, _) = tokio_tungstenite::connect_async(url.as_str()).await.unwrap();
+    let send = |msg: &ClientMessage| Message::Text(serde_json::to_string(msg).unwrap().into());
+    let hello = ClientMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_build: "test".to_string(),
+        capabilities: vec![],
+    };
+    client.send(send(&hello)).await.unwrap();
+    let register = ClientMessage::Register {
+        username: "alice".to_string(),
+        password: "correct horse".to_string(),
+    };
+    client.send(send(&register)).await.unwrap();
+    client.send(send(&ClientMessage::Join)).await.unwrap();
+    // Wait until the sim has put the player in the world
+    let joined = async {
+        loop {
+            if let Message::Text(text) = client.next().await.unwrap().unwrap()
+                && matches!(serde_json::from_str(&text).unwrap(), ServerMessage::PlayerJoined { .. })
+            {
+                break;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), joined).await.expect("never joined");
+
+    // Shut down the way main.rs does: the net side first, then the sim
+    stop_tx.send(ShutdownNotice { reason: "maintenance".to_string(), reconnect_after: None }).unwrap();
+    while client.next().await.is_some() {}
+    tokio::time::timeout(Duration::from_secs(5), server).await.expect("server didn't stop").unwrap();
+    shutdown_tx.send(EcsCommand::Shutdown).unwrap();
+    sim_thread.join().unwrap();
+
+    // The player who was still online made it into the final save
+    let saved = Storage::open(&db_path).unwrap().load().unwrap();
+    let _ = std::fs::remove_file(&db_path);
+    assert_eq!(saved.players.len(), 1);
+    assert_eq!(saved.players[0].account, "alice");
+    assert!(saved.players[0].inventory.iter().any(Option::is_some));
+}
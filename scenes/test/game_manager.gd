@@ -4,10 +4,19 @@ signal player_joined(player_id: String, position: Vector2)
 signal player_left(player_id: String)
 signal player_moved(player_id: String, position: Vector2)
 
+const PROTOCOL_VERSION := 1  # Must be in the server's supported range
+
 var websocket: WebSocketPeer
 var connected := false
 var local_player_id := ""
+var session_token := ""  # Lets a dropped connection pick up where it left off
+var resuming := false  # A resumed session is put back in the world without Join
 var last_direction := Vector2.ZERO
+var snapshots := {}  # seq -> {player_id: Vector2}, deltas are applied to these
+var visible := {}  # player_id -> true for players the server says are in view
+var input_seq := 0  # Numbers our inputs so snapshots can tell which were applied
+var server_tick := 0  # Latest simulation tick seen in a snapshot
+var rtt_ms := 0  # Round trip to the server, measured by its pings
 
 func _ready():
 	print("=== GameManager Starting ===")
@@ -38,8 +47,8 @@ func _physics_process(delta):
 			if not connected:
 				connected = true
 				print("✅ Connected to server successfully")
-				# Send join message after connection
-				get_tree().create_timer(0.1).timeout.connect(func(): send_join_message())
+				# Introduce ourselves; we log in once the server welcomes us
+				send_hello_message()
 
 			# Process incoming messages
 			while websocket.get_available_packet_count() > 0:
@@ -60,6 +69,39 @@ func _physics_process(delta):
 				var reason = websocket.get_close_reason()
 				print("   Close code: ", code, ", Reason: ", reason)
 
+func send_hello_message():
+	websocket.send_text(JSON.stringify({
+		"action": "Hello",
+		"data": {
+			"protocol_version": PROTOCOL_VERSION,
+			"client_build": ProjectSettings.get_setting("application/config/version", "dev"),
+			"capabilities": ["resume"]
+		}
+	}))
+	print("📤 Sent Hello, protocol ", PROTOCOL_VERSION)
+
+func send_login_message():
+	if websocket.get_ready_state() == WebSocketPeer.STATE_OPEN:
+		if session_token != "":
+			resuming = true
+			websocket.send_text(JSON.stringify({
+				"action": "Resume",
+				"data": {"session_token": session_token}
+			}))
+			print("📤 Sent Resume")
+			return
+		var login_data = {
+			"action": "Login" if GameConfig.account_registered else "Register",
+			"data": {
+				"username": GameConfig.account_name,
+				"password": GameConfig.account_password
+			}
+		}
+		websocket.send_text(JSON.stringify(login_data))
+		print("📤 Sent ", login_data["action"], " for ", GameConfig.account_name)
+	else:
+		print("⚠️  Cannot log in - WebSocket not connected")
+
 func send_join_message():
 	if websocket.get_ready_state() == WebSocketPeer.STATE_OPEN:
 		var join_data = {
@@ -80,16 +122,19 @@ func send_movement_input():
 			if direction.distance_to(last_direction) > 0.01:
 				last_direction = direction
 
+				input_seq += 1
 				var move_data = {
-					"action": "Move",
+					"action": "Input",
 					"data": {
+						"seq": input_seq,
 						"dx": direction.x,
-						"dy": direction.y
+						"dy": direction.y,
+						"tick": server_tick + 1
 					}
 				}
 				var json_string = JSON.stringify(move_data)
 				websocket.send_text(json_string)
-				print("📤 Sent MOVE message: ", json_string)
+				print("📤 Sent INPUT message: ", json_string)
 		else:
 			print("⚠️  Cannot send movement - WebSocket not connected")
 	# In single-player, movement is handled locally by player.gd
@@ -114,24 +159,73 @@ func handle_server_message(message: String):
 				emit_signal("player_joined", player_id, Vector2(x, y))
 				print("🎮 PLAYER JOINED - ID: ", player_id, " at (", x, ", ", y, ")")
 
-			"PlayerState":
+			"Snapshot":
+				handle_snapshot(data.get("data", {}))
+
+			"Welcome":
+				print("🤝 Server speaks protocol ", data.get("data", {}).get("protocol_version", 0))
+				send_login_message()
+
+			"Rejected":
+				# The server closes the connection right after this
+				print("⛔ REJECTED by server: ", data.get("data", {}).get("reason", ""))
+
+			"ServerShutdown":
+				# The server closes the connection right after this
 				var event_data = data.get("data", {})
-				var players = event_data.get("players", [])
-				#print("📊 PLAYER STATE UPDATE - ", players.size(), " players:")
-				for player in players:
-					var pid = player.get("player_id", "")
-					var x = player.get("x", 0.0)
-					var y = player.get("y", 0.0)
-					emit_signal("player_moved", pid, Vector2(x, y))
-					#var is_me = pid == local_player_id
-					#var marker = "👤" if is_me else "👥"
-					#print("   ", marker, " Player ", pid.substr(0, 8), "... at (", x, ", ", y, ")")
+				print("🛑 SERVER SHUTTING DOWN: ", event_data.get("reason", ""))
+				if event_data.get("reconnect_after") != null:
+					print("   Back in about ", event_data.get("reconnect_after"), " seconds")
 
-			"PlayerLeft":
+			"LoggedIn":
 				var event_data = data.get("data", {})
-				var pid = event_data.get("player_id", "")
+				local_player_id = event_data.get("player_id", "")
+				session_token = event_data.get("session_token", "")
+				snapshots.clear()  # The server starts this connection with a keyframe
+				visible.clear()
+				input_seq = 0  # Input numbering starts over too
+				if not GameConfig.account_registered:
+					GameConfig.account_registered = true
+					GameConfig.save_account()
+				print("🔑 LOGGED IN as ", event_data.get("username", ""))
+				if not resuming:
+					send_join_message()
+				resuming = false
+
+			"Error":
+				var event_data = data.get("data", {})
+				print("⚠️  SERVER ERROR ", event_data.get("code", ""), ": ", event_data.get("message", ""))
+				if event_data.get("code", "") == "InvalidSession":
+					# The session ran out, log in with the password instead
+					session_token = ""
+					resuming = false
+					send_login_message()
+
+			"Ack":
+				# Only requests sent with a request_id are acknowledged
+				var event_data = data.get("data", {})
+				var result = event_data.get("result", {})
+				if result.has("Err"):
+					var error = result["Err"]
+					print("⚠️  REQUEST ", event_data.get("request_id"), " FAILED ", error.get("code", ""), ": ", error.get("message", ""))
+
+			"EntityEntered":
+				var event_data = data.get("data", {})
+				var pid = event_data.get("entity_id", "")
+				visible[pid] = true
+				emit_signal("player_joined", pid, Vector2(event_data.get("x", 0.0), event_data.get("y", 0.0)))
+
+			"EntityLeft":
+				var pid = data.get("data", {}).get("entity_id", "")
+				visible.erase(pid)
 				emit_signal("player_left", pid)
-				print("👋 PLAYER LEFT - ID: ", pid)
+
+			"Latency":
+				rtt_ms = int(data.get("data", {}).get("rtt_ms", 0))
+
+			"PlayerLeft":
+				# Out of the world; EntityLeft already took them off screen
+				print("👋 PLAYER LEFT - ID: ", data.get("data", {}).get("player_id", ""))
 
 			_:
 				print("❓ UNKNOWN EVENT: ", event_type, " - Full data: ", data)
@@ -139,6 +233,30 @@ func handle_server_message(message: String):
 		print("❌ Failed to parse server message: ", message)
 		print("   JSON Parse Error: ", error)
 
+func handle_snapshot(event_data: Dictionary):
+	var seq = int(event_data.get("seq", 0))
+	server_tick = max(server_tick, int(event_data.get("tick", 0)))
+	var baseline = event_data.get("baseline")
+	var positions := {}
+	if baseline != null:
+		if not snapshots.has(int(baseline)):
+			return  # Too old, the next keyframe will catch us up
+		positions = snapshots[int(baseline)].duplicate()
+	for pid in positions.keys():
+		if not visible.has(pid):
+			positions.erase(pid)
+	for player in event_data.get("players", []):
+		var pid = player.get("player_id", "")
+		var position = Vector2(player.get("x", 0.0), player.get("y", 0.0))
+		positions[pid] = position
+		emit_signal("player_moved", pid, position)
+
+	snapshots[seq] = positions
+	for old_seq in snapshots.keys():
+		if old_seq < seq - 32:
+			snapshots.erase(old_seq)
+	websocket.send_text(JSON.stringify({"action": "AckSnapshot", "data": {"seq": seq}}))
+
 func _notification(what):
 	if what == NOTIFICATION_WM_CLOSE_REQUEST:
 		print("🛑 Window close requested - disconnecting from server...")
@@ -2,7 +2,35 @@ extends Node
 
 enum GameMode { SINGLE_PLAYER, MULTIPLAYER }
 
+const SETTINGS_PATH := "user://settings.cfg"
+
 var game_mode: GameMode = GameMode.MULTIPLAYER  # Default to multiplayer
+var account_name := ""  # The server saves progress per account
+var account_password := ""
+var account_registered := false
 
 func _ready():
+	load_account()
 	print("=== GameConfig Initialized ===")
+
+func load_account():
+	var config = ConfigFile.new()
+	config.load(SETTINGS_PATH)
+	account_name = config.get_value("player", "account", "")
+	account_password = config.get_value("player", "password", "")
+	account_registered = config.get_value("player", "registered", false)
+	if account_name == "" or account_password == "":
+		# First launch: pick credentials and keep them so progress survives restarts
+		var crypto = Crypto.new()
+		account_name = "farmer_%d" % (randi() % 1000000)
+		account_password = Marshalls.raw_to_base64(crypto.generate_random_bytes(18))
+		account_registered = false
+		save_account()
+
+func save_account():
+	var config = ConfigFile.new()
+	config.load(SETTINGS_PATH)
+	config.set_value("player", "account", account_name)
+	config.set_value("player", "password", account_password)
+	config.set_value("player", "registered", account_registered)
+	config.save(SETTINGS_PATH)